@@ -1,6 +1,5 @@
 #![feature(test)]
 extern crate piston_window;
-extern crate camera_capture;
 extern crate rayon;
 
 use image::{ConvertBuffer, open, DynamicImage, FilterType};
@@ -1,13 +1,17 @@
 extern crate piston_window;
-extern crate camera_capture;
 
-use image::ConvertBuffer;
+use image::{ConvertBuffer, ImageBuffer, Rgba};
 use piston_window::{clear, PistonWindow, Texture, TextureSettings, WindowSettings};
 use nalgebra::{Vector2, Point2};
 use cv_pinhole::CameraIntrinsics;
 
 extern crate fiducial;
-use fiducial::debug::find_lftags_debug;
+use fiducial::debug::draw_lftags;
+use fiducial::calibration_file::load_calibration;
+use fiducial::camera::BrownConrady;
+use fiducial::detector::LFTagDetector;
+use fiducial::source::CameraSource;
+use std::path::Path;
 
 fn main() {
     let horiz_res = 640;
@@ -17,33 +21,37 @@ fn main() {
         .exit_on_esc(true)
         .build()
         .unwrap();
+    // camera matrix from camera_cal, unless a calibration file is given
+    // [[712.44128286   0.         316.80287675]
+    //  [  0.         711.06570126 228.46397532]
+    //  [  0.           0.           1.        ]]
+    let camera = match std::env::args().nth(1) {
+        Some(path) => load_calibration(Path::new(&path)).unwrap().camera,
+        None => BrownConrady::new(CameraIntrinsics {
+            focals: Vector2::new(712.44128286, 711.06570126),
+            principal_point: Point2::new(316.80287675, 228.46397532),
+            skew: 0.0
+        }, [0.0; 5]),
+    };
+
     let mut tex: Option<Texture<_>> = None;
     let (sender, receiver) = std::sync::mpsc::channel();
     let imgthread = std::thread::spawn(move || {
-        let res1 = camera_capture::create(0).unwrap().resolution(horiz_res, vert_res);
-        if let Err(e) = res1 {
-            eprintln!("could not open camera: {:?}", e);
+        let mut source = CameraSource::open(0, horiz_res, vert_res, 30.0).unwrap_or_else(|e| {
+            eprintln!("could not open camera: {}", e);
             std::process::exit(1);
-        }
-        let res2 = res1.unwrap().fps(30.0).unwrap().start();
-        if let Err(e) = res2 {
-            eprintln!("could retrieve data from camera: {}", e);
+        });
+        let detector = LFTagDetector::new(camera);
+        let result = detector.run(&mut source, |frame, tags| {
+            let mut output: ImageBuffer<Rgba<u8>, Vec<u8>> = frame.image.convert();
+            draw_lftags(&mut output, tags, &detector.camera);
+            // stop once the window is closed
+            sender.send(output).is_ok()
+        });
+        if let Err(e) = result {
+            eprintln!("could not retrieve data from camera: {}", e);
             std::process::exit(2);
         }
-        let cam = res2.unwrap();
-        // camera matrix from camera_cal
-        // [[712.44128286   0.         316.80287675]
-        //  [  0.         711.06570126 228.46397532]
-        //  [  0.           0.           1.        ]]
-        for frame in cam {
-            if sender.send(find_lftags_debug(&frame.convert(), CameraIntrinsics {
-                focals: Vector2::new(712.44128286, 711.06570126),
-                principal_point: Point2::new(316.80287675, 228.46397532),
-                skew: 0.0
-            }).unwrap().convert()).is_err() {
-                break;
-            }
-        }
     });
     while let Some(e) = window.next() {
         if let Ok(frame) = receiver.try_recv() {
@@ -0,0 +1,108 @@
+extern crate fiducial;
+
+use cv_pinhole::CameraIntrinsics;
+use nalgebra::{Point2, Vector2};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use fiducial::calibrate::{calibrate_camera, Board};
+use fiducial::calibration_file::{save_calibration, CalibrationFormat, CameraCalibration};
+use fiducial::detector::LFTagDetector;
+
+fn usage() -> ! {
+    eprintln!("usage: fiducial-calibrate <board.json> <image directory> [output .yaml/.xml/.json/.toml]");
+    std::process::exit(1);
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.len() != 2 && args.len() != 3 {
+        usage();
+    }
+
+    let board = Board::load(Path::new(&args[0])).unwrap_or_else(|e| {
+        eprintln!("could not load board {}: {}", args[0], e);
+        std::process::exit(1);
+    });
+
+    let mut paths: Vec<PathBuf> = fs::read_dir(&args[1])
+        .unwrap_or_else(|e| {
+            eprintln!("could not read {}: {}", args[1], e);
+            std::process::exit(1);
+        })
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|p| p.is_file())
+        .collect();
+    paths.sort();
+
+    let mut observations = Vec::new();
+    let mut used_paths = Vec::new();
+    let mut image_size = None;
+
+    for path in &paths {
+        let im = match image::open(path) {
+            Ok(im) => im.to_luma(),
+            Err(_) => continue,
+        };
+        let dim = im.dimensions();
+        if *image_size.get_or_insert(dim) != dim {
+            eprintln!("skipping {}: resolution differs from the other images", path.display());
+            continue;
+        }
+
+        // the decoder only needs a rough camera to find the tags
+        let detector = LFTagDetector::new(CameraIntrinsics {
+            focals: Vector2::new(dim.0.max(dim.1) as f64, dim.0.max(dim.1) as f64),
+            principal_point: Point2::new(dim.0 as f64 / 2.0, dim.1 as f64 / 2.0),
+            skew: 0.0,
+        });
+
+        match detector.detect(&im) {
+            Some(tags) => {
+                let points = board.correspondences(&tags);
+                println!("{}: {} tags, {} points", path.display(), tags.len(), points.len());
+                observations.push(points);
+                used_paths.push(path);
+            }
+            None => eprintln!("skipping {}: dimensions must be a multiple of 16", path.display()),
+        }
+    }
+
+    let calibration = match image_size.and_then(|size| calibrate_camera(&observations, size)) {
+        Some(c) => c,
+        None => {
+            eprintln!("not enough board points to calibrate");
+            std::process::exit(1);
+        }
+    };
+
+    for (path, error) in used_paths.iter().zip(&calibration.image_errors) {
+        match error {
+            Some(e) => println!("{}: reprojection error {:.3}px", path.display(), e),
+            None => println!("{}: not used", path.display()),
+        }
+    }
+
+    let intrinsics = &calibration.camera.intrinsics;
+    println!("rms reprojection error: {:.3}px", calibration.rms);
+    println!("focals: {} {}", intrinsics.focals[0], intrinsics.focals[1]);
+    println!("principal point: {} {}", intrinsics.principal_point[0], intrinsics.principal_point[1]);
+    println!("distortion (k1 k2 p1 p2 k3): {:?}", calibration.camera.distortion);
+
+    if let Some(output) = args.get(2) {
+        let output = Path::new(output);
+        let format = CalibrationFormat::from_path(output).unwrap_or_else(|| {
+            eprintln!("unknown calibration format for {}", output.display());
+            std::process::exit(1);
+        });
+        let file = CameraCalibration {
+            camera: calibration.camera,
+            image_size,
+        };
+        if let Err(e) = save_calibration(output, &file, format) {
+            eprintln!("could not write {}: {}", output.display(), e);
+            std::process::exit(1);
+        }
+    }
+}
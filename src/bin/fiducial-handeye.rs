@@ -0,0 +1,85 @@
+extern crate fiducial;
+
+use std::env;
+use std::path::Path;
+
+use fiducial::calibration_file::load_calibration;
+use fiducial::detector::LFTagDetector;
+use fiducial::handeye::{calibrate_hand_eye, RobotStations, TargetObservation};
+use fiducial::localize::TagSize;
+use fiducial::pose::PoseRecord;
+
+fn usage() -> ! {
+    eprintln!("usage: fiducial-handeye [--id <tag id>] <calibration file> <tag size> <robot stations .yaml/.json/.toml>");
+    eprintln!("stations list the image taken at each gripper pose, with the pose of the gripper in the robot base");
+    std::process::exit(1);
+}
+
+fn main() {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let mut id = None;
+    if args.first().map(|a| a.as_str()) == Some("--id") && args.len() > 1 {
+        id = Some(args[1].parse::<usize>().unwrap_or_else(|_| usage()));
+        args.drain(..2);
+    }
+    if args.len() != 3 {
+        usage();
+    }
+
+    let calibration = load_calibration(Path::new(&args[0])).unwrap_or_else(|e| {
+        eprintln!("could not load calibration {}: {}", args[0], e);
+        std::process::exit(1);
+    });
+    let tag_size: f64 = args[1].parse().unwrap_or_else(|_| usage());
+    let stations_path = Path::new(&args[2]);
+    let stations = RobotStations::load(stations_path).unwrap_or_else(|e| {
+        eprintln!("could not load {}: {}", args[2], e);
+        std::process::exit(1);
+    });
+    let base_dir = stations_path.parent().unwrap_or_else(|| Path::new("."));
+
+    let mut detector = LFTagDetector::new(calibration.camera);
+    detector.tag_size = TagSize::new(tag_size);
+
+    let mut grippers = Vec::new();
+    let mut observations = Vec::new();
+    let mut used = Vec::new();
+    for station in &stations.stations {
+        let path = base_dir.join(&station.image);
+        let observation = image::open(&path).ok()
+            .and_then(|im| detector.detect(&im.to_luma()))
+            .and_then(|tags| TargetObservation::from_detections(&tags, id));
+        match observation {
+            Some(obs) => {
+                grippers.push(station.pose.isometry());
+                observations.push(obs);
+                used.push(path);
+            }
+            None => eprintln!("skipping {}: tag not found", path.display()),
+        }
+    }
+
+    let solution = match calibrate_hand_eye(&detector.camera, &grippers, &observations) {
+        Some(s) => s,
+        None => {
+            eprintln!("need at least 3 stations with the tag in view and rotations about different axes");
+            std::process::exit(1);
+        }
+    };
+
+    for (idx, path) in used.iter().enumerate() {
+        println!(
+            "{}: {:.3}deg {:.4} {:.3}px",
+            path.display(),
+            solution.rotation_residuals[idx].to_degrees(),
+            solution.translation_residuals[idx],
+            solution.image_errors[idx]
+        );
+    }
+    println!("rms reprojection error: {:.3}px", solution.rms);
+
+    let record = PoseRecord::from(solution.camera_in_gripper);
+    println!("camera in gripper: translation {:?} rotation {:?}", record.translation, record.rotation);
+    let record = PoseRecord::from(solution.target_in_base);
+    println!("tag in base: translation {:?} rotation {:?}", record.translation, record.rotation);
+}
@@ -0,0 +1,73 @@
+extern crate fiducial;
+
+use std::env;
+use std::path::Path;
+
+use fiducial::calibration_file::load_calibration;
+use fiducial::detector::LFTagDetector;
+use fiducial::localize::TagSize;
+use fiducial::mapping::build_map;
+
+fn usage() -> ! {
+    eprintln!("usage: fiducial-map [--reference <tag id>] <calibration file> <tag size> <output map .yaml/.json/.toml> <image>...");
+    eprintln!("the reference tag defines the world frame, by default the first tag seen");
+    std::process::exit(1);
+}
+
+fn main() {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let mut reference = None;
+    if args.first().map(|a| a.as_str()) == Some("--reference") && args.len() > 1 {
+        reference = Some(args[1].parse::<usize>().unwrap_or_else(|_| usage()));
+        args.drain(..2);
+    }
+    if args.len() < 4 {
+        usage();
+    }
+
+    let calibration = load_calibration(Path::new(&args[0])).unwrap_or_else(|e| {
+        eprintln!("could not load calibration {}: {}", args[0], e);
+        std::process::exit(1);
+    });
+    let tag_size: f64 = args[1].parse().unwrap_or_else(|_| usage());
+    let output = Path::new(&args[2]);
+
+    let mut detector = LFTagDetector::new(calibration.camera);
+    detector.tag_size = TagSize::new(tag_size);
+
+    let mut frames = Vec::new();
+    for filename in &args[3..] {
+        let tags = image::open(Path::new(filename)).ok().and_then(|im| detector.detect(&im.to_luma()));
+        match tags {
+            Some(tags) => {
+                println!("{}: {:?}", filename, tags.iter().map(|t| t.data).collect::<Vec<_>>());
+                frames.push(tags);
+            }
+            None => {
+                eprintln!("skipping {}", filename);
+                frames.push(Vec::new());
+            }
+        }
+    }
+
+    let mapping = match build_map(&detector.camera, &frames, &detector.tag_size, reference, 5.0) {
+        Some(m) => m,
+        None => {
+            eprintln!("no tags found");
+            std::process::exit(1);
+        }
+    };
+
+    for (filename, pose) in args[3..].iter().zip(&mapping.trajectory) {
+        match pose {
+            Some(pose) => println!("{}: camera at {:?}", filename, pose.translation.vector.as_slice()),
+            None => println!("{}: not localized", filename),
+        }
+    }
+    println!("{} tags mapped, rms reprojection error {:.3}px", mapping.map.tags.len(), mapping.rms_error);
+
+    if let Err(e) = mapping.map.save(output) {
+        eprintln!("could not write {}: {}", output.display(), e);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,111 @@
+extern crate fiducial;
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use fiducial::calibrate::{calibrate_rig, Board, RigView};
+use fiducial::calibration_file::load_calibration;
+use fiducial::detector::LFTagDetector;
+use fiducial::marker_map::MarkerMap;
+
+fn usage() -> ! {
+    eprintln!("usage: fiducial-rig [--refine-intrinsics] <board or marker map> <output rig .yaml/.json/.toml> (<calibration file> <image directory>)...");
+    eprintln!("each directory holds the images of one camera, matched into snapshots by sorted file name");
+    eprintln!("cameras are named after their calibration file");
+    std::process::exit(1);
+}
+
+fn sorted_files(dir: &str) -> Vec<PathBuf> {
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir)
+        .unwrap_or_else(|e| {
+            eprintln!("could not read {}: {}", dir, e);
+            std::process::exit(1);
+        })
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|p| p.is_file())
+        .collect();
+    paths.sort();
+    paths
+}
+
+fn main() {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let refine_intrinsics = args.first().map(|a| a.as_str()) == Some("--refine-intrinsics");
+    if refine_intrinsics {
+        args.remove(0);
+    }
+    if args.len() < 6 || args.len() % 2 != 0 {
+        usage();
+    }
+
+    let target_path = Path::new(&args[0]);
+    let target = MarkerMap::load(target_path)
+        .or_else(|_| Board::load(target_path).map(|b| b.to_marker_map()))
+        .unwrap_or_else(|e| {
+            eprintln!("could not load {}: {}", args[0], e);
+            std::process::exit(1);
+        });
+    let output = Path::new(&args[1]);
+
+    let mut cameras = Vec::new();
+    let mut views = Vec::new();
+
+    for (idx, pair) in args[2..].chunks(2).enumerate() {
+        let calibration = load_calibration(Path::new(&pair[0])).unwrap_or_else(|e| {
+            eprintln!("could not load calibration {}: {}", pair[0], e);
+            std::process::exit(1);
+        });
+        let detector = LFTagDetector::new(calibration.camera);
+        cameras.push(calibration.camera);
+        let name = Path::new(&pair[0]).file_stem().map_or_else(|| format!("camera{}", idx), |s| s.to_string_lossy().into_owned());
+
+        for (snapshot, path) in sorted_files(&pair[1]).iter().enumerate() {
+            let tags = match image::open(path).ok().and_then(|im| detector.detect(&im.to_luma())) {
+                Some(tags) => tags,
+                None => {
+                    eprintln!("skipping {}", path.display());
+                    continue;
+                }
+            };
+
+            let localization = match target.localize(&detector.camera, &tags, 5.0) {
+                Some(l) => l,
+                None => continue,
+            };
+            let inliers: Vec<_> = tags.into_iter().filter(|t| localization.tags.contains(&t.data)).collect();
+            let points = target.correspondences(&inliers);
+            println!("camera {} {}: {} tags, {} points", idx, path.display(), inliers.len(), points.len());
+
+            views.push(RigView {
+                camera: idx,
+                snapshot,
+                name: name.clone(),
+                target_pose: localization.world_pose.0,
+                points,
+            });
+        }
+    }
+
+    let calibration = match calibrate_rig(&cameras, &views, refine_intrinsics) {
+        Some(c) => c,
+        None => {
+            eprintln!("every camera must share at least one snapshot of the target with another camera");
+            std::process::exit(1);
+        }
+    };
+
+    for (camera, error) in calibration.rig.cameras.iter().zip(&calibration.camera_errors) {
+        let position = camera.pose.inverse().translation.vector;
+        match error {
+            Some(e) => println!("{}: at {:?}, reprojection error {:.3}px", camera.name, position.as_slice(), e),
+            None => println!("{}: at {:?}, no observations", camera.name, position.as_slice()),
+        }
+    }
+    println!("rms reprojection error: {:.3}px", calibration.rms);
+
+    if let Err(e) = calibration.rig.save(output) {
+        eprintln!("could not write {}: {}", output.display(), e);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,410 @@
+extern crate fiducial;
+
+use cv_pinhole::CameraIntrinsics;
+use image::{ConvertBuffer, ImageBuffer, Rgba};
+use nalgebra::{Point2, Point3, UnitQuaternion, Vector2};
+#[cfg(feature = "serde-serialize")]
+use serde::Serialize;
+use std::env;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use fiducial::calibration_file::load_calibration;
+use fiducial::camera::BrownConrady;
+use fiducial::debug::draw_lftags;
+use fiducial::decode::{DecodedLFTag, LFTagClass, Polarity};
+use fiducial::detector::LFTagDetector;
+use fiducial::dictionary::Dictionary;
+use fiducial::localize::{project, TagSize};
+use fiducial::pose::{FrameConvention, PoseConfig, PoseForm};
+use fiducial::source::{open_source, Frame, FrameSource};
+use fiducial::tracker::{TrackerConfig, TrackingDetector};
+
+fn usage() -> ! {
+    eprintln!("usage: fiducial [options] <image, glob, directory, numbered pattern or video>...");
+    eprintln!("  --calibration <file>      camera calibration, a rough pinhole guess is used otherwise");
+    eprintln!("  --family <name>           tag family, only lftag is supported");
+    eprintln!("  --class <list>            comma separated grids to detect, default 3x3,4x4");
+    eprintln!("  --polarity <name>         dark (dark nodes on light), light or both, default dark");
+    eprintln!("  --mirrored                also decode mirror images of tags, needs --dictionary");
+    eprintln!("  --dictionary <file>       ids in use, reads outside it are rejected (JSON, YAML or TOML)");
+    eprintln!("  --max-missing <n>         data nodes a tag may be missing, needs a dictionary");
+    eprintln!("  --tag-size <size>         printed edge length, poses are in tag grid units otherwise");
+    eprintln!("  --format <jsonl|csv>      output format, default jsonl (csv without serde-serialize)");
+    eprintln!("  --convention <name>       opencv, ros-optical, ros-body or opengl, default opencv");
+    eprintln!("  --camera-in-tag           report the camera pose in the tag frame");
+    eprintln!("  --output <file>           write records here instead of stdout");
+    eprintln!("  --annotate <dir>          write annotated images into this directory");
+    eprintln!("  --fps <rate>              frame rate of image inputs for timestamps, default 30");
+    eprintln!("  --track                   track tags across the frames of each input");
+    std::process::exit(1);
+}
+
+#[cfg_attr(feature = "serde-serialize", derive(Serialize))]
+struct Record<'a> {
+    file: &'a str,
+    frame: usize,
+    // seconds since the start of the input
+    timestamp: f64,
+    id: usize,
+    class: &'static str,
+    tag_size: f64,
+    // outer tag corners in pixels, clockwise from the top left
+    corners: Vec<[f64; 2]>,
+    nodes: Vec<[f32; 2]>,
+    translation: [f64; 3],
+    // x, y, z, w
+    rotation: [f64; 4],
+    quality: f32,
+    confidence: f32,
+    inverted: bool,
+    mirrored: bool,
+}
+
+impl<'a> Record<'a> {
+    fn new(file: &'a str, frame: &Frame, tag: &DecodedLFTag, camera: &BrownConrady, config: &PoseConfig) -> Record<'a> {
+        let s = tag.tag_size;
+        let corners = [(0.0, 0.0), (s, 0.0), (s, s), (0.0, s)].iter().map(|&(x, y)| {
+            let p = project(camera, &tag.final_pose.0, &Point3::new(x, y, 0.0));
+            [p.x, p.y]
+        }).collect();
+
+        let pose = config.transform(&tag.final_pose.0);
+        let t = pose.translation.vector;
+        let q = UnitQuaternion::from_rotation_matrix(&pose.rotation);
+
+        Record {
+            file,
+            frame: frame.index,
+            timestamp: frame.timestamp,
+            id: tag.data,
+            class: tag.class.name(),
+            tag_size: s,
+            corners,
+            nodes: tag.node_pos.iter().map(|p| [p.0, p.1]).collect(),
+            translation: [t[0], t[1], t[2]],
+            rotation: [q.i, q.j, q.k, q.w],
+            quality: tag.quality,
+            confidence: tag.confidence,
+            inverted: tag.inverted,
+            mirrored: tag.mirrored,
+        }
+    }
+
+    fn csv_header() -> &'static str {
+        "file,frame,timestamp,id,class,tag_size,quality,confidence,inverted,mirrored,tx,ty,tz,qx,qy,qz,qw,x0,y0,x1,y1,x2,y2,x3,y3"
+    }
+
+    fn csv_row(&self) -> String {
+        let mut fields = vec![
+            self.file.to_string(),
+            self.frame.to_string(),
+            self.timestamp.to_string(),
+            self.id.to_string(),
+            self.class.to_string(),
+            self.tag_size.to_string(),
+            self.quality.to_string(),
+            self.confidence.to_string(),
+            self.inverted.to_string(),
+            self.mirrored.to_string(),
+        ];
+        fields.extend(self.translation.iter().map(|v| v.to_string()));
+        fields.extend(self.rotation.iter().map(|v| v.to_string()));
+        fields.extend(self.corners.iter().flat_map(|c| c.iter().map(|v| v.to_string())));
+        fields.join(",")
+    }
+}
+
+fn open_input(spec: &str, fps: f64) -> Box<dyn FrameSource> {
+    open_source(spec, fps).unwrap_or_else(|e| {
+        eprintln!("could not open {}: {}", spec, e);
+        std::process::exit(1);
+    })
+}
+
+fn rough_camera(dim: (u32, u32)) -> BrownConrady {
+    let f = dim.0.max(dim.1) as f64;
+    BrownConrady::new(
+        CameraIntrinsics {
+            focals: Vector2::new(f, f),
+            principal_point: Point2::new(dim.0 as f64 / 2.0, dim.1 as f64 / 2.0),
+            skew: 0.0,
+        },
+        [0.0; 5],
+    )
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Args {
+    calibration: Option<PathBuf>,
+    classes: Vec<LFTagClass>,
+    tag_size: Option<f64>,
+    polarity: Polarity,
+    mirrored: bool,
+    dictionary: Option<PathBuf>,
+    max_missing: usize,
+    csv: bool,
+    config: PoseConfig,
+    output: Option<PathBuf>,
+    annotate: Option<PathBuf>,
+    fps: f64,
+    track: bool,
+    inputs: Vec<String>,
+}
+
+impl Default for Args {
+    fn default() -> Args {
+        Args {
+            calibration: None,
+            classes: vec![LFTagClass::LFTag3x3, LFTagClass::LFTag4x4],
+            tag_size: None,
+            polarity: Polarity::default(),
+            mirrored: false,
+            dictionary: None,
+            max_missing: 0,
+            csv: cfg!(not(feature = "serde-serialize")),
+            config: PoseConfig::default(),
+            output: None,
+            annotate: None,
+            fps: 30.0,
+            track: false,
+            inputs: Vec::new(),
+        }
+    }
+}
+
+/// Parses the command line without the program name, the error says what was wrong.
+fn parse_args(args: &[String]) -> Result<Args, String> {
+    let mut parsed = Args::default();
+
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        let mut value = || iter.next().map(|v| v.as_str()).ok_or_else(|| format!("{} needs a value", arg));
+        let invalid = |value: &str| format!("invalid value {} for {}", value, arg);
+        match arg.as_str() {
+            "--calibration" => parsed.calibration = Some(PathBuf::from(value()?)),
+            "--family" => {
+                if value()? != "lftag" {
+                    return Err(String::from("only the lftag family is supported"));
+                }
+            }
+            "--class" => {
+                let list = value()?;
+                parsed.classes = list.split(',').map(LFTagClass::from_name).collect::<Option<_>>().ok_or_else(|| invalid(list))?;
+            }
+            "--polarity" => {
+                parsed.polarity = match value()? {
+                    "dark" => Polarity::DarkOnLight,
+                    "light" => Polarity::LightOnDark,
+                    "both" => Polarity::Both,
+                    v => return Err(invalid(v)),
+                }
+            }
+            "--mirrored" => parsed.mirrored = true,
+            #[cfg(feature = "serde-serialize")]
+            "--dictionary" => parsed.dictionary = Some(PathBuf::from(value()?)),
+            "--max-missing" => {
+                let v = value()?;
+                parsed.max_missing = v.parse().map_err(|_| invalid(v))?;
+            }
+            "--tag-size" => {
+                let v = value()?;
+                parsed.tag_size = Some(v.parse().map_err(|_| invalid(v))?);
+            }
+            "--format" => {
+                parsed.csv = match value()? {
+                    "csv" => true,
+                    #[cfg(feature = "serde-serialize")]
+                    "jsonl" => false,
+                    v => return Err(invalid(v)),
+                }
+            }
+            "--convention" => {
+                parsed.config.convention = match value()? {
+                    "opencv" => FrameConvention::OpenCv,
+                    "ros-optical" => FrameConvention::RosOptical,
+                    "ros-body" => FrameConvention::RosBody,
+                    "opengl" => FrameConvention::OpenGl,
+                    v => return Err(invalid(v)),
+                }
+            }
+            "--camera-in-tag" => parsed.config.form = PoseForm::CameraInTag,
+            "--output" => parsed.output = Some(PathBuf::from(value()?)),
+            "--annotate" => parsed.annotate = Some(PathBuf::from(value()?)),
+            "--fps" => {
+                let v = value()?;
+                parsed.fps = v.parse().map_err(|_| invalid(v))?;
+            }
+            "--track" => parsed.track = true,
+            a if a.starts_with("--") => return Err(format!("unknown option {}", a)),
+            a => parsed.inputs.push(a.to_string()),
+        }
+    }
+    if parsed.inputs.is_empty() {
+        return Err(String::from("no inputs"));
+    }
+    Ok(parsed)
+}
+
+fn main() -> io::Result<()> {
+    match run() {
+        // output piped into e.g. `head`, which stopped reading
+        Err(ref e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+        result => result,
+    }
+}
+
+fn run() -> io::Result<()> {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let args = parse_args(&args).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        usage();
+    });
+
+    let calibration = args.calibration.as_ref().map(|path| load_calibration(path).unwrap_or_else(|e| {
+        eprintln!("could not load calibration {}: {}", path.display(), e);
+        std::process::exit(1);
+    }));
+    #[cfg(feature = "serde-serialize")]
+    let dictionary = args.dictionary.as_ref().map(|path| Dictionary::load(path).unwrap_or_else(|e| {
+        eprintln!("could not load dictionary {}: {}", path.display(), e);
+        std::process::exit(1);
+    }));
+    #[cfg(not(feature = "serde-serialize"))]
+    let dictionary: Option<Dictionary> = None;
+    let tag_size = args.tag_size.map(TagSize::new).unwrap_or_default();
+    let mut output: Box<dyn Write> = match &args.output {
+        Some(path) => Box::new(BufWriter::new(File::create(path).unwrap_or_else(|e| {
+            eprintln!("could not create {}: {}", path.display(), e);
+            std::process::exit(1);
+        }))),
+        None => Box::new(BufWriter::new(io::stdout())),
+    };
+
+    if args.csv {
+        writeln!(output, "{}", Record::csv_header())?;
+    }
+
+    for spec in &args.inputs {
+        let mut source = open_input(spec, args.fps);
+        let mut tracking: Option<TrackingDetector<BrownConrady>> = None;
+
+        while let Some(frame) = source.next_frame() {
+            let frame = match frame {
+                Ok(frame) => frame,
+                Err(e) => {
+                    eprintln!("skipping frame: {}", e);
+                    continue;
+                }
+            };
+
+            let camera = calibration.map(|c| c.camera).unwrap_or_else(|| rough_camera(frame.image.dimensions()));
+            let detector = LFTagDetector {
+                camera,
+                classes: args.classes.clone(),
+                tag_size: tag_size.clone(),
+                polarity: args.polarity,
+                mirrored: args.mirrored,
+                max_missing: args.max_missing,
+                dictionary: dictionary.clone(),
+            };
+
+            let tags = if args.track {
+                let tracking = tracking.get_or_insert_with(|| TrackingDetector::new(detector, TrackerConfig::default()));
+                tracking.process(&frame.image, frame.timestamp).0
+            } else {
+                match detector.detect(&frame.image) {
+                    Some(tags) => tags,
+                    None => {
+                        eprintln!("skipping frame {} of {}: dimensions must be a multiple of 16", frame.index, spec);
+                        continue;
+                    }
+                }
+            };
+
+            let file = frame.name.clone().unwrap_or_else(|| spec.clone());
+            for tag in &tags {
+                let record = Record::new(&file, &frame, tag, &camera, &args.config);
+                #[cfg(feature = "serde-serialize")]
+                let line = if args.csv { record.csv_row() } else { serde_json::to_string(&record)? };
+                #[cfg(not(feature = "serde-serialize"))]
+                let line = record.csv_row();
+                writeln!(output, "{}", line)?;
+            }
+
+            if let Some(dir) = &args.annotate {
+                let mut annotated: ImageBuffer<Rgba<u8>, Vec<u8>> = frame.image.convert();
+                draw_lftags(&mut annotated, &tags, &camera);
+                let stem = Path::new(&file).file_stem().unwrap_or_default().to_string_lossy().into_owned();
+                let name = match frame.name {
+                    Some(_) => format!("{}_annotated.png", stem),
+                    None => format!("{}_{:06}_annotated.png", stem, frame.index),
+                };
+                if let Err(e) = annotated.save(dir.join(name)) {
+                    eprintln!("could not write annotated image for {}: {}", file, e);
+                }
+            }
+        }
+    }
+    output.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(line: &str) -> Result<Args, String> {
+        parse_args(&line.split_whitespace().map(String::from).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn defaults() {
+        let args = parse("a.png b.png").unwrap();
+        assert_eq!(args, Args { inputs: vec![String::from("a.png"), String::from("b.png")], ..Args::default() });
+        assert_eq!(args.classes, vec![LFTagClass::LFTag3x3, LFTagClass::LFTag4x4]);
+        assert_eq!(args.fps, 30.0);
+    }
+
+    #[test]
+    fn options() {
+        let args = parse("--calibration cam.yaml --class 2x2,5x5 --polarity both --mirrored --max-missing 2 \
+            --tag-size 0.05 --format csv --convention ros-optical --camera-in-tag --output out.csv \
+            --annotate dbg --fps 60 --track frames/*.png").unwrap();
+        assert_eq!(args.calibration, Some(PathBuf::from("cam.yaml")));
+        assert_eq!(args.classes, vec![LFTagClass::LFTag2x2, LFTagClass::LFTag5x5]);
+        assert_eq!(args.polarity, Polarity::Both);
+        assert!(args.mirrored);
+        assert_eq!(args.max_missing, 2);
+        assert_eq!(args.tag_size, Some(0.05));
+        assert!(args.csv);
+        assert_eq!(args.config.convention, FrameConvention::RosOptical);
+        assert_eq!(args.config.form, PoseForm::CameraInTag);
+        assert_eq!(args.output, Some(PathBuf::from("out.csv")));
+        assert_eq!(args.annotate, Some(PathBuf::from("dbg")));
+        assert_eq!(args.fps, 60.0);
+        assert!(args.track);
+        assert_eq!(args.inputs, vec![String::from("frames/*.png")]);
+    }
+
+    #[test]
+    #[cfg(feature = "serde-serialize")]
+    fn serde_options() {
+        let args = parse("--dictionary ids.json --format jsonl a.png").unwrap();
+        assert_eq!(args.dictionary, Some(PathBuf::from("ids.json")));
+        assert!(!args.csv);
+    }
+
+    #[test]
+    fn errors() {
+        assert!(parse("").is_err());
+        assert!(parse("--track").is_err());
+        assert!(parse("a.png --fps").is_err());
+        assert!(parse("--fps fast a.png").is_err());
+        assert!(parse("--class 3x3,6x6 a.png").is_err());
+        assert!(parse("--polarity grey a.png").is_err());
+        assert!(parse("--family apriltag a.png").is_err());
+        assert!(parse("--format xml a.png").is_err());
+        assert!(parse("--frobnicate a.png").is_err());
+    }
+}
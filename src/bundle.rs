@@ -0,0 +1,142 @@
+use cv_core::WorldPose;
+#[cfg(feature = "serde-serialize")]
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "serde-serialize")]
+use std::io;
+#[cfg(feature = "serde-serialize")]
+use std::path::Path;
+
+use crate::camera::Camera;
+use crate::decode::DecodedLFTag;
+use crate::localize::{pooled_pose, PooledPose, TagCorrespondences};
+use crate::pose::PoseRecord;
+#[cfg(feature = "serde-serialize")]
+use crate::serialize::{read_structured, write_structured};
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde-serialize", derive(Serialize, Deserialize))]
+pub struct BundleTag {
+    pub id: usize,
+    // printed edge length, in the units of the bundle
+    pub size: f64,
+    // tag frame in the object frame
+    pub pose: PoseRecord,
+}
+
+/// A rigid object carrying several tags at known poses, loaded from JSON, YAML or TOML.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde-serialize", derive(Serialize, Deserialize))]
+pub struct TagBundle {
+    pub tags: Vec<BundleTag>,
+}
+
+#[derive(Debug, Clone)]
+pub struct BundlePose {
+    // object frame in the camera frame
+    pub pose: WorldPose,
+    pub tags: Vec<usize>,
+    pub rejected_tags: Vec<usize>,
+    pub rms_error: f64,
+}
+
+/// Correspondences of every detection of a listed tag, with world points in the frame the tags are
+/// placed in. Detections of other tags are left out.
+pub(crate) fn member_correspondences(members: &[BundleTag], detections: &[DecodedLFTag]) -> Vec<TagCorrespondences> {
+    detections.iter().filter_map(|tag| {
+        let member = members.iter().find(|m| m.id == tag.data)?;
+        Some(TagCorrespondences::from_detection(tag, member.size, &member.pose.isometry()))
+    }).collect()
+}
+
+/// Pose of the frame the listed tags are placed in, pooled over all their detections.
+pub(crate) fn pooled_member_pose<C: Camera>(camera: &C, members: &[BundleTag], detections: &[DecodedLFTag], threshold: f64) -> Option<PooledPose> {
+    pooled_pose(camera, &member_correspondences(members, detections), threshold)
+}
+
+impl TagBundle {
+    #[cfg(feature = "serde-serialize")]
+    pub fn load(path: &Path) -> io::Result<TagBundle> {
+        read_structured(path)
+    }
+
+    #[cfg(feature = "serde-serialize")]
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        write_structured(path, self)
+    }
+
+    pub fn get(&self, id: usize) -> Option<&BundleTag> {
+        self.tags.iter().find(|t| t.id == id)
+    }
+
+    /// Joint pose of the object from every visible member tag. Tags reprojecting worse than
+    /// `threshold` pixels under the joint pose are left out.
+    pub fn solve<C: Camera>(&self, camera: &C, detections: &[DecodedLFTag], threshold: f64) -> Option<BundlePose> {
+        let pooled = pooled_member_pose(camera, &self.tags, detections, threshold)?;
+
+        Some(BundlePose {
+            pose: WorldPose(pooled.pose),
+            tags: pooled.inliers,
+            rejected_tags: pooled.outliers,
+            rms_error: pooled.rms,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera::BrownConrady;
+    use crate::decode::LFTagClass;
+    use cv_pinhole::CameraIntrinsics;
+    use nalgebra::{IsometryMatrix3, Point2, Rotation3, Translation3, Vector2};
+    use rand::rngs::SmallRng;
+    use rand::SeedableRng;
+
+    fn camera() -> BrownConrady {
+        BrownConrady::new(
+            CameraIntrinsics {
+                focals: Vector2::new(800.0, 800.0),
+                principal_point: Point2::new(320.0, 240.0),
+                skew: 0.0,
+            },
+            [0.0; 5],
+        )
+    }
+
+    fn member(id: usize, x: f64, y: f64, tilt: f64) -> BundleTag {
+        BundleTag {
+            id,
+            size: 0.05,
+            pose: PoseRecord::from(IsometryMatrix3::from_parts(Translation3::new(x, y, 0.0), Rotation3::from_euler_angles(tilt, 0.0, 0.0))),
+        }
+    }
+
+    #[test]
+    fn solves_object_frame() {
+        let camera = camera();
+        // the object frame sits at tag 1, which is hidden in the image
+        let bundle = TagBundle {
+            tags: vec![member(1, 0.0, 0.0, 0.0), member(2, 0.1, 0.0, 0.2), member(3, 0.0, 0.1, -0.2), member(4, 0.1, 0.1, 0.0)],
+        };
+        let object = IsometryMatrix3::from_parts(Translation3::new(-0.1, -0.05, 0.5), Rotation3::from_euler_angles(0.3, -0.2, 0.1));
+
+        let mut rng = SmallRng::from_seed([3; 16]);
+        let mut detections: Vec<DecodedLFTag> = bundle.tags[1..].iter()
+            .map(|t| DecodedLFTag::synthetic_sized(&camera, LFTagClass::LFTag3x3, t.id, t.size, &(object * t.pose.isometry()), 0.3, &mut rng))
+            .collect();
+        // another tag somewhere else in the image is not part of the object
+        let stray = IsometryMatrix3::from_parts(Translation3::new(0.1, 0.1, 0.6), Rotation3::identity());
+        detections.push(DecodedLFTag::synthetic_sized(&camera, LFTagClass::LFTag3x3, 8, 0.05, &stray, 0.3, &mut rng));
+
+        let solved = bundle.solve(&camera, &detections, 3.0).unwrap();
+        let mut tags = solved.tags.clone();
+        tags.sort();
+        assert_eq!(tags, vec![2, 3, 4]);
+        assert!(solved.rejected_tags.is_empty());
+        assert!(solved.rms_error < 0.5);
+        assert!((solved.pose.0.translation.vector - object.translation.vector).norm() < 5e-3);
+        assert!((solved.pose.0.rotation.inverse() * object.rotation).angle() < 1e-2);
+
+        assert!(bundle.solve(&camera, &detections[3..], 3.0).is_none());
+    }
+}
@@ -0,0 +1,589 @@
+use cv_pinhole::CameraIntrinsics;
+use nalgebra::{IsometryMatrix3, Matrix3, Point2, Point3, SymmetricEigen, Vector2, MatrixN, U9};
+#[cfg(feature = "serde-serialize")]
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+#[cfg(feature = "serde-serialize")]
+use std::io;
+#[cfg(feature = "serde-serialize")]
+use std::path::Path;
+
+use crate::bundle::BundleTag;
+use crate::camera::BrownConrady;
+use crate::decode::DecodedLFTag;
+use crate::localize::{node_correspondences, opencv_localize, project};
+use crate::marker_map::MarkerMap;
+use crate::optimize::{levenberg_marquardt, pose_from_params, pose_to_params, LeastSquares};
+use crate::pose::PoseRecord;
+#[cfg(feature = "serde-serialize")]
+use crate::serialize::read_structured;
+use crate::rig::{Rig, RigCamera};
+
+const MIN_POINTS: usize = 12;
+
+/// A tag printed on a calibration board, `(x, y)` is the position of the top left corner of the
+/// tag on the board.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde-serialize", derive(Serialize, Deserialize))]
+pub struct BoardTag {
+    pub id: usize,
+    pub x: f64,
+    pub y: f64,
+}
+
+/// Planar board with several tags of the same printed size, loaded from JSON, YAML or TOML:
+/// `{"tag_size": 0.05, "tags": [{"id": 0, "x": 0.0, "y": 0.0}, ...]}`
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde-serialize", derive(Serialize, Deserialize))]
+pub struct Board {
+    pub tag_size: f64,
+    pub tags: Vec<BoardTag>,
+}
+
+impl Board {
+    #[cfg(feature = "serde-serialize")]
+    pub fn load(path: &Path) -> io::Result<Board> {
+        read_structured(path)
+    }
+
+    /// Pairs every detected node with its position on the board.
+    pub fn correspondences(&self, detections: &[DecodedLFTag]) -> Vec<(Point3<f64>, Point2<f64>)> {
+        let mut out = Vec::new();
+
+        for tag in detections {
+            if let Some(board_tag) = self.tags.iter().find(|t| t.id == tag.data) {
+                out.extend(node_correspondences(tag, self.tag_size).into_iter()
+                    .map(|(world, image)| (Point3::new(board_tag.x + world.x, board_tag.y + world.y, 0.0), image)));
+            }
+        }
+        out
+    }
+
+    /// The board as a marker map, with the board frame as the world frame.
+    pub fn to_marker_map(&self) -> MarkerMap {
+        MarkerMap {
+            tags: self.tags.iter().map(|t| BundleTag {
+                id: t.id,
+                size: self.tag_size,
+                pose: PoseRecord {
+                    translation: [t.x, t.y, 0.0],
+                    rotation: [0.0; 3],
+                },
+            }).collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Calibration {
+    pub camera: BrownConrady,
+    // per input image, None if the image had too few points to be used
+    pub poses: Vec<Option<IsometryMatrix3<f64>>>,
+    pub image_errors: Vec<Option<f64>>,
+    pub rms: f64,
+}
+
+// skew is not a parameter, it stays at the value it started from
+fn camera_from_params(params: &[f64], skew: f64) -> BrownConrady {
+    BrownConrady::new(
+        CameraIntrinsics {
+            focals: Vector2::new(params[0], params[1]),
+            principal_point: Point2::new(params[2], params[3]),
+            skew,
+        },
+        [params[4], params[5], params[6], params[7], params[8]],
+    )
+}
+
+struct CalibrationProblem<'a> {
+    observations: Vec<&'a [(Point3<f64>, Point2<f64>)]>,
+}
+
+impl<'a> LeastSquares for CalibrationProblem<'a> {
+    fn blocks(&self) -> usize {
+        self.observations.len()
+    }
+
+    fn block_params(&self, block: usize) -> Vec<usize> {
+        (0..9).chain(9 + block * 6..15 + block * 6).collect()
+    }
+
+    fn residuals(&self, block: usize, params: &[f64]) -> Vec<f64> {
+        let camera = camera_from_params(params, 0.0);
+        let pose = pose_from_params(&params[9 + block * 6..15 + block * 6]);
+
+        self.observations[block].iter().flat_map(|(world, image)| {
+            let p = project(&camera, &pose, world);
+            vec![p.x - image.x, p.y - image.y]
+        }).collect()
+    }
+}
+
+fn homography(points: &[(Point3<f64>, Point2<f64>)]) -> Matrix3<f64> {
+    let mut ata = MatrixN::<f64, U9>::zeros();
+
+    for (w, i) in points {
+        let rows = [
+            [w.x, w.y, 1.0, 0.0, 0.0, 0.0, -i.x * w.x, -i.x * w.y, -i.x],
+            [0.0, 0.0, 0.0, w.x, w.y, 1.0, -i.y * w.x, -i.y * w.y, -i.y],
+        ];
+        for row in &rows {
+            for a in 0..9 {
+                for b in 0..9 {
+                    ata[(a, b)] += row[a] * row[b];
+                }
+            }
+        }
+    }
+
+    let eigen = SymmetricEigen::new(ata);
+    let (min_idx, _) = eigen.eigenvalues.iter().enumerate()
+        .fold((0, std::f64::MAX), |best, (idx, v)| if *v < best.1 { (idx, *v) } else { best });
+    let h = eigen.eigenvectors.column(min_idx);
+
+    Matrix3::new(h[0], h[1], h[2], h[3], h[4], h[5], h[6], h[7], h[8])
+}
+
+/// Closed form focal length estimate from the board homographies, with the principal point fixed
+/// at the image center and zero skew (Zhang's method with two unknowns).
+fn initial_focals(observations: &[&[(Point3<f64>, Point2<f64>)]], center: Point2<f64>, scale: f64) -> Option<(f64, f64)> {
+    let mut ata = nalgebra::Matrix2::zeros();
+    let mut atb = Vector2::zeros();
+
+    for obs in observations {
+        // centered and scaled down to keep the homography well conditioned
+        let centered: Vec<_> = obs.iter()
+            .map(|(w, i)| (*w, Point2::new((i.x - center.x) / scale, (i.y - center.y) / scale)))
+            .collect();
+        let h = homography(&centered);
+
+        let rows = [
+            (h[(0, 0)] * h[(0, 1)], h[(1, 0)] * h[(1, 1)], -h[(2, 0)] * h[(2, 1)]),
+            (
+                h[(0, 0)].powi(2) - h[(0, 1)].powi(2),
+                h[(1, 0)].powi(2) - h[(1, 1)].powi(2),
+                -(h[(2, 0)].powi(2) - h[(2, 1)].powi(2)),
+            ),
+        ];
+        for (a, b, c) in &rows {
+            let row = Vector2::new(*a, *b);
+            ata += row * row.transpose();
+            atb += row * *c;
+        }
+    }
+
+    let inv_sq = ata.try_inverse()? * atb;
+    if inv_sq[0] <= 0.0 || inv_sq[1] <= 0.0 {
+        return None;
+    }
+    Some((scale / inv_sq[0].sqrt(), scale / inv_sq[1].sqrt()))
+}
+
+/// Solves for intrinsics and distortion by bundle adjustment over every node seen on the board,
+/// `observations` holds the board to image correspondences for each image. Skew is fixed at zero,
+/// as in `cv::calibrateCamera`.
+pub fn calibrate_camera(observations: &[Vec<(Point3<f64>, Point2<f64>)>], image_size: (u32, u32)) -> Option<Calibration> {
+    let used: Vec<usize> = (0..observations.len()).filter(|&i| observations[i].len() >= MIN_POINTS).collect();
+    if used.is_empty() {
+        return None;
+    }
+
+    let center = Point2::new(image_size.0 as f64 / 2.0, image_size.1 as f64 / 2.0);
+    let mut used_obs: Vec<Vec<(Point3<f64>, Point2<f64>)>> = used.iter().map(|&i| observations[i].clone()).collect();
+    let slices: Vec<&[(Point3<f64>, Point2<f64>)]> = used_obs.iter().map(|o| o.as_slice()).collect();
+
+    let fallback = image_size.0.max(image_size.1) as f64;
+    let (fx, fy) = initial_focals(&slices, center, fallback).unwrap_or((fallback, fallback));
+
+    let mut params = vec![fx, fy, center.x, center.y, 0.0, 0.0, 0.0, 0.0, 0.0];
+    let initial_camera = camera_from_params(&params, 0.0);
+    for obs in &used_obs {
+        let world: Vec<(f32, f32)> = obs.iter().map(|(w, _)| (w.x as f32, w.y as f32)).collect();
+        let image: Vec<(f32, f32)> = obs.iter().map(|(_, i)| (i.x as f32, i.y as f32)).collect();
+        let pose = opencv_localize(&initial_camera, &world, &image);
+        params.extend_from_slice(&pose_to_params(&pose.0));
+    }
+
+    levenberg_marquardt(&CalibrationProblem { observations: slices }, &mut params, 100);
+
+    // drop gross outliers (usually misread tag ids) and refine once more
+    let camera = camera_from_params(&params, 0.0);
+    for (idx, obs) in used_obs.iter_mut().enumerate() {
+        let pose = pose_from_params(&params[9 + idx * 6..15 + idx * 6]);
+        let errors: Vec<f64> = obs.iter().map(|(w, i)| nalgebra::distance(&project(&camera, &pose, w), i)).collect();
+        let rms = (errors.iter().map(|e| e * e).sum::<f64>() / errors.len() as f64).sqrt();
+        let mut errors = errors.into_iter();
+        obs.retain(|_| errors.next().unwrap() <= (3.0 * rms).max(1.0));
+    }
+    let slices: Vec<&[(Point3<f64>, Point2<f64>)]> = used_obs.iter().map(|o| o.as_slice()).collect();
+    levenberg_marquardt(&CalibrationProblem { observations: slices }, &mut params, 100);
+
+    let camera = camera_from_params(&params, 0.0);
+    let mut poses = vec![None; observations.len()];
+    let mut image_errors = vec![None; observations.len()];
+    let mut total_sq = 0.0;
+    let mut total_count = 0;
+
+    for (idx, &image_idx) in used.iter().enumerate() {
+        let pose = pose_from_params(&params[9 + idx * 6..15 + idx * 6]);
+        let sq: f64 = used_obs[idx].iter()
+            .map(|(w, i)| nalgebra::distance_squared(&project(&camera, &pose, w), i))
+            .sum();
+
+        total_sq += sq;
+        total_count += used_obs[idx].len();
+        image_errors[image_idx] = Some((sq / used_obs[idx].len() as f64).sqrt());
+        poses[image_idx] = Some(pose);
+    }
+
+    Some(Calibration {
+        camera,
+        poses,
+        image_errors,
+        rms: (total_sq / total_count as f64).sqrt(),
+    })
+}
+
+/// Observations of the calibration target by one camera of a rig in one synchronized snapshot.
+#[derive(Debug, Clone)]
+pub struct RigView {
+    pub camera: usize,
+    pub snapshot: usize,
+    // name of the camera, kept in the calibrated rig
+    pub name: String,
+    // target frame in the camera frame, e.g. from `MarkerMap::localize`
+    pub target_pose: IsometryMatrix3<f64>,
+    pub points: Vec<(Point3<f64>, Point2<f64>)>,
+}
+
+#[derive(Debug, Clone)]
+pub struct RigCalibration {
+    // the rig frame is the frame of the first camera
+    pub rig: Rig<BrownConrady>,
+    // target frame in the rig frame for each snapshot, None if no camera saw the target
+    pub target_poses: Vec<Option<IsometryMatrix3<f64>>>,
+    pub camera_errors: Vec<Option<f64>>,
+    pub rms: f64,
+}
+
+// skew is left out, `camera_from_params` takes it from the camera being refined
+fn camera_to_params(camera: &BrownConrady) -> [f64; 9] {
+    let i = &camera.intrinsics;
+    let d = &camera.distortion;
+    [i.focals[0], i.focals[1], i.principal_point[0], i.principal_point[1], d[0], d[1], d[2], d[3], d[4]]
+}
+
+struct RigProblem<'a> {
+    views: &'a [RigView],
+    cameras: &'a [BrownConrady],
+    // intrinsics occupy the first 9 parameters of each camera when refined
+    refine_intrinsics: bool,
+    // None for the reference camera
+    extrinsic_offsets: &'a [Option<usize>],
+    snapshot_offsets: &'a HashMap<usize, usize>,
+}
+
+impl<'a> RigProblem<'a> {
+    fn camera(&self, camera: usize, params: &[f64]) -> BrownConrady {
+        if self.refine_intrinsics {
+            camera_from_params(&params[camera * 9..camera * 9 + 9], self.cameras[camera].intrinsics.skew)
+        } else {
+            self.cameras[camera]
+        }
+    }
+
+    fn extrinsics(&self, camera: usize, params: &[f64]) -> IsometryMatrix3<f64> {
+        match self.extrinsic_offsets[camera] {
+            Some(offset) => pose_from_params(&params[offset..offset + 6]),
+            None => IsometryMatrix3::identity(),
+        }
+    }
+}
+
+impl<'a> LeastSquares for RigProblem<'a> {
+    fn blocks(&self) -> usize {
+        self.views.len()
+    }
+
+    fn block_params(&self, block: usize) -> Vec<usize> {
+        let view = &self.views[block];
+        let snapshot = self.snapshot_offsets[&view.snapshot];
+        let mut out: Vec<usize> = (snapshot..snapshot + 6).collect();
+        if let Some(offset) = self.extrinsic_offsets[view.camera] {
+            out.extend(offset..offset + 6);
+        }
+        if self.refine_intrinsics {
+            out.extend(view.camera * 9..view.camera * 9 + 9);
+        }
+        out
+    }
+
+    fn residuals(&self, block: usize, params: &[f64]) -> Vec<f64> {
+        let view = &self.views[block];
+        let snapshot = self.snapshot_offsets[&view.snapshot];
+        let camera = self.camera(view.camera, params);
+        let pose = self.extrinsics(view.camera, params) * pose_from_params(&params[snapshot..snapshot + 6]);
+
+        view.points.iter().flat_map(|(world, image)| {
+            let p = project(&camera, &pose, world);
+            vec![p.x - image.x, p.y - image.y]
+        }).collect()
+    }
+}
+
+/// Solves the poses of all cameras of a rig relative to the first from synchronized views of a
+/// common target, optionally refining the intrinsics too. Cameras that never share a snapshot
+/// with a camera already placed are left out and the function returns None.
+pub fn calibrate_rig(cameras: &[BrownConrady], views: &[RigView], refine_intrinsics: bool) -> Option<RigCalibration> {
+    let views: Vec<RigView> = views.iter().filter(|v| v.points.len() >= MIN_POINTS).cloned().collect();
+
+    // place cameras one at a time through snapshots shared with a placed camera
+    let mut extrinsics: Vec<Option<IsometryMatrix3<f64>>> = vec![None; cameras.len()];
+    *extrinsics.get_mut(0)? = Some(IsometryMatrix3::identity());
+    loop {
+        let mut placed = false;
+        for known in &views {
+            let rig_in_known = match extrinsics[known.camera] {
+                Some(p) => p,
+                None => continue,
+            };
+            let found: Vec<(usize, IsometryMatrix3<f64>)> = views.iter()
+                .filter(|v| v.snapshot == known.snapshot && extrinsics[v.camera].is_none())
+                .map(|v| (v.camera, v.target_pose * known.target_pose.inverse() * rig_in_known))
+                .collect();
+            for (camera, pose) in found {
+                if extrinsics[camera].is_none() {
+                    extrinsics[camera] = Some(pose);
+                    placed = true;
+                }
+            }
+        }
+        if !placed {
+            break;
+        }
+    }
+    if extrinsics.iter().any(|e| e.is_none()) {
+        return None;
+    }
+
+    let mut params = Vec::new();
+    if refine_intrinsics {
+        for camera in cameras {
+            params.extend_from_slice(&camera_to_params(camera));
+        }
+    }
+
+    let extrinsic_offsets: Vec<Option<usize>> = extrinsics.iter().enumerate().map(|(idx, pose)| {
+        if idx == 0 {
+            return None;
+        }
+        let offset = params.len();
+        params.extend_from_slice(&pose_to_params(pose.as_ref().unwrap()));
+        Some(offset)
+    }).collect();
+
+    let mut snapshot_offsets = HashMap::new();
+    for view in &views {
+        if !snapshot_offsets.contains_key(&view.snapshot) {
+            let target_in_rig = extrinsics[view.camera].unwrap().inverse() * view.target_pose;
+            snapshot_offsets.insert(view.snapshot, params.len());
+            params.extend_from_slice(&pose_to_params(&target_in_rig));
+        }
+    }
+
+    let problem = RigProblem {
+        views: &views,
+        cameras,
+        refine_intrinsics,
+        extrinsic_offsets: &extrinsic_offsets,
+        snapshot_offsets: &snapshot_offsets,
+    };
+    let cost = levenberg_marquardt(&problem, &mut params, 100);
+
+    let mut camera_sq = vec![(0.0, 0); cameras.len()];
+    for (block, view) in views.iter().enumerate() {
+        let residuals = problem.residuals(block, &params);
+        camera_sq[view.camera].0 += residuals.iter().map(|r| r * r).sum::<f64>();
+        camera_sq[view.camera].1 += view.points.len();
+    }
+    let point_count: usize = views.iter().map(|v| v.points.len()).sum();
+    let snapshots = views.iter().map(|v| v.snapshot + 1).max().unwrap_or(0);
+
+    Some(RigCalibration {
+        rig: Rig::new((0..cameras.len()).map(|idx| RigCamera {
+            name: views.iter().find(|v| v.camera == idx).map_or_else(|| format!("camera{}", idx), |v| v.name.clone()),
+            camera: problem.camera(idx, &params),
+            pose: problem.extrinsics(idx, &params),
+        }).collect()),
+        target_poses: (0..snapshots).map(|s| {
+            snapshot_offsets.get(&s).map(|&offset| pose_from_params(&params[offset..offset + 6]))
+        }).collect(),
+        camera_errors: camera_sq.iter().map(|(sq, n)| if *n > 0 { Some((sq / *n as f64).sqrt()) } else { None }).collect(),
+        rms: (cost / point_count as f64).sqrt(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nalgebra::{Rotation3, Translation3};
+    use rand::rngs::SmallRng;
+    use rand::{Rng, SeedableRng};
+
+    fn true_camera() -> BrownConrady {
+        BrownConrady::new(
+            CameraIntrinsics {
+                focals: Vector2::new(600.0, 590.0),
+                principal_point: Point2::new(325.0, 236.0),
+                skew: 0.0,
+            },
+            [-0.2, 0.06, 5e-4, -3e-4, 0.0],
+        )
+    }
+
+    // 8 by 6 nodes 4cm apart, board frame at its top left node
+    fn board() -> Vec<Point3<f64>> {
+        (0..6).flat_map(|y| (0..8).map(move |x| Point3::new(x as f64 * 0.04, y as f64 * 0.04, 0.0))).collect()
+    }
+
+    // board in camera, centered at (x, y, z) and tilted by the euler angles
+    fn view(x: f64, y: f64, z: f64, roll: f64, pitch: f64, yaw: f64) -> IsometryMatrix3<f64> {
+        IsometryMatrix3::from_parts(Translation3::new(x, y, z), Rotation3::from_euler_angles(roll, pitch, yaw))
+            * IsometryMatrix3::translation(-0.14, -0.1, 0.0)
+    }
+
+    #[test]
+    fn recovers_synthetic_board() {
+        let camera = true_camera();
+        let mut rng = SmallRng::from_seed([3; 16]);
+        let views = [
+            view(0.0, 0.0, 0.6, 0.0, 0.0, 0.0),
+            view(-0.15, -0.1, 0.65, 0.35, -0.2, 0.1),
+            view(0.15, -0.1, 0.6, 0.3, 0.3, -0.1),
+            view(-0.15, 0.1, 0.7, -0.3, -0.3, 0.05),
+            view(0.15, 0.1, 0.65, -0.35, 0.25, 0.0),
+            view(0.0, 0.0, 0.8, 0.0, 0.45, 0.2),
+        ];
+        let mut observations: Vec<Vec<(Point3<f64>, Point2<f64>)>> = views.iter().map(|pose| {
+            board().into_iter().map(|w| {
+                let p = project(&camera, pose, &w);
+                (w, Point2::new(p.x + rng.gen_range(-0.1, 0.1), p.y + rng.gen_range(-0.1, 0.1)))
+            }).collect()
+        }).collect();
+        // too few nodes to take part
+        observations.push(observations[0][..MIN_POINTS - 1].to_vec());
+
+        let calibration = calibrate_camera(&observations, (640, 480)).unwrap();
+        assert!(calibration.rms < 0.1);
+        assert!(calibration.poses[views.len()].is_none());
+        assert!(calibration.image_errors[views.len()].is_none());
+
+        let found = calibration.camera;
+        assert!((found.intrinsics.focals - camera.intrinsics.focals).norm() < 3.0);
+        assert!((found.intrinsics.principal_point - camera.intrinsics.principal_point).norm() < 2.0);
+        assert!((found.distortion[0] - camera.distortion[0]).abs() < 0.02);
+        for (pose, expected) in calibration.poses.iter().zip(&views) {
+            let pose = pose.unwrap();
+            assert!((pose.translation.vector - expected.translation.vector).norm() < 2e-3);
+        }
+
+        // whatever split between the distortion terms was found, it has to project the same
+        for w in board() {
+            let expected = project(&camera, &views[1], &w);
+            assert!(nalgebra::distance(&project(&found, &views[1], &w), &expected) < 0.2);
+        }
+    }
+
+    fn assert_pose_near(found: &IsometryMatrix3<f64>, expected: &IsometryMatrix3<f64>, translation: f64, angle: f64) {
+        assert!((found.translation.vector - expected.translation.vector).norm() < translation);
+        assert!((found.rotation.inverse() * expected.rotation).angle() < angle);
+    }
+
+    // rig frame in each camera: the reference, one 20cm to its side and one below the second
+    fn rig_extrinsics() -> Vec<IsometryMatrix3<f64>> {
+        vec![
+            IsometryMatrix3::identity(),
+            IsometryMatrix3::from_parts(Translation3::new(-0.2, 0.0, 0.01), Rotation3::from_euler_angles(0.0, -0.1, 0.02)),
+            IsometryMatrix3::from_parts(Translation3::new(-0.2, -0.15, 0.0), Rotation3::from_euler_angles(0.08, -0.1, 0.0)),
+        ]
+    }
+
+    // cameras 0 and 1 share the first snapshots, 1 and 2 the last ones, so camera 2 is only placed
+    // through camera 1
+    fn rig_views(cameras: &[(usize, usize)], rng: &mut SmallRng) -> Vec<RigView> {
+        let camera = true_camera();
+        let extrinsics = rig_extrinsics();
+        let snapshots = [
+            view(0.1, 0.0, 0.8, 0.3, -0.2, 0.0),
+            view(0.05, 0.05, 0.7, -0.3, 0.25, 0.1),
+            view(0.15, -0.05, 0.75, 0.25, 0.3, -0.1),
+            view(0.1, 0.1, 0.85, -0.2, -0.3, 0.05),
+            view(0.0, -0.05, 0.7, 0.35, 0.1, 0.2),
+            view(0.12, 0.05, 0.8, -0.1, 0.35, -0.15),
+        ];
+        // targets localized by each camera alone are a little off
+        let error = IsometryMatrix3::from_parts(Translation3::new(0.005, -0.003, 0.01), Rotation3::from_euler_angles(0.01, -0.01, 0.0));
+
+        cameras.iter().map(|&(idx, snapshot)| {
+            let target_pose = extrinsics[idx] * snapshots[snapshot];
+            RigView {
+                camera: idx,
+                snapshot,
+                name: format!("cam{}", idx),
+                target_pose: error * target_pose,
+                points: board().into_iter().map(|w| {
+                    let p = project(&camera, &target_pose, &w);
+                    (w, Point2::new(p.x + rng.gen_range(-0.1, 0.1), p.y + rng.gen_range(-0.1, 0.1)))
+                }).collect(),
+            }
+        }).collect()
+    }
+
+    #[test]
+    fn chains_rig_extrinsics() {
+        let mut rng = SmallRng::from_seed([4; 16]);
+        let pairs = [(0, 0), (1, 0), (0, 1), (1, 1), (0, 2), (1, 2), (1, 3), (2, 3), (1, 4), (2, 4), (1, 5), (2, 5)];
+        let views = rig_views(&pairs, &mut rng);
+        let extrinsics = rig_extrinsics();
+        let cameras = [true_camera(); 3];
+
+        let calibration = calibrate_rig(&cameras, &views, false).unwrap();
+        assert_eq!(calibration.rig.cameras.iter().map(|c| c.name.as_str()).collect::<Vec<_>>(), vec!["cam0", "cam1", "cam2"]);
+        for (found, expected) in calibration.rig.cameras.iter().zip(&extrinsics) {
+            assert_pose_near(&found.pose, expected, 2e-3, 5e-3);
+        }
+        assert_eq!(calibration.target_poses.len(), 6);
+        assert!(calibration.target_poses.iter().all(|p| p.is_some()));
+        assert!(calibration.camera_errors.iter().all(|e| e.unwrap() < 0.15));
+        assert!(calibration.rms < 0.15);
+
+        // cameras that start a few percent off come back to the true intrinsics
+        let mut guesses = cameras;
+        for (idx, guess) in guesses.iter_mut().enumerate() {
+            guess.intrinsics.focals *= 1.0 + 0.02 * (idx as f64 + 1.0);
+            guess.intrinsics.principal_point.x -= 5.0;
+            guess.distortion = [0.0; 5];
+        }
+        let refined = calibrate_rig(&guesses, &views, true).unwrap();
+        for (found, expected) in refined.rig.cameras.iter().zip(&extrinsics) {
+            assert_pose_near(&found.pose, expected, 5e-3, 1e-2);
+            assert!((found.camera.intrinsics.focals - true_camera().intrinsics.focals).norm() < 9.0);
+            assert!((found.camera.intrinsics.principal_point - true_camera().intrinsics.principal_point).norm() < 6.0);
+        }
+        assert!(refined.rms < 0.15);
+    }
+
+    #[test]
+    fn rig_needs_shared_views() {
+        let mut rng = SmallRng::from_seed([4; 16]);
+        let cameras = [true_camera(); 3];
+
+        // camera 2 only ever sees the target alone
+        let views = rig_views(&[(0, 0), (1, 0), (0, 1), (1, 1), (2, 2)], &mut rng);
+        assert!(calibrate_rig(&cameras, &views, false).is_none());
+        // or not at all
+        let views = rig_views(&[(0, 0), (1, 0), (0, 1), (1, 1)], &mut rng);
+        assert!(calibrate_rig(&cameras, &views, false).is_none());
+        assert!(calibrate_rig(&cameras[..2], &views, false).is_some());
+    }
+}
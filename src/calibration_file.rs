@@ -0,0 +1,464 @@
+use cv_pinhole::CameraIntrinsics;
+use nalgebra::{Point2, Vector2};
+use opencv::core::{FileStorage, FileStorage_READ};
+use opencv::prelude::*;
+#[cfg(feature = "serde-serialize")]
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::camera::BrownConrady;
+
+/// Camera model loaded from or written to disk, along with the resolution it was calibrated at.
+#[derive(Debug, Clone, Copy)]
+pub struct CameraCalibration {
+    pub camera: BrownConrady,
+    pub image_size: Option<(u32, u32)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CalibrationFormat {
+    OpenCvYaml,
+    OpenCvXml,
+    RosYaml,
+    Json,
+    Toml,
+}
+
+impl CalibrationFormat {
+    /// Guesses the format from the extension, `.yaml` files are treated as ROS `camera_info`
+    /// unless they start with the OpenCV `%YAML` header.
+    pub fn from_path(path: &Path) -> Option<CalibrationFormat> {
+        match path.extension()?.to_str()?.to_lowercase().as_str() {
+            "xml" => Some(CalibrationFormat::OpenCvXml),
+            "json" => Some(CalibrationFormat::Json),
+            "toml" => Some(CalibrationFormat::Toml),
+            "yaml" | "yml" => {
+                match fs::read_to_string(path) {
+                    Ok(contents) if !contents.trim_start().starts_with("%YAML") => Some(CalibrationFormat::RosYaml),
+                    _ => Some(CalibrationFormat::OpenCvYaml),
+                }
+            }
+            _ => None,
+        }
+    }
+}
+
+fn invalid<E: ToString>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+fn distortion_from_slice(coefficients: &[f64]) -> io::Result<[f64; 5]> {
+    if coefficients.len() > 5 && coefficients[5..].iter().any(|c| *c != 0.0) {
+        return Err(invalid("only the 5 coefficient radial-tangential distortion model is supported"));
+    }
+    let mut out = [0.0; 5];
+    for (o, c) in out.iter_mut().zip(coefficients) {
+        *o = *c;
+    }
+    Ok(out)
+}
+
+fn from_matrix(k: &[f64], distortion: &[f64], image_size: Option<(u32, u32)>) -> io::Result<CameraCalibration> {
+    if k.len() != 9 {
+        return Err(invalid("camera matrix must be 3x3"));
+    }
+
+    Ok(CameraCalibration {
+        camera: BrownConrady::new(
+            CameraIntrinsics {
+                focals: Vector2::new(k[0], k[4]),
+                principal_point: Point2::new(k[2], k[5]),
+                skew: k[1],
+            },
+            distortion_from_slice(distortion)?,
+        ),
+        image_size,
+    })
+}
+
+impl CameraCalibration {
+    pub fn camera_matrix(&self) -> [f64; 9] {
+        let i = &self.camera.intrinsics;
+        [
+            i.focals[0], i.skew, i.principal_point[0],
+            0.0, i.focals[1], i.principal_point[1],
+            0.0, 0.0, 1.0,
+        ]
+    }
+}
+
+#[cfg(feature = "serde-serialize")]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RosMatrix {
+    rows: usize,
+    cols: usize,
+    data: Vec<f64>,
+}
+
+#[cfg(feature = "serde-serialize")]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RosCameraInfo {
+    image_width: u32,
+    image_height: u32,
+    #[serde(default)]
+    camera_name: String,
+    camera_matrix: RosMatrix,
+    distortion_model: String,
+    distortion_coefficients: RosMatrix,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rectification_matrix: Option<RosMatrix>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    projection_matrix: Option<RosMatrix>,
+}
+
+#[cfg(feature = "serde-serialize")]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct SimpleCalibration {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    width: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    height: Option<u32>,
+    fx: f64,
+    fy: f64,
+    cx: f64,
+    cy: f64,
+    #[serde(default)]
+    skew: f64,
+    // k1, k2, p1, p2, k3
+    #[serde(default)]
+    distortion: Vec<f64>,
+}
+
+#[cfg(feature = "serde-serialize")]
+pub fn from_ros_yaml(contents: &str) -> io::Result<CameraCalibration> {
+    let info: RosCameraInfo = serde_yaml::from_str(contents).map_err(invalid)?;
+    if info.distortion_model != "plumb_bob" && info.distortion_model != "rational_polynomial" {
+        return Err(invalid(format!("unsupported distortion model {}", info.distortion_model)));
+    }
+    from_matrix(&info.camera_matrix.data, &info.distortion_coefficients.data, Some((info.image_width, info.image_height)))
+}
+
+#[cfg(feature = "serde-serialize")]
+pub fn to_ros_yaml(calibration: &CameraCalibration) -> io::Result<String> {
+    let (width, height) = calibration.image_size.unwrap_or((0, 0));
+    let k = calibration.camera_matrix();
+    let mut projection = vec![0.0; 12];
+    for row in 0..3 {
+        for col in 0..3 {
+            projection[row * 4 + col] = k[row * 3 + col];
+        }
+    }
+
+    let info = RosCameraInfo {
+        image_width: width,
+        image_height: height,
+        camera_name: String::from("camera"),
+        camera_matrix: RosMatrix { rows: 3, cols: 3, data: k.to_vec() },
+        distortion_model: String::from("plumb_bob"),
+        distortion_coefficients: RosMatrix { rows: 1, cols: 5, data: calibration.camera.distortion.to_vec() },
+        rectification_matrix: Some(RosMatrix { rows: 3, cols: 3, data: vec![1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0] }),
+        projection_matrix: Some(RosMatrix { rows: 3, cols: 4, data: projection }),
+    };
+    serde_yaml::to_string(&info).map_err(invalid)
+}
+
+#[cfg(feature = "serde-serialize")]
+pub(crate) fn to_simple(calibration: &CameraCalibration) -> SimpleCalibration {
+    let i = &calibration.camera.intrinsics;
+    SimpleCalibration {
+        width: calibration.image_size.map(|s| s.0),
+        height: calibration.image_size.map(|s| s.1),
+        fx: i.focals[0],
+        fy: i.focals[1],
+        cx: i.principal_point[0],
+        cy: i.principal_point[1],
+        skew: i.skew,
+        distortion: calibration.camera.distortion.to_vec(),
+    }
+}
+
+#[cfg(feature = "serde-serialize")]
+pub(crate) fn from_simple(simple: SimpleCalibration) -> io::Result<CameraCalibration> {
+    let k = [simple.fx, simple.skew, simple.cx, 0.0, simple.fy, simple.cy, 0.0, 0.0, 1.0];
+    let image_size = match (simple.width, simple.height) {
+        (Some(w), Some(h)) => Some((w, h)),
+        _ => None,
+    };
+    from_matrix(&k, &simple.distortion, image_size)
+}
+
+#[cfg(feature = "serde-serialize")]
+pub fn from_json(contents: &str) -> io::Result<CameraCalibration> {
+    from_simple(serde_json::from_str(contents).map_err(invalid)?)
+}
+
+#[cfg(feature = "serde-serialize")]
+pub fn to_json(calibration: &CameraCalibration) -> io::Result<String> {
+    serde_json::to_string_pretty(&to_simple(calibration)).map_err(invalid)
+}
+
+#[cfg(feature = "serde-serialize")]
+pub fn from_toml(contents: &str) -> io::Result<CameraCalibration> {
+    from_simple(toml::from_str(contents).map_err(invalid)?)
+}
+
+#[cfg(feature = "serde-serialize")]
+pub fn to_toml(calibration: &CameraCalibration) -> io::Result<String> {
+    toml::to_string(&to_simple(calibration)).map_err(invalid)
+}
+
+fn read_opencv_mat(fs: &FileStorage, names: &[&str]) -> io::Result<Option<Vec<f64>>> {
+    for name in names {
+        let node = fs.get(name).map_err(invalid)?;
+        if node.empty().map_err(invalid)? {
+            continue;
+        }
+        let mat = node.mat().map_err(invalid)?;
+        let mut out = Vec::new();
+        for row in 0..mat.rows() {
+            for col in 0..mat.cols() {
+                out.push(*mat.at_2d::<f64>(row, col).map_err(invalid)?);
+            }
+        }
+        return Ok(Some(out));
+    }
+    Ok(None)
+}
+
+fn read_opencv_int(fs: &FileStorage, name: &str) -> io::Result<Option<u32>> {
+    let node = fs.get(name).map_err(invalid)?;
+    if node.empty().map_err(invalid)? {
+        return Ok(None);
+    }
+    Ok(Some(node.real().map_err(invalid)? as u32))
+}
+
+/// Reads the output of `cv::calibrateCamera` saved through `cv::FileStorage`, YAML or XML.
+pub fn load_opencv(path: &Path) -> io::Result<CameraCalibration> {
+    let path_str = path.to_str().ok_or_else(|| invalid("path is not valid unicode"))?;
+    let fs = FileStorage::new(path_str, FileStorage_READ, "").map_err(invalid)?;
+    if !fs.is_opened().map_err(invalid)? {
+        return Err(invalid(format!("could not open {}", path.display())));
+    }
+
+    let k = read_opencv_mat(&fs, &["camera_matrix", "cameraMatrix", "K"])?
+        .ok_or_else(|| invalid("missing camera_matrix"))?;
+    let distortion = read_opencv_mat(&fs, &["distortion_coefficients", "dist_coeffs", "distCoeffs", "D"])?
+        .unwrap_or_default();
+    let image_size = match (read_opencv_int(&fs, "image_width")?, read_opencv_int(&fs, "image_height")?) {
+        (Some(w), Some(h)) => Some((w, h)),
+        _ => None,
+    };
+
+    from_matrix(&k, &distortion, image_size)
+}
+
+fn format_list(values: &[f64]) -> String {
+    values.iter().map(|v| format!("{:?}", v)).collect::<Vec<_>>().join(", ")
+}
+
+pub fn to_opencv_yaml(calibration: &CameraCalibration) -> String {
+    let mut out = String::from("%YAML:1.0\n---\n");
+    if let Some((w, h)) = calibration.image_size {
+        out += &format!("image_width: {}\nimage_height: {}\n", w, h);
+    }
+    out += &format!(
+        "camera_matrix: !!opencv-matrix\n   rows: 3\n   cols: 3\n   dt: d\n   data: [ {} ]\n",
+        format_list(&calibration.camera_matrix())
+    );
+    out += &format!(
+        "distortion_coefficients: !!opencv-matrix\n   rows: 1\n   cols: 5\n   dt: d\n   data: [ {} ]\n",
+        format_list(&calibration.camera.distortion)
+    );
+    out
+}
+
+pub fn to_opencv_xml(calibration: &CameraCalibration) -> String {
+    let mut out = String::from("<?xml version=\"1.0\"?>\n<opencv_storage>\n");
+    if let Some((w, h)) = calibration.image_size {
+        out += &format!("<image_width>{}</image_width>\n<image_height>{}</image_height>\n", w, h);
+    }
+    let matrix = |name: &str, rows: usize, cols: usize, data: &[f64]| {
+        format!(
+            "<{0} type_id=\"opencv-matrix\">\n  <rows>{1}</rows>\n  <cols>{2}</cols>\n  <dt>d</dt>\n  <data>\n    {3}</data></{0}>\n",
+            name,
+            rows,
+            cols,
+            data.iter().map(|v| format!("{:?}", v)).collect::<Vec<_>>().join(" ")
+        )
+    };
+    out += &matrix("camera_matrix", 3, 3, &calibration.camera_matrix());
+    out += &matrix("distortion_coefficients", 1, 5, &calibration.camera.distortion);
+    out += "</opencv_storage>\n";
+    out
+}
+
+// ROS, JSON and TOML files go through serde
+#[cfg(not(feature = "serde-serialize"))]
+fn needs_serde(path: &Path) -> io::Error {
+    invalid(format!("{} needs the serde-serialize feature", path.display()))
+}
+
+pub fn load_calibration(path: &Path) -> io::Result<CameraCalibration> {
+    match CalibrationFormat::from_path(path) {
+        Some(CalibrationFormat::OpenCvYaml) | Some(CalibrationFormat::OpenCvXml) => load_opencv(path),
+        #[cfg(feature = "serde-serialize")]
+        Some(CalibrationFormat::RosYaml) => from_ros_yaml(&fs::read_to_string(path)?),
+        #[cfg(feature = "serde-serialize")]
+        Some(CalibrationFormat::Json) => from_json(&fs::read_to_string(path)?),
+        #[cfg(feature = "serde-serialize")]
+        Some(CalibrationFormat::Toml) => from_toml(&fs::read_to_string(path)?),
+        #[cfg(not(feature = "serde-serialize"))]
+        Some(_) => Err(needs_serde(path)),
+        None => Err(invalid(format!("unknown calibration format for {}", path.display()))),
+    }
+}
+
+pub fn save_calibration(path: &Path, calibration: &CameraCalibration, format: CalibrationFormat) -> io::Result<()> {
+    let contents = match format {
+        CalibrationFormat::OpenCvYaml => to_opencv_yaml(calibration),
+        CalibrationFormat::OpenCvXml => to_opencv_xml(calibration),
+        #[cfg(feature = "serde-serialize")]
+        CalibrationFormat::RosYaml => to_ros_yaml(calibration)?,
+        #[cfg(feature = "serde-serialize")]
+        CalibrationFormat::Json => to_json(calibration)?,
+        #[cfg(feature = "serde-serialize")]
+        CalibrationFormat::Toml => to_toml(calibration)?,
+        #[cfg(not(feature = "serde-serialize"))]
+        _ => return Err(needs_serde(path)),
+    };
+    fs::write(path, contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "serde-serialize")]
+    const ROS_CAMERA_INFO: &str = "image_width: 640
+image_height: 480
+camera_name: narrow_stereo
+camera_matrix:
+  rows: 3
+  cols: 3
+  data: [712.44128286, 0, 316.80287675, 0, 711.06570126, 228.46397532, 0, 0, 1]
+distortion_model: plumb_bob
+distortion_coefficients:
+  rows: 1
+  cols: 5
+  data: [0.1, -0.2, 0.001, 0.002, 0.05]
+rectification_matrix:
+  rows: 3
+  cols: 3
+  data: [1, 0, 0, 0, 1, 0, 0, 0, 1]
+projection_matrix:
+  rows: 3
+  cols: 4
+  data: [712.44128286, 0, 316.80287675, 0, 0, 711.06570126, 228.46397532, 0, 0, 0, 1, 0]
+";
+
+    // written by the OpenCV calibration sample through cv::FileStorage
+    const OPENCV_YAML: &str = "%YAML:1.0
+---
+calibration_time: \"Tue 14 Jan 2020 10:12:03 CET\"
+nr_of_frames: 12
+image_width: 1280
+image_height: 720
+board_width: 9
+board_height: 6
+square_size: 2.5000000372529030e-02
+flags: 0
+camera_matrix: !!opencv-matrix
+   rows: 3
+   cols: 3
+   dt: d
+   data: [ 9.1556072283257016e+02, 0., 6.4264027346518454e+02, 0.,
+       9.1488318637710474e+02, 3.5831098657422612e+02, 0., 0., 1. ]
+distortion_coefficients: !!opencv-matrix
+   rows: 5
+   cols: 1
+   dt: d
+   data: [ 1.0335958429093051e-01, -2.4129290138658516e-01,
+       -1.3373049612534539e-03, 1.3862546425359396e-03,
+       1.2397815011148612e-01 ]
+avg_reprojection_error: 2.6523913479838567e-01
+";
+
+    fn scratch_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("fiducial-calibration-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn assert_same(a: &CameraCalibration, b: &CameraCalibration) {
+        assert_eq!(a.camera_matrix(), b.camera_matrix());
+        assert_eq!(a.camera.distortion, b.camera.distortion);
+        assert_eq!(a.image_size, b.image_size);
+    }
+
+    #[test]
+    #[cfg(feature = "serde-serialize")]
+    fn reads_ros_camera_info() {
+        let calibration = from_ros_yaml(ROS_CAMERA_INFO).unwrap();
+        assert_eq!(calibration.image_size, Some((640, 480)));
+        assert_eq!(calibration.camera.intrinsics.focals[0], 712.44128286);
+        assert_eq!(calibration.camera.intrinsics.principal_point[1], 228.46397532);
+        assert_eq!(calibration.camera.distortion, [0.1, -0.2, 0.001, 0.002, 0.05]);
+    }
+
+    #[test]
+    #[cfg(feature = "serde-serialize")]
+    fn roundtrips_text_formats() {
+        let calibration = from_ros_yaml(ROS_CAMERA_INFO).unwrap();
+
+        assert_same(&calibration, &from_ros_yaml(&to_ros_yaml(&calibration).unwrap()).unwrap());
+        assert_same(&calibration, &from_json(&to_json(&calibration).unwrap()).unwrap());
+        assert_same(&calibration, &from_toml(&to_toml(&calibration).unwrap()).unwrap());
+    }
+
+    #[test]
+    fn reads_opencv_file_storage() {
+        let path = scratch_dir("sample").join("camera.yaml");
+        fs::write(&path, OPENCV_YAML).unwrap();
+        assert_eq!(CalibrationFormat::from_path(&path), Some(CalibrationFormat::OpenCvYaml));
+
+        let calibration = load_calibration(&path).unwrap();
+        assert_eq!(calibration.image_size, Some((1280, 720)));
+        assert_eq!(calibration.camera.intrinsics.focals[0], 9.1556072283257016e+02);
+        assert_eq!(calibration.camera.intrinsics.principal_point[1], 3.5831098657422612e+02);
+        assert_eq!(calibration.camera.intrinsics.skew, 0.0);
+        assert_eq!(calibration.camera.distortion[4], 1.2397815011148612e-01);
+    }
+
+    #[test]
+    fn roundtrips_opencv_formats() {
+        let dir = scratch_dir("roundtrip");
+        let mut calibration = CameraCalibration {
+            camera: BrownConrady::new(
+                CameraIntrinsics {
+                    focals: Vector2::new(712.44128286, 711.06570126),
+                    principal_point: Point2::new(316.80287675, 228.46397532),
+                    skew: 0.25,
+                },
+                [0.1, -0.2, 0.001, 0.002, 0.05],
+            ),
+            image_size: Some((640, 480)),
+        };
+
+        for &(name, format) in &[("camera.yaml", CalibrationFormat::OpenCvYaml), ("camera.xml", CalibrationFormat::OpenCvXml)] {
+            let path = dir.join(name);
+            save_calibration(&path, &calibration, format).unwrap();
+            assert_eq!(CalibrationFormat::from_path(&path), Some(format));
+            assert_same(&calibration, &load_calibration(&path).unwrap());
+        }
+
+        // the image size is optional
+        calibration.image_size = None;
+        let path = dir.join("no_size.xml");
+        fs::write(&path, to_opencv_xml(&calibration)).unwrap();
+        assert_same(&calibration, &load_opencv(&path).unwrap());
+    }
+}
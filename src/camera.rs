@@ -0,0 +1,205 @@
+use cv_core::{CameraModel, ImagePoint, KeyPoint};
+use cv_pinhole::{CameraIntrinsics, NormalizedKeyPoint};
+use nalgebra::Point2;
+#[cfg(feature = "serde-serialize")]
+use serde::{Deserialize, Serialize};
+
+/// Anything that maps pixels to normalized image coordinates and back can be used by the
+/// decoder and localizer. Implement `cv_core::CameraModel` for a custom model to plug it in.
+pub trait Camera: CameraModel<Projection = NormalizedKeyPoint> {}
+
+impl<T: CameraModel<Projection = NormalizedKeyPoint>> Camera for T {}
+
+/// Pinhole camera with radial and tangential distortion, coefficients in OpenCV order
+/// `[k1, k2, p1, p2, k3]`.
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde-serialize", derive(Serialize, Deserialize))]
+pub struct BrownConrady {
+    #[cfg_attr(feature = "serde-serialize", serde(with = "crate::serialize::intrinsics"))]
+    pub intrinsics: CameraIntrinsics,
+    pub distortion: [f64; 5],
+}
+
+impl BrownConrady {
+    pub fn new(intrinsics: CameraIntrinsics, distortion: [f64; 5]) -> BrownConrady {
+        BrownConrady {
+            intrinsics,
+            distortion,
+        }
+    }
+
+    pub fn distort(&self, point: Point2<f64>) -> Point2<f64> {
+        let [k1, k2, p1, p2, k3] = self.distortion;
+        let (x, y) = (point.x, point.y);
+        let r2 = x * x + y * y;
+        let radial = 1.0 + k1 * r2 + k2 * r2 * r2 + k3 * r2 * r2 * r2;
+
+        Point2::new(
+            x * radial + 2.0 * p1 * x * y + p2 * (r2 + 2.0 * x * x),
+            y * radial + p1 * (r2 + 2.0 * y * y) + 2.0 * p2 * x * y,
+        )
+    }
+
+    pub fn undistort(&self, point: Point2<f64>) -> Point2<f64> {
+        let [k1, k2, p1, p2, k3] = self.distortion;
+        let mut x = point.x;
+        let mut y = point.y;
+
+        // same fixed point iteration as cv::undistortPoints
+        for _ in 0..20 {
+            let r2 = x * x + y * y;
+            let radial = 1.0 + k1 * r2 + k2 * r2 * r2 + k3 * r2 * r2 * r2;
+            let dx = 2.0 * p1 * x * y + p2 * (r2 + 2.0 * x * x);
+            let dy = p1 * (r2 + 2.0 * y * y) + 2.0 * p2 * x * y;
+            x = (point.x - dx) / radial;
+            y = (point.y - dy) / radial;
+        }
+
+        Point2::new(x, y)
+    }
+}
+
+impl CameraModel for BrownConrady {
+    type Projection = NormalizedKeyPoint;
+
+    fn calibrate<P>(&self, point: P) -> NormalizedKeyPoint
+    where
+        P: ImagePoint,
+    {
+        let distorted = self.intrinsics.calibrate(point);
+        NormalizedKeyPoint(self.undistort(distorted.0))
+    }
+
+    fn uncalibrate(&self, projection: NormalizedKeyPoint) -> KeyPoint {
+        self.intrinsics.uncalibrate(NormalizedKeyPoint(self.distort(projection.0)))
+    }
+}
+
+/// Equidistant fisheye model with coefficients `[k1, k2, k3, k4]`, matching `cv::fisheye`.
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde-serialize", derive(Serialize, Deserialize))]
+pub struct KannalaBrandt {
+    #[cfg_attr(feature = "serde-serialize", serde(with = "crate::serialize::intrinsics"))]
+    pub intrinsics: CameraIntrinsics,
+    pub distortion: [f64; 4],
+}
+
+impl KannalaBrandt {
+    pub fn new(intrinsics: CameraIntrinsics, distortion: [f64; 4]) -> KannalaBrandt {
+        KannalaBrandt {
+            intrinsics,
+            distortion,
+        }
+    }
+
+    fn theta_d(&self, theta: f64) -> f64 {
+        let [k1, k2, k3, k4] = self.distortion;
+        let t2 = theta * theta;
+        theta * (1.0 + t2 * (k1 + t2 * (k2 + t2 * (k3 + t2 * k4))))
+    }
+
+    pub fn distort(&self, point: Point2<f64>) -> Point2<f64> {
+        let r = point.coords.norm();
+        if r < 1e-12 {
+            return point;
+        }
+        let theta_d = self.theta_d(r.atan());
+        Point2::from(point.coords * (theta_d / r))
+    }
+
+    pub fn undistort(&self, point: Point2<f64>) -> Point2<f64> {
+        let [k1, k2, k3, k4] = self.distortion;
+        let theta_d = point.coords.norm();
+        if theta_d < 1e-12 {
+            return point;
+        }
+
+        // newton's method on theta_d(theta) - theta_d = 0
+        let mut theta = theta_d;
+        for _ in 0..10 {
+            let t2 = theta * theta;
+            let derivative = 1.0 + t2 * (3.0 * k1 + t2 * (5.0 * k2 + t2 * (7.0 * k3 + t2 * 9.0 * k4)));
+            let step = (self.theta_d(theta) - theta_d) / derivative;
+            theta -= step;
+            if step.abs() < 1e-12 {
+                break;
+            }
+        }
+
+        Point2::from(point.coords * (theta.tan() / theta_d))
+    }
+}
+
+impl CameraModel for KannalaBrandt {
+    type Projection = NormalizedKeyPoint;
+
+    fn calibrate<P>(&self, point: P) -> NormalizedKeyPoint
+    where
+        P: ImagePoint,
+    {
+        let distorted = self.intrinsics.calibrate(point);
+        NormalizedKeyPoint(self.undistort(distorted.0))
+    }
+
+    fn uncalibrate(&self, projection: NormalizedKeyPoint) -> KeyPoint {
+        self.intrinsics.uncalibrate(NormalizedKeyPoint(self.distort(projection.0)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nalgebra::Vector2;
+
+    fn intrinsics() -> CameraIntrinsics {
+        CameraIntrinsics {
+            focals: Vector2::new(600.0, 610.0),
+            principal_point: Point2::new(320.0, 240.0),
+            skew: 0.0,
+        }
+    }
+
+    // pixels spread over a 640x480 image, corners included
+    fn pixels() -> Vec<KeyPoint> {
+        let mut out = Vec::new();
+        for y in 0..=6 {
+            for x in 0..=8 {
+                out.push(KeyPoint(Point2::new(x as f64 * 80.0, y as f64 * 80.0)));
+            }
+        }
+        out
+    }
+
+    fn assert_roundtrip<C: Camera>(camera: &C) {
+        for pixel in pixels() {
+            let back = camera.uncalibrate(camera.calibrate(pixel));
+            assert!((back.0 - pixel.0).norm() < 1e-6, "{:?} came back as {:?}", pixel, back);
+        }
+    }
+
+    #[test]
+    fn brown_conrady_roundtrip() {
+        assert_roundtrip(&BrownConrady::new(intrinsics(), [-0.25, 0.08, 1e-3, -5e-4, -0.01]));
+    }
+
+    #[test]
+    fn kannala_brandt_roundtrip() {
+        assert_roundtrip(&KannalaBrandt::new(intrinsics(), [0.05, -0.01, 2e-3, -1e-4]));
+    }
+
+    #[test]
+    fn zero_distortion_is_pinhole() {
+        let camera = BrownConrady::new(intrinsics(), [0.0; 5]);
+        for pixel in pixels() {
+            let expected = intrinsics().calibrate(pixel);
+            assert!((camera.calibrate(pixel).0 - expected.0).norm() < 1e-12);
+            assert!((camera.uncalibrate(expected).0 - pixel.0).norm() < 1e-9);
+        }
+
+        // without coefficients the fisheye model is purely equidistant
+        let fisheye = KannalaBrandt::new(intrinsics(), [0.0; 4]);
+        let point = Point2::new(0.6, -0.8);
+        assert!((fisheye.distort(point).coords.norm() - 1.0f64.atan()).abs() < 1e-12);
+        assert!((fisheye.undistort(fisheye.distort(point)) - point).norm() < 1e-12);
+    }
+}
@@ -1,15 +1,18 @@
 use image::{ImageBuffer, ConvertBuffer, Rgb, Rgba, Luma, GenericImageView, GrayImage};
-use crate::{create_threshold, add_border, segmentation, decode};
+use crate::{create_threshold, add_border, segmentation, decode, Intensity};
 use std::time::Instant;
 use std::collections::HashMap;
 use imageproc::drawing::{draw_hollow_circle_mut, draw_text_mut, draw_antialiased_line_segment, draw_line_segment_mut, draw_antialiased_line_segment_mut, draw_hollow_rect, draw_hollow_rect_mut};
-use imageproc::filter::{filter3x3, gaussian_blur_f32};
+use imageproc::definitions::Image;
+use imageproc::filter::gaussian_blur_f32;
 use rusttype::{FontCollection, Scale};
-use crate::localize::{localize, draw_coords};
-use cv_pinhole::CameraIntrinsics;
+use crate::localize::{localize, draw_coords, scale_pose};
+use crate::camera::Camera;
 use crate::segmentation::FeatureVector;
 use imageproc::region_labelling::{connected_components, Connectivity};
-use crate::decode::LFTagComponents;
+use crate::decode::{DecodedLFTag, LFTagComponents};
+use crate::detector::LFTagDetector;
+use crate::pose::{euler_angles, EulerOrder};
 use opencv::core::dump_bool;
 use imageproc::morphology::dilate;
 use imageproc::distance_transform::Norm;
@@ -19,10 +22,22 @@ use imageproc::pixelops::interpolate;
 use imageproc::gradients::{prewitt_gradients, sobel_gradients};
 use imageproc::rect::Rect;
 
-pub fn sharpen3x3(image: &GrayImage) -> GrayImage {
+/// Identity minus a tenth of the laplacian with clamped edges, the same filter for any sample
+/// depth.
+pub fn sharpen3x3<T: Intensity>(image: &Image<Luma<T>>) -> Image<Luma<T>> {
     let s = 0.1;
-    let identity_minus_laplacian = [0.0, -s, 0.0, -s, 1.0 + 4.0 * s, -s, 0.0, -s, 0.0];
-    filter3x3(image, &identity_minus_laplacian)
+    let (width, height) = image.dimensions();
+    let at = |x: i64, y: i64| {
+        let x = x.max(0).min(width as i64 - 1) as u32;
+        let y = y.max(0).min(height as i64 - 1) as u32;
+        image.get_pixel(x, y).data[0].to_u32() as f32
+    };
+    ImageBuffer::from_fn(width, height, |x, y| {
+        let (x, y) = (x as i64, y as i64);
+        let laplacian = at(x - 1, y) + at(x + 1, y) + at(x, y - 1) + at(x, y + 1) - 4.0 * at(x, y);
+        let value = at(x, y) - s * laplacian;
+        Luma { data: [T::from_u32(value.max(0.0).min(T::MAX as f32) as u32)] }
+    })
 }
 
 lazy_static! {
@@ -36,7 +51,7 @@ lazy_static! {
     };
 }
 
-pub fn find_topotags_debug(input: ImageBuffer<Rgb<u8>, Vec<u8>>, camera: CameraIntrinsics) -> Option<ImageBuffer<Rgba<u8>, Vec<u8>>> {
+pub fn find_topotags_debug<C: Camera>(input: ImageBuffer<Rgb<u8>, Vec<u8>>, camera: C) -> Option<ImageBuffer<Rgba<u8>, Vec<u8>>> {
     let input: ImageBuffer<Luma<u8>, Vec<u8>> = input.convert();
 
     let dim = input.dimensions();
@@ -208,7 +223,7 @@ pub fn find_topotags_debug(input: ImageBuffer<Rgb<u8>, Vec<u8>>, camera: CameraI
     Some(output)
 }
 
-pub fn dilate_fv(bin_input: &ImageBuffer<Luma<u8>, Vec<u8>>, input: &ImageBuffer<Luma<u8>, Vec<u8>>, fv: &mut FeatureVector) {
+pub fn dilate_fv<T: Intensity>(bin_input: &ImageBuffer<Luma<u8>, Vec<u8>>, input: &ImageBuffer<Luma<T>, Vec<T>>, fv: &mut FeatureVector) {
     let buffer = 3;
     let x = fv.bounding_box[0].0 - buffer;
     let y = fv.bounding_box[0].1 - buffer;
@@ -221,7 +236,9 @@ pub fn dilate_fv(bin_input: &ImageBuffer<Luma<u8>, Vec<u8>>, input: &ImageBuffer
 
     let roi = bin_input.view(x, y, width, height).to_image();
     let roi_grey = input.view(x, y, width, height);
-    let mut connected = connected_components(&roi, Connectivity::Four, Luma{data: [255]});
+    // pixels of the other color are background
+    let background = if fv.color { 0 } else { 255 };
+    let mut connected = connected_components(&roi, Connectivity::Four, Luma{data: [background]});
     let connected_id = connected.get_pixel(fv.max_x - x, fv.bounding_box[1].1 - y).data[0];
 
     let mut v = Vec::new();
@@ -240,79 +257,98 @@ pub fn dilate_fv(bin_input: &ImageBuffer<Luma<u8>, Vec<u8>>, input: &ImageBuffer
     dialated.enumerate_pixels().map(|(x_r, y_r, p)| {
         if p.data[0] != 0 {
             if connected.get_pixel(x_r, y_r).data[0] == 0 {
-                fv.add_pixel_no_area(x + x_r, y + y_r, roi_grey.get_pixel(x_r, y_r).data[0] as u32, fv.color)
+                fv.add_pixel_no_area(x + x_r, y + y_r, roi_grey.get_pixel(x_r, y_r).data[0].to_u32(), T::MAX, fv.color)
             }
         }
     }).last();
 }
 
-pub fn find_lftags_debug(input_color: &ImageBuffer<Rgb<u8>, Vec<u8>>, camera: CameraIntrinsics) -> Option<ImageBuffer<Rgba<u8>, Vec<u8>>> {
-    let now = Instant::now();
-
-    let input: ImageBuffer<Luma<u8>, Vec<u8>> = input_color.convert();
-    let mut gradient = &mut sobel_gradients(&input);
-    let input = sharpen3x3(&input);
+/// Draws the nodes, id, orientation and axes of each detection onto an image.
+pub fn draw_lftags<C: Camera>(output: &mut ImageBuffer<Rgba<u8>, Vec<u8>>, tags: &[DecodedLFTag], camera: &C) {
+    let height = 20.0;
+    let scale = Scale {
+        x: height,
+        y: height,
+    };
 
-    let dim = input.dimensions();
+    for decoded in tags {
+        let k = decoded.bounding_box;
+        for i in &decoded.node_pos {
+            let vert_start = (i.0, i.1 - 5.0);
+            let vert_end = (i.0, i.1 + 5.0);
+
+            let horiz_start = (i.0 - 5.0, i.1);
+            let horiz_end = (i.0 + 5.0, i.1);
+
+            draw_line_segment_mut(output,
+                                  vert_start,
+                                  vert_end,
+                                  Rgba {
+                                      data: [255, 0, 0, 255],
+                                  });
+            draw_line_segment_mut(output,
+                                  horiz_start,
+                                  horiz_end,
+                                  Rgba {
+                                      data: [255, 0, 0, 255],
+                                  });
+        }
 
-    let threshold_map = create_threshold(&input)?;
-    let mut output = ImageBuffer::new(dim.0, dim.1);
-    let hard_thresh = 10; // handles saturation better
-    for i in input.enumerate_pixels() {
-        let thresh = threshold_map.get_pixel(i.0, i.1).data[0];
-        output.put_pixel(
-            i.0,
-            i.1,
-            Luma {
-                data: [{
-                    if (i.2.data[0] as u32 > thresh as u32 || i.2.data[0] > (255 - hard_thresh))
-                        && (i.2.data[0] > hard_thresh)                    {
-                        255
-                    } else {
-                        0
-                    }
-                }],
+        for i in &decoded.expected_node_pos {
+            let vert_start = ((i.0 - 5.0) as i32, (i.1 - 5.0) as i32);
+            let vert_end = ((i.0 + 5.0) as i32, (i.1 + 5.0) as i32);
+
+            let horiz_start = ((i.0 - 5.0) as i32, (i.1 + 5.0) as i32);
+            let horiz_end = ((i.0 + 5.0) as i32, (i.1 - 5.0) as i32);
+
+            draw_antialiased_line_segment_mut(output,
+                                  vert_start,
+                                  vert_end,
+                                  Rgba {
+                                      data: [0, 255, 0, 255],
+                                  }, interpolate);
+            draw_antialiased_line_segment_mut(output,
+                                  horiz_start,
+                                  horiz_end,
+                                  Rgba {
+                                      data: [0, 255, 0, 255],
+                                  }, interpolate);
+        }
+        draw_text_mut(
+            output,
+            Rgba {
+                data: [255, 0, 0, 255],
             },
-        )
+            k[1].0 as u32,
+            k[1].1 as u32,
+            scale,
+            &FONT,
+            &format!("id = {}", decoded.data),
+        );
+        let ang = euler_angles(&decoded.final_pose.0.rotation, EulerOrder::Zyx);
+        draw_text_mut(
+            output,
+            Rgba {
+                data: [255, 0, 0, 255],
+            },
+            k[1].0 as u32,
+            k[1].1 as u32 + scale.y as u32,
+            scale,
+            &FONT,
+            &format!("ypr = {:.0} {:.0} {:.0}", ang[0].to_degrees(), ang[1].to_degrees(), ang[2].to_degrees()),
+        );
+        // axes are drawn in tag grid units
+        let grid = decoded.class.get_pix_size() as f64;
+        draw_coords(output, camera, &scale_pose(&decoded.final_pose, grid / decoded.tag_size), grid);
     }
+}
 
-    add_border(&mut output);
-
-    let mut topo = segmentation::to_topo(&output, &input);
-    let len = (topo.len() - 1) as u32;
-
-    //assert_eq!(area(&mut topo, len), 480 * 640);
-
-    let mut tags = HashMap::new();
-    decode::detect_lftag(
-        &mut topo,
-        len,
-        &mut tags,
-        &vec![decode::LFTagClass::LFTag3x3, decode::LFTagClass::LFTag4x4],
-        // &vec![decode::LFTagClass::LFTag3x3],
-    );
-
-    // if tags.len() > 10 {
-    //     return None
-    // }
-
-    for (_, mut i) in &mut tags {
-        match &mut i {
-            LFTagComponents::Root { ref mut nodes , ..} => {
-                for mut i in nodes {
-                    match &mut i {
-                        LFTagComponents::Normal { ref mut node } => {
-                            dilate_fv(&output, &input, node);
-                        },
-                        _ => {}
-                    }
-                }
-            },
-            _ => {
+pub fn find_lftags_debug<C: Camera>(input_color: &ImageBuffer<Rgb<u8>, Vec<u8>>, camera: C) -> Option<ImageBuffer<Rgba<u8>, Vec<u8>>> {
+    let now = Instant::now();
 
-            }
-        }
-    }
+    let input: ImageBuffer<Luma<u8>, Vec<u8>> = input_color.convert();
+    let detector = LFTagDetector::new(camera);
+    let tags = detector.detect(&input)?;
 
     let mut output: ImageBuffer<Rgba<u8>, Vec<u8>> = input_color.convert();
     //let mut output: ImageBuffer<Rgba<u8>, Vec<u8>> = output.convert();
@@ -331,80 +367,8 @@ pub fn find_lftags_debug(input_color: &ImageBuffer<Rgb<u8>, Vec<u8>>, camera: Ca
     //     }
     // }
 
-    for (k, v) in tags {
-        // println!("potential tag");
-        // draw_hollow_rect_mut(&mut output, Rect::at(k[0].0 as i32, k[0].1 as i32).of_size(k[1].0 - k[0].0, k[1].1 - k[0].1), Rgba { data: [255, 0, 0, 255], });
-
-        if let Some(decoded) = decode::DecodedLFTag::decode_lftag(&v, camera, &mut gradient) {
-            for (idx, i) in decoded.node_pos.iter().enumerate() {
-                let vert_start = (i.0, i.1 - 5.0);
-                let vert_end = (i.0, i.1 + 5.0);
-
-                let horiz_start = (i.0 - 5.0, i.1);
-                let horiz_end = (i.0 + 5.0, i.1);
-
-                draw_line_segment_mut(&mut output,
-                                      vert_start,
-                                      vert_end,
-                                      Rgba {
-                                          data: [255, 0, 0, 255],
-                                      });
-                draw_line_segment_mut(&mut output,
-                                      horiz_start,
-                                      horiz_end,
-                                      Rgba {
-                                          data: [255, 0, 0, 255],
-                                      });
-            }
+    draw_lftags(&mut output, &tags, &detector.camera);
 
-            for (idx, i) in decoded.expected_node_pos.iter().enumerate() {
-                //draw_text_mut(
-                //    &mut output,
-                //    Rgba {
-                //        data: [255, 0, 0, 255],
-                //    },
-                //    i.0 as u32,
-                //    i.1 as u32,
-                //    scale,
-                //    &FONT,
-                //    &format!("{}", idx),
-                //);
-                let vert_start = ((i.0 - 5.0) as i32, (i.1 - 5.0) as i32);
-                let vert_end = ((i.0 + 5.0) as i32, (i.1 + 5.0) as i32);
-
-                let horiz_start = ((i.0 - 5.0) as i32, (i.1 + 5.0) as i32);
-                let horiz_end = ((i.0 + 5.0) as i32, (i.1 - 5.0) as i32);
-
-                draw_antialiased_line_segment_mut(&mut output,
-                                      vert_start,
-                                      vert_end,
-                                      Rgba {
-                                          data: [0, 255, 0, 255],
-                                      }, interpolate);
-                draw_antialiased_line_segment_mut(&mut output,
-                                      horiz_start,
-                                      horiz_end,
-                                      Rgba {
-                                          data: [0, 255, 0, 255],
-                                      }, interpolate);
-            }
-            let ang = decoded.final_pose.0.rotation.euler_angles();
-            draw_text_mut(
-                &mut output,
-                Rgba {
-                    data: [255, 0, 0, 255],
-                },
-                k[1].0 as u32,
-                k[1].1 as u32,
-                scale,
-                &FONT,
-                &format!("id = {}", decoded.data),
-            );
-            let scale = (decoded.class.get_width() as f64 + 1.0) * 6.0;
-            draw_coords(&mut output, &camera, &decoded.final_pose, scale);
-        }
-
-    }
     draw_text_mut(
         &mut output,
         Rgba {
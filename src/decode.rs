@@ -1,24 +1,31 @@
 use crate::segmentation::{FeatureVector, area};
 use euclid::default::{Vector2D};
+use std::cmp::Ordering;
 use std::collections::HashMap;
-use cv_pinhole::{CameraIntrinsics, NormalizedKeyPoint};
+use cv_pinhole::NormalizedKeyPoint;
+use crate::camera::Camera;
 use arrsac::{Arrsac, Config};
 use rand::prelude::SmallRng;
 use rand::SeedableRng;
 use lambda_twist::LambdaTwist;
 use cv_core::{CameraModel, FeatureWorldMatch, KeyPoint, WorldPoint, sample_consensus::Consensus, WorldPose};
 use nalgebra::{Point2, Point3, Isometry3, IsometryMatrix3, Translation, Vector3, Matrix3, Rotation3, distance};
-use image::{ImageBuffer, Rgba, Luma};
+use image::{GrayImage, ImageBuffer, Rgba, Luma};
 use opencv::core::{Vector, CV_64F};
 use opencv::prelude::*;
 use itertools::iproduct;
 
-use crate::localize::{opencv_localize, world_to_camera, world_to_camera_i32};
+use crate::dictionary::{erasure_mask, Dictionary};
+use crate::localize::{opencv_localize, scale_pose, world_to_camera, world_to_camera_i32};
+use crate::pose::{PoseConfig, PoseValue};
 use imageproc::definitions::Image;
 use imageproc::drawing::draw_antialiased_line_segment_mut;
 use std::cell::RefCell;
+#[cfg(feature = "serde-serialize")]
+use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde-serialize", derive(Serialize, Deserialize))]
 pub enum TopotagComponents {
     Root {
         nodes: Vec<TopotagComponents>,
@@ -35,11 +42,14 @@ pub enum TopotagComponents {
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde-serialize", derive(Serialize, Deserialize))]
 pub enum LFTagComponents {
     Root {
         nodes: Vec<LFTagComponents>,
         bg: FeatureVector,
         class: LFTagClass,
+        // light nodes on a dark background
+        inverted: bool,
     },
     None,
     Normal {
@@ -51,6 +61,7 @@ pub enum LFTagComponents {
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde-serialize", derive(Serialize, Deserialize))]
 pub struct DecodedTopotag {
     pub data: usize,
     pub nodes: Vec<TopotagComponents>,
@@ -229,15 +240,137 @@ impl DecodedTopotag {
     }
 }
 
+/// How clearly a data node was read. Distances run from the node centroid to the nearest and
+/// second nearest symbol sub-position, in units of the sub-position spacing (one grid unit).
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde-serialize", derive(Serialize, Deserialize))]
+pub struct NodeConfidence {
+    pub symbol: usize,
+    pub best_distance: f32,
+    pub second_distance: f32,
+    // no blob near the node, the symbol comes from the dictionary
+    pub erased: bool,
+}
+
+impl NodeConfidence {
+    /// 1 when the node sits exactly on its symbol, 0 when it is equally close to another one.
+    pub fn confidence(&self) -> f32 {
+        if self.erased {
+            return 0.0
+        }
+        let total = self.best_distance + self.second_distance;
+        if total > 0.0 {
+            (self.second_distance - self.best_distance) / total
+        } else {
+            0.0
+        }
+    }
+}
+
+/// One way of reading a tag: a choice of calibration dot sub-positions (and mirroring), the initial
+/// pose it implies and the data read under that pose.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde-serialize", derive(Serialize, Deserialize))]
+pub struct DecodeHypothesis {
+    pub data: usize,
+    #[cfg_attr(feature = "serde-serialize", serde(with = "crate::serialize::world_pose"))]
+    pub pose: WorldPose,
+    // residual normalized like `DecodedLFTag::quality`, lower is better
+    pub quality: f32,
+    // lowest node confidence under this hypothesis
+    pub confidence: f32,
+    // data nodes without a blob, their symbols in `data` are 0
+    pub erasures: Vec<usize>,
+    // indices of the bottom left and bottom right calibration dot sub-positions
+    pub calibration: (usize, usize),
+    pub mirrored: bool,
+}
+
+/// Which tag printings to look for: the usual dark nodes on a light background, the inverse (e.g.
+/// film seen against a dark backdrop), or both at the cost of a second pass over the regions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde-serialize", derive(Serialize, Deserialize))]
+pub enum Polarity {
+    DarkOnLight,
+    LightOnDark,
+    Both,
+}
+
+impl Default for Polarity {
+    fn default() -> Polarity {
+        Polarity::DarkOnLight
+    }
+}
+
+impl Polarity {
+    /// Values of the `inverted` flag of `detect_lftag_with` to search.
+    pub fn variants(&self) -> &'static [bool] {
+        match self {
+            Polarity::DarkOnLight => &[false],
+            Polarity::LightOnDark => &[true],
+            Polarity::Both => &[false, true],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DecodeOptions {
+    // only accept this id, which also fixes the calibration dot hypothesis
+    pub known_id: Option<usize>,
+    // expected pose in tag grid units, replaces the geometric corner search
+    pub prior_pose: Option<WorldPose>,
+    // also try decoding the mirror image of the tag, e.g. seen through a mirror or from behind film
+    pub mirrored: bool,
+    // data nodes that may be missing, e.g. occluded, their symbols are treated as erasures
+    pub max_missing: usize,
+    // valid ids, reads outside it are rejected and erased symbols are recovered from it
+    pub dictionary: Option<Dictionary>,
+    // image position of the top left gradient pixel, when decoding against the gradient of a crop
+    pub gradient_origin: (u32, u32),
+}
+
+/// A decoded LFTag and its pose in the camera frame (OpenCV convention, x right, y down, z forward).
+///
+/// The tag frame has its origin at the top left outer corner of the printed border, with x
+/// pointing along the baseline towards the second baseline node, y pointing down towards the
+/// calibration dots and z pointing into the tag surface, away from the viewer. Lengths in the tag
+/// frame are in units of `tag_size`, the physical edge length of the printed border: a tag decoded
+/// without a known size uses `class.get_pix_size()` grid units, see `set_tag_size`.
+///
+/// Mirrored tags are reported in the frame of their mirror image, whose x axis runs from the top
+/// right corner of the printed tag towards the top left: a point at (x, y) on the printed tag is
+/// at (edge - x, y) in the frame of `final_pose` and `node_world_pos`.
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde-serialize", derive(Serialize, Deserialize))]
 pub struct DecodedLFTag {
     pub data: usize,
     // pub nodes: Vec<LFTagComponents>,
     pub node_pos: Vec<(f32, f32)>,
+    // position of each entry of node_pos in the tag frame
+    pub node_world_pos: Vec<(f32, f32)>,
     pub expected_node_pos: Vec<(f32, f32)>,
+    pub bounding_box: [(u32, u32); 2],
     pub class: LFTagClass,
+    pub tag_size: f64,
+    // decoding residual normalized by the tag area, lower is better
+    pub quality: f32,
+    // light nodes on a dark background
+    pub inverted: bool,
+    // decoded from the mirror image of the tag
+    pub mirrored: bool,
+    // soft decision of every data node, in the order of their bits in `data`
+    pub node_confidence: Vec<NodeConfidence>,
+    // lowest node confidence, 0 when some node sits halfway between two symbols or is erased
+    pub confidence: f32,
+    // data nodes without a blob, their symbols in `data` were recovered from the dictionary
+    pub erasures: Vec<usize>,
+    #[cfg_attr(feature = "serde-serialize", serde(with = "crate::serialize::world_pose"))]
     pub initial_pose: WorldPose,
+    #[cfg_attr(feature = "serde-serialize", serde(with = "crate::serialize::world_poses"))]
     pub initial_poses: Vec<WorldPose>,
+    // every calibration dot (and mirror) hypothesis, best first
+    pub hypotheses: Vec<DecodeHypothesis>,
+    #[cfg_attr(feature = "serde-serialize", serde(with = "crate::serialize::world_pose"))]
     pub final_pose: WorldPose
 }
 
@@ -256,7 +389,7 @@ fn get_corner_point(base0_fv: &FeatureVector, base1_fv: &FeatureVector, fvs: &[F
         let distance = baseline_to_node.length();
 
         (angle, distance, idx)
-    }).collect();
+    }).filter(|(angle, distance, _)| angle.is_finite() && distance.is_finite()).collect();
 
     let mut angles: Vec<_> = possible_nodes.iter().map(|(a, _, _)| *a).collect();
 
@@ -264,18 +397,19 @@ fn get_corner_point(base0_fv: &FeatureVector, base1_fv: &FeatureVector, fvs: &[F
 
     angles.sort_by(|a, b| {
         if angle_sum > 0.0 {
-            a.partial_cmp(&b).unwrap()
+            a.partial_cmp(&b).unwrap_or(Ordering::Equal)
         } else {
-            b.partial_cmp(&a).unwrap()
+            b.partial_cmp(&a).unwrap_or(Ordering::Equal)
         }
     });
 
+    let last_positive = angles.last()?.is_sign_positive();
     let valid = angles.iter().skip(class.get_width() - 2).all(|a| {
-        a.is_sign_positive() == angles.last().unwrap().is_sign_positive()
+        a.is_sign_positive() == last_positive
     });
 
     possible_nodes.sort_by(|a, b| {
-        b.0.abs().partial_cmp(&a.0.abs()).unwrap()
+        b.0.abs().partial_cmp(&a.0.abs()).unwrap_or(Ordering::Equal)
     });
 
     // println!("angle sum {}", angle_sum);
@@ -286,10 +420,202 @@ fn get_corner_point(base0_fv: &FeatureVector, base1_fv: &FeatureVector, fvs: &[F
     // dbg!(&possible_nodes);
 
     possible_nodes.sort_by(|a, b| {
-        b.1.partial_cmp(&a.1).unwrap()
+        b.1.partial_cmp(&a.1).unwrap_or(Ordering::Equal)
     });
 
-    Some((possible_nodes[0].2, angle_sum > 0.0, valid))
+    Some((possible_nodes.first()?.2, angle_sum > 0.0, valid))
+}
+
+/// Finds the baseline and calibration dot nodes from the geometry of the blobs alone, assuming the
+/// two largest blobs are the baseline. Returns their indices as [top left, top right, bottom left,
+/// bottom right].
+fn find_corners(fvs: &[FeatureVector], class: &LFTagClass) -> Option<[usize; 4]> {
+    let (a, zero_first, valid_a) = get_corner_point(&fvs[0], &fvs[1], &fvs, class)?;
+    let (b, one_first, valid_b) = get_corner_point(&fvs[1], &fvs[0], &fvs, class)?;
+
+    if !valid_a || !valid_b {
+        // println!("rejected due to geometry constraint");
+        return None
+    }
+
+    if zero_first == one_first {
+        // println!("rejected due to angle constraint");
+        return None
+    }
+
+    let centroids: Vec<_> = fvs.iter().map(|fv| {
+        fv.get_com_f32()
+    }).collect();
+
+    if find_lin_fit_error(&centroids) < 20.0 {
+        // println!("rejected due to collinearity constraint");
+        return None
+    }
+
+    // dbg!(find_lin_fit_error(&centroids));
+
+    // assert_ne!(zero_first, one_first);
+
+    if zero_first {
+        Some([0, 1, a, b])
+    } else {
+        Some([1, 0, b, a])
+    }
+}
+
+/// Projected distance between neighbouring nodes (six grid units) near the top left of the tag.
+fn node_spacing<C: Camera>(camera: &C, pose: &WorldPose) -> f32 {
+    let a = world_to_camera(camera, pose, &WorldPoint(Point3::new(6.0, 6.0, 0.0)));
+    let b = world_to_camera(camera, pose, &WorldPoint(Point3::new(12.0, 6.0, 0.0)));
+    ((a.0 - b.0).powi(2) + (a.1 - b.1).powi(2)).sqrt()
+}
+
+/// Picks the blobs nearest to where the baseline and calibration dots project under a prior pose,
+/// returns their indices like `find_corners`.
+fn seeded_corners<C: Camera>(camera: &C, prior: &WorldPose, class: &LFTagClass, fvs: &[FeatureVector], hypothesis: Option<(usize, usize)>) -> Option<[usize; 4]> {
+    let keypoints = class.get_keypoint_pos();
+    let (bl_idx, br_idx) = hypothesis.unwrap_or((0, 0));
+    let expected = [keypoints[0][0], keypoints[1][0], keypoints[2][bl_idx], keypoints[3][br_idx]];
+
+    // blobs further than a third of the node spacing away are not accepted
+    let spacing = node_spacing(camera, prior);
+
+    let mut used = Vec::new();
+    for point in &expected {
+        let projected = world_to_camera(camera, prior, &WorldPoint(Point3::new(point.0 as f64, point.1 as f64, 0.0)));
+        let (idx, dist) = fvs.iter().enumerate()
+            .filter(|(idx, _)| !used.contains(idx))
+            .map(|(idx, fv)| {
+                let com = fv.get_com_f32();
+                (idx, ((com.0 - projected.0).powi(2) + (com.1 - projected.1).powi(2)).sqrt())
+            })
+            .filter(|(_, dist)| dist.is_finite())
+            .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(Ordering::Equal))?;
+
+        if dist > spacing / 3.0 {
+            return None
+        }
+        used.push(idx);
+    }
+
+    Some([used[0], used[1], used[2], used[3]])
+}
+
+/// Blobs consistent with the tag layout under `pose`: the given corners plus, for every other node,
+/// the nearest unused blob within a third of the node spacing. Returns the blob indices and the
+/// sum of squared distances to the node centers.
+fn associate<C: Camera>(camera: &C, pose: &WorldPose, class: &LFTagClass, fvs: &[FeatureVector], corners: [usize; 4]) -> (Vec<usize>, f32) {
+    let pix = class.get_pix_size();
+    let corner_centers = [(6.0, 6.0), (pix - 6.0, 6.0), (6.0, pix - 6.0), (pix - 6.0, pix - 6.0)];
+    let tolerance = node_spacing(camera, pose) / 3.0;
+
+    let mut inliers = corners.to_vec();
+    let mut residual = 0.0;
+    for center in class.node_centers() {
+        if corner_centers.contains(&center) {
+            continue
+        }
+        let projected = world_to_camera(camera, pose, &WorldPoint(Point3::new(center.0 as f64, center.1 as f64, 0.0)));
+        let nearest = fvs.iter().enumerate()
+            .filter(|(idx, _)| !inliers.contains(idx))
+            .map(|(idx, fv)| {
+                let com = fv.get_com_f32();
+                (idx, (com.0 - projected.0).powi(2) + (com.1 - projected.1).powi(2))
+            })
+            .filter(|(_, dist2)| dist2.is_finite())
+            .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(Ordering::Equal));
+
+        if let Some((idx, dist2)) = nearest {
+            if dist2 < tolerance.powi(2) {
+                inliers.push(idx);
+                residual += dist2;
+            }
+        }
+    }
+    (inliers, residual)
+}
+
+/// Hypothesize-and-verify search for the tag corners among blobs that may include distractors
+/// such as dirt, glare or printed text. Every ordered pair of the blobs closest to the expected
+/// baseline node area is tried as the baseline, with the calibration dots taken as the blobs
+/// nearest to where a square tag would put them, and each hypothesis is scored by how many blobs
+/// fit the full node layout under the pose its corners imply. Returns the corners and the inlier
+/// blobs of the best hypothesis with at least `min_inliers` blobs.
+fn ransac_corners<C: Camera>(camera: &C, class: &LFTagClass, fvs: &[FeatureVector], min_inliers: usize) -> Option<([usize; 4], Vec<usize>)> {
+    let pix = class.get_pix_size();
+    let corner_centers = vec![(6.0, 6.0), (pix - 6.0, 6.0), (6.0, pix - 6.0), (pix - 6.0, pix - 6.0)];
+    let com = |idx: usize| Vector2D::<f32>::from(fvs[idx].get_com_f32());
+
+    let mut hypotheses = Vec::new();
+    // the blob geometry heuristic on the largest blobs, right whenever there are no distractors
+    if let Some(corners) = find_corners(&fvs[..fvs.len().min(class.total_node_count())], class) {
+        hypotheses.push(corners);
+    }
+
+    // baseline nodes are 4x4 grid units against 3x3 for the data nodes, which make up most blobs, so
+    // the baseline is looked for among the blobs closest to 16/9 of the median blob area. Distractors
+    // far from node size rank last however large they are.
+    let mut areas: Vec<u32> = fvs.iter().map(|fv| fv.area).collect();
+    areas.sort();
+    let expected = areas[areas.len() / 2] as f32 * 16.0 / 9.0;
+    let misfit = |idx: usize| (fvs[idx].area as f32 / expected).ln().abs();
+    let mut candidates: Vec<usize> = (0..fvs.len()).collect();
+    candidates.sort_by(|&a, &b| misfit(a).partial_cmp(&misfit(b)).unwrap_or(Ordering::Equal));
+    candidates.truncate(8);
+
+    for (&a, &b) in iproduct!(candidates.iter(), candidates.iter()) {
+        if a == b {
+            continue
+        }
+        let baseline = com(b) - com(a);
+        // tag y points along the baseline turned clockwise in the image
+        let down = Vector2D::new(-baseline.y, baseline.x);
+        let nearest = |target: Vector2D<f32>, skip: &[usize]| {
+            fvs.iter().enumerate()
+                .filter(|(idx, _)| !skip.contains(idx))
+                .map(|(idx, _)| (idx, (com(idx) - target).length()))
+                .filter(|(_, dist)| dist.is_finite())
+                .min_by(|x, y| x.1.partial_cmp(&y.1).unwrap_or(Ordering::Equal))
+                .filter(|(_, dist)| *dist < baseline.length() / 2.0)
+                .map(|(idx, _)| idx)
+        };
+        let bl = match nearest(com(a) + down, &[a, b]) {
+            Some(idx) => idx,
+            None => continue,
+        };
+        if let Some(br) = nearest(com(b) + down, &[a, b, bl]) {
+            hypotheses.push([a, b, bl, br]);
+        }
+    }
+
+    let mut best: Option<([usize; 4], Vec<usize>, f32)> = None;
+    for corners in hypotheses {
+        let [tl, tr, bl, br] = corners;
+
+        // the corners have to form a convex quadrilateral in reading order, which also keeps the
+        // pose estimate away from degenerate input
+        let edge = (com(tr) - com(tl)).length().powi(2);
+        if (com(tr) - com(tl)).cross(com(br) - com(tl)) < 0.1 * edge || (com(br) - com(tl)).cross(com(bl) - com(tl)) < 0.1 * edge {
+            continue
+        }
+
+        let cam_points = corners.iter().map(|&idx| fvs[idx].get_com_f32()).collect();
+        let pose = opencv_localize(camera, &corner_centers, &cam_points);
+        let (inliers, residual) = associate(camera, &pose, class, fvs, corners);
+
+        let better = best.as_ref().map_or(true, |(_, best_inliers, best_residual)| {
+            inliers.len() > best_inliers.len() || (inliers.len() == best_inliers.len() && residual < *best_residual)
+        });
+        if better {
+            best = Some((corners, inliers, residual));
+        }
+    }
+
+    let (corners, inliers, _) = best?;
+    if inliers.len() < min_inliers {
+        return None
+    }
+    Some((corners, inliers))
 }
 
 fn find_lin_fit_error(input: &Vec<(f32, f32)>) -> f32 {
@@ -313,8 +639,41 @@ fn find_lin_fit_error(input: &Vec<(f32, f32)>) -> f32 {
 }
 
 impl DecodedLFTag {
-    pub fn decode_lftag(root: &LFTagComponents, camera: CameraIntrinsics, gradient: &mut Image<Luma<u16>>) -> Option<DecodedLFTag> {
-        if let LFTagComponents::Root { nodes, class, bg } = root {
+    /// Rescales poses and tag frame node positions so that the printed edge of the tag has the
+    /// given length, e.g. in meters.
+    pub fn set_tag_size(&mut self, edge_length: f64) {
+        let factor = edge_length / self.tag_size;
+
+        self.initial_pose = scale_pose(&self.initial_pose, factor);
+        self.final_pose = scale_pose(&self.final_pose, factor);
+        for pose in &mut self.initial_poses {
+            *pose = scale_pose(pose, factor);
+        }
+        for hypothesis in &mut self.hypotheses {
+            hypothesis.pose = scale_pose(&hypothesis.pose, factor);
+        }
+        for pos in &mut self.node_world_pos {
+            *pos = ((pos.0 as f64 * factor) as f32, (pos.1 as f64 * factor) as f32);
+        }
+        self.tag_size = edge_length;
+    }
+
+    /// The `n` best decoding hypotheses in rank order, the first one is the reported decoding.
+    pub fn top_hypotheses(&self, n: usize) -> &[DecodeHypothesis] {
+        &self.hypotheses[..n.min(self.hypotheses.len())]
+    }
+
+    /// `final_pose` in the given convention, form and representation.
+    pub fn pose(&self, config: &PoseConfig) -> PoseValue {
+        config.apply(&self.final_pose.0)
+    }
+
+    pub fn decode_lftag<C: Camera>(root: &LFTagComponents, camera: &C, gradient: &mut Image<Luma<u16>>) -> Option<DecodedLFTag> {
+        DecodedLFTag::decode_lftag_with(root, camera, gradient, &DecodeOptions::default())
+    }
+
+    pub fn decode_lftag_with<C: Camera>(root: &LFTagComponents, camera: &C, gradient: &mut Image<Luma<u16>>, options: &DecodeOptions) -> Option<DecodedLFTag> {
+        if let LFTagComponents::Root { nodes, class, bg, inverted } = root {
             let mut fvs: Vec<_> = nodes.iter().map(|n| {
                 if let LFTagComponents::Normal {node: fv} = n {
                     fv.clone()
@@ -324,63 +683,48 @@ impl DecodedLFTag {
             }).collect();
 
             fvs.sort_by(|a, b| {
-                (b.zom * b.area as f32).partial_cmp(&(a.zom * a.area as f32)).unwrap()
+                (b.zom * b.area as f32).partial_cmp(&(a.zom * a.area as f32)).unwrap_or(Ordering::Equal)
             });
-            assert!(fvs.len() >= class.total_node_count());
-
-            fvs.truncate(class.total_node_count());
-            // println!("fvs: {:#?}", fvs);
-
-            if fvs[0].zom / 5.0 > fvs.last().unwrap().zom {
-                // println!("rejected due to area constraint");
-                return None
-            }
-
-
-            let (a, zero_first, valid_a) = get_corner_point(&fvs[0], &fvs[1], &fvs, class)?;
-            let (b, one_first, valid_b) = get_corner_point(&fvs[1], &fvs[0], &fvs, class)?;
-
-            if !valid_a || !valid_b {
-                // println!("rejected due to geometry constraint");
+            let min_inliers = class.total_node_count().saturating_sub(options.max_missing);
+            if fvs.len() < min_inliers.max(4) {
                 return None
             }
 
-            if zero_first == one_first {
-                // println!("rejected due to angle constraint");
-                return None
-            }
+            let calibration_hypothesis = options.known_id.map(|id| class.calibration_hypothesis(id));
 
-            let centroids: Vec<_> = fvs.iter().map(|fv| {
-                fv.get_com_f32()
-            }).collect();
+            // separate the tag nodes from any other blobs inside the tag
+            let (corners, inliers) = match &options.prior_pose {
+                Some(prior) => {
+                    let corners = seeded_corners(camera, prior, class, &fvs, calibration_hypothesis)?;
+                    let (inliers, _) = associate(camera, prior, class, &fvs, corners);
+                    if inliers.len() < min_inliers {
+                        return None
+                    }
+                    (corners, inliers)
+                }
+                None => ransac_corners(camera, class, &fvs, min_inliers)?,
+            };
 
-            if find_lin_fit_error(&centroids) < 20.0 {
-                // println!("rejected due to collinearity constraint");
+            let largest = inliers.iter().map(|&idx| fvs[idx].zom).fold(0.0, f32::max);
+            let smallest = inliers.iter().map(|&idx| fvs[idx].zom).fold(f32::MAX, f32::min);
+            if largest / 5.0 > smallest {
+                // println!("rejected due to area constraint");
                 return None
             }
 
-            // dbg!(find_lin_fit_error(&centroids));
-
-            // assert_ne!(zero_first, one_first);
+            let (tl, tr, bl, br) = (
+                Vector2D::<f32>::from(fvs[corners[0]].get_com_f32()),
+                Vector2D::<f32>::from(fvs[corners[1]].get_com_f32()),
+                Vector2D::<f32>::from(fvs[corners[2]].get_com_f32()),
+                Vector2D::<f32>::from(fvs[corners[3]].get_com_f32()),
+            );
 
-            let tl: Vector2D<f32>;
-            let tr: Vector2D<f32>;
-            let bl: Vector2D<f32>;
-            let br: Vector2D<f32>;
-
-            if zero_first {
-                tl = fvs[0].get_com_f32().into();
-                tr = fvs[1].get_com_f32().into();
-
-                bl = fvs[a].get_com_f32().into();
-                br = fvs[b].get_com_f32().into();
-            } else {
-                tl = fvs[1].get_com_f32().into();
-                tr = fvs[0].get_com_f32().into();
-
-                bl = fvs[b].get_com_f32().into();
-                br = fvs[a].get_com_f32().into();
-            }
+            // the baseline first, then the other nodes largest first
+            let mut ordered = vec![fvs[corners[0]].clone(), fvs[corners[1]].clone()];
+            let mut rest: Vec<usize> = inliers.into_iter().filter(|idx| *idx != corners[0] && *idx != corners[1]).collect();
+            rest.sort();
+            ordered.extend(rest.into_iter().map(|idx| fvs[idx].clone()));
+            let fvs = ordered;
 
             let cam_points: Vec<(f32, f32)> = vec![tl.into(), tr.into(), bl.into(), br.into()];
             let all_cam_points: Vec<Point2::<f32>> = fvs[2..].iter().map(|fv| {
@@ -390,6 +734,7 @@ impl DecodedLFTag {
 
             let node_pos = class.get_keypoint_pos();
             let potential_node_pos = class.get_data_pos();
+            let pix = class.get_pix_size();
 
             let mut best_data = 0;
             let mut best_res = f32::MAX;
@@ -397,25 +742,41 @@ impl DecodedLFTag {
             let mut best_idx = 0;
             let mut best_data_pts = Vec::new();
             let mut best_data_pts_world = Vec::new();
+            let mut best_mirrored = false;
+            let mut best_node_confidence = Vec::new();
+            let mut ranked = Vec::new();
+            let mut best_erasures = Vec::new();
 
             let mut i = 0;
             let mut poses = Vec::new();
 
-            for (e_tl, e_tr, e_bl, e_br) in iproduct!(&node_pos[0], &node_pos[1], &node_pos[2], &node_pos[3]) {
+            let mirror_variants: &[bool] = if options.mirrored { &[false, true] } else { &[false] };
+            let hypotheses = iproduct!(mirror_variants.iter().cloned(), 0..node_pos[2].len(), 0..node_pos[3].len())
+                .filter(|(_, bl, br)| calibration_hypothesis.map_or(true, |known| known == (*bl, *br)));
+
+            for (mirrored, bl_idx, br_idx) in hypotheses {
+                // the mirror image swaps left and right, found corners keep their image layout
+                let world = |p: (f32, f32)| if mirrored { (pix - p.0, p.1) } else { p };
+                let (e_tl, e_tr, e_bl, e_br) = if mirrored {
+                    (world(node_pos[1][0]), world(node_pos[0][0]), world(node_pos[3][br_idx]), world(node_pos[2][bl_idx]))
+                } else {
+                    (node_pos[0][0], node_pos[1][0], node_pos[2][bl_idx], node_pos[3][br_idx])
+                };
                 let mut data_pts = Vec::new();
                 let mut data_pts_world = Vec::new();
 
-                let expected_points: Vec<(f32, f32)> = vec![e_tl.clone(), e_tr.clone(), e_bl.clone(), e_br.clone()];
+                let expected_points: Vec<(f32, f32)> = vec![e_tl, e_tr, e_bl, e_br];
                 // dbg!(&expected_points);
 
-                let initial_pose = opencv_localize(&camera, &expected_points, &cam_points);
+                let initial_pose = opencv_localize(camera, &expected_points, &cam_points);
                 poses.push(initial_pose.clone());
 
                 let transformed: Vec<[Point2<f32>; 4]> = potential_node_pos.iter().map(|positions| {
                     let mut output = [Point2::<f32>::new(0.0, 0.0); 4];
 
                     for i in 0..4 {
-                        let transformed_point = world_to_camera(&camera, &initial_pose, &WorldPoint(Point3::new(positions[i].0 as f64, positions[i].1 as f64, 0.0)));
+                        let position = world(positions[i]);
+                        let transformed_point = world_to_camera(camera, &initial_pose, &WorldPoint(Point3::new(position.0 as f64, position.1 as f64, 0.0)));
                         output[i] = nalgebra::convert(Point2::new(transformed_point.0, transformed_point.1))
                     }
                     output
@@ -423,8 +784,11 @@ impl DecodedLFTag {
 
                 let mut data = 0;
                 let mut total_bit_dist = 0.0;
+                let mut erasures = Vec::new();
+                let mut node_confidence = Vec::new();
                 for (o_idx, potential_nodes) in transformed.iter().enumerate().rev() {
                     let mut min_bit_dist = f32::MAX;
+                    let mut second_bit_dist = f32::MAX;
                     let mut bit = 0;
                     let mut best_point = None;
 
@@ -436,24 +800,70 @@ impl DecodedLFTag {
 
                             if dist < min_dist {
                                 min_dist = dist;
-                                best_point_inner = Some((data_bit.clone(), potential_node_pos[o_idx][idx].clone()))
+                                best_point_inner = Some((data_bit.clone(), world(potential_node_pos[o_idx][idx])))
                             }
                         }
                         if min_dist < min_bit_dist {
+                            second_bit_dist = min_bit_dist;
                             min_bit_dist = min_dist;
                             bit = idx;
                             best_point = best_point_inner.clone();
+                        } else if min_dist < second_bit_dist {
+                            second_bit_dist = min_dist;
                         }
                     }
 
+                    // sub-positions are one grid unit apart
+                    let spacing = (nalgebra::distance(&potential_nodes[0], &potential_nodes[1])
+                        + nalgebra::distance(&potential_nodes[0], &potential_nodes[2])) / 2.0;
+
+                    // a missing node leaves its nearest blob at the neighbouring nodes, which are
+                    // six sub-position spacings away
+                    let erasure_dist = 2.0 * spacing;
+                    if options.max_missing > 0 && min_bit_dist > erasure_dist {
+                        erasures.push(o_idx);
+                        if erasures.len() > options.max_missing {
+                            break
+                        }
+                        data <<= 2;
+                        node_confidence.push(NodeConfidence {
+                            symbol: 0,
+                            best_distance: min_bit_dist / spacing,
+                            second_distance: second_bit_dist / spacing,
+                            erased: true,
+                        });
+
+                        // predicted from the pose, at the center of the sub-positions
+                        let center = potential_nodes.iter().fold(Point2::new(0.0, 0.0), |sum, p| sum + p.coords / 4.0);
+                        data_pts.push((center.x, center.y));
+                        continue
+                    }
+
                     data <<= 2;
                     data |= bit;
                     total_bit_dist += min_bit_dist.powi(2);
 
+                    node_confidence.push(NodeConfidence {
+                        symbol: bit,
+                        best_distance: min_bit_dist / spacing,
+                        second_distance: second_bit_dist / spacing,
+                        erased: false,
+                    });
+
                     data_pts_world.push(best_point?);
                     data_pts.push((potential_nodes[bit].coords[0], potential_nodes[bit].coords[1]));
                 }
 
+                if erasures.len() > options.max_missing {
+                    i += 1;
+                    continue
+                }
+
+                // erased nodes carry no residual, the found ones stand in for them so that
+                // hypotheses with different erasures compare fairly
+                let found = transformed.len() - erasures.len();
+                total_bit_dist *= transformed.len() as f32 / found.max(1) as f32;
+
                 let grad_sum = RefCell::new(0.0f32);
                 let weight_total = RefCell::new(0.0f32);
                 let sum_grad = |a: Luma<u16>, b: Luma<u16>, w| {
@@ -466,10 +876,14 @@ impl DecodedLFTag {
                 let inner_edge = (class.get_width() as f64 + 1.0) * 6.0 - 2.0;
                 // dbg!(inner_edge);
 
-                let orig = world_to_camera_i32(&camera, &initial_pose, &WorldPoint(Point3::new(2.0, 2.0, 0.0)));
-                let x = world_to_camera_i32(&camera, &initial_pose, &WorldPoint(Point3::new(2.0, inner_edge, 0.0)));
-                let y = world_to_camera_i32(&camera, &initial_pose, &WorldPoint(Point3::new(inner_edge, 2.0, 0.0)));
-                let xy = world_to_camera_i32(&camera, &initial_pose, &WorldPoint(Point3::new(inner_edge, inner_edge, 0.0)));
+                let in_gradient = |x: f64, y: f64| {
+                    let p = world_to_camera_i32(camera, &initial_pose, &WorldPoint(Point3::new(x, y, 0.0)));
+                    (p.0 - options.gradient_origin.0 as i32, p.1 - options.gradient_origin.1 as i32)
+                };
+                let orig = in_gradient(2.0, 2.0);
+                let x = in_gradient(2.0, inner_edge);
+                let y = in_gradient(inner_edge, 2.0);
+                let xy = in_gradient(inner_edge, inner_edge);
                 draw_antialiased_line_segment_mut(gradient, orig, x, Luma {
                     data: [0],
                 }, sum_grad);
@@ -485,21 +899,72 @@ impl DecodedLFTag {
 
                 let mean_grad = *grad_sum.borrow() / *weight_total.borrow();
                 total_bit_dist /= mean_grad;
+                // no border under the projected edges, e.g. they left the image
+                if !total_bit_dist.is_finite() {
+                    i += 1;
+                    continue
+                }
+
+                // collected from the last node down, data node k is bits 2k and 2k + 1
+                node_confidence.reverse();
+
+                // a tag and its mirror image read as two valid ids with the same residual, only the
+                // known id or the dictionary tell them apart, and erasures need either to be filled
+                let mask = erasure_mask(&erasures);
+                let admissible = match (options.known_id, &options.dictionary) {
+                    (Some(id), _) => id & mask == data & mask,
+                    (None, Some(dictionary)) => dictionary.recover(data, &erasures).is_some(),
+                    (None, None) => erasures.is_empty(),
+                };
 
-                if total_bit_dist < best_res {
+                ranked.push((!admissible, DecodeHypothesis {
+                    data,
+                    pose: initial_pose.clone(),
+                    quality: total_bit_dist / (bg.area as f32).sqrt(),
+                    confidence: node_confidence.iter().map(NodeConfidence::confidence).fold(1.0, f32::min),
+                    calibration: (bl_idx, br_idx),
+                    mirrored,
+                    erasures: erasures.clone(),
+                }));
+
+                if admissible && total_bit_dist < best_res {
                     best_res = total_bit_dist;
                     best_data = data;
                     best_loc = Some(initial_pose);
                     best_idx = i;
                     best_data_pts_world = data_pts_world;
-                    best_data_pts = data_pts
+                    best_data_pts = data_pts;
+                    best_mirrored = mirrored;
+                    best_node_confidence = node_confidence;
+                    best_erasures = erasures;
                 }
                 i += 1;
                 // println!("data: {}, res: {}", data, total_bit_dist);
                 // dbg!(mean_grad*total_bit_dist);
             }
 
+            let best_loc = best_loc?;
+
+            // erased symbols have to be filled in from the known id or the dictionary
+            let mask = erasure_mask(&best_erasures);
+            if let Some(id) = options.known_id {
+                if id & mask != best_data & mask {
+                    return None
+                }
+                best_data = id;
+            } else if let Some(dictionary) = &options.dictionary {
+                best_data = dictionary.recover(best_data, &best_erasures)?;
+            } else if !best_erasures.is_empty() {
+                return None
+            }
+            for &node in &best_erasures {
+                best_node_confidence[node].symbol = (best_data >> (2 * node)) & 0b11;
+            }
+
+            // the same normalized residual the hypotheses were ranked by
             let quality_metric = best_res / (bg.area as f32).sqrt();
+            // admissible hypotheses first, which puts the reported one in front
+            ranked.sort_by(|a: &(bool, DecodeHypothesis), b| a.0.cmp(&b.0).then(a.1.quality.partial_cmp(&b.1.quality).unwrap_or(Ordering::Equal)));
 
             // println!("area: {}", (bg.area as f32).sqrt() / 3.0);
             match class {
@@ -530,7 +995,7 @@ impl DecodedLFTag {
             final_world_pts.push(node_pos[0][0]);
             final_world_pts.push(node_pos[1][0]);
 
-            let final_pose = opencv_localize(&camera, &final_world_pts, &final_camera_pts);
+            let final_pose = opencv_localize(camera, &final_world_pts, &final_camera_pts);
 
             // println!("best_idx: {} ,best_res: {}", best_idx, best_res);
 
@@ -541,13 +1006,23 @@ impl DecodedLFTag {
             }).collect();
 
             Some(DecodedLFTag{
-                initial_pose: best_loc.unwrap(),
+                initial_pose: best_loc,
                 final_pose,
                 initial_poses: poses,
+                hypotheses: ranked.into_iter().map(|(_, h)| h).collect(),
                 data: best_data as usize,
                 node_pos: final_camera_pts,
+                node_world_pos: final_world_pts,
                 expected_node_pos: best_data_pts,
-                class: class.clone()
+                bounding_box: bg.bounding_box,
+                class: class.clone(),
+                tag_size: class.get_pix_size() as f64,
+                quality: quality_metric,
+                inverted: *inverted,
+                mirrored: best_mirrored,
+                confidence: best_node_confidence.iter().map(NodeConfidence::confidence).fold(1.0, f32::min),
+                node_confidence: best_node_confidence,
+                erasures: best_erasures,
             })
         } else {
             None
@@ -556,6 +1031,7 @@ impl DecodedLFTag {
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde-serialize", derive(Serialize, Deserialize))]
 pub enum LFTagClass {
     LFTag2x2,
     LFTag3x3,
@@ -574,10 +1050,18 @@ impl LFTagClass {
         }
     }
 
-    fn total_node_count(&self) -> usize {
+    pub(crate) fn total_node_count(&self) -> usize {
         self.data_node_count() + 2
     }
 
+    /// Centers of the baseline nodes followed by those of the data nodes, in grid units.
+    fn node_centers(&self) -> Vec<(f32, f32)> {
+        let pix = self.get_pix_size();
+        let mut centers = vec![(6.0, 6.0), (pix - 6.0, 6.0)];
+        centers.extend(self.get_data_pos().iter().map(|p| ((p[0].0 + p[3].0) / 2.0, (p[0].1 + p[3].1) / 2.0)));
+        centers
+    }
+
     fn get_height(&self) -> usize {
         match self {
             LFTagClass::LFTag2x2 => 2,
@@ -591,10 +1075,30 @@ impl LFTagClass {
         self.get_height()
     }
 
-    fn get_pix_size(&self) -> f32 {
+    pub fn get_pix_size(&self) -> f32 {
         ((self.get_height() + 1) * 6) as f32
     }
 
+    /// Grid name as used on the command line, e.g. "4x4".
+    pub fn name(&self) -> &'static str {
+        match self {
+            LFTagClass::LFTag2x2 => "2x2",
+            LFTagClass::LFTag3x3 => "3x3",
+            LFTagClass::LFTag4x4 => "4x4",
+            LFTagClass::LFTag5x5 => "5x5",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<LFTagClass> {
+        match name {
+            "2x2" => Some(LFTagClass::LFTag2x2),
+            "3x3" => Some(LFTagClass::LFTag3x3),
+            "4x4" => Some(LFTagClass::LFTag4x4),
+            "5x5" => Some(LFTagClass::LFTag5x5),
+            _ => None,
+        }
+    }
+
     fn get_keypoint_pos(&self) -> Vec<Vec<(f32, f32)>> {
         let pix = self.get_pix_size();
         // baseline
@@ -617,6 +1121,58 @@ impl LFTagClass {
         ret
     }
 
+    /// Sub-positions of the bottom left and bottom right calibration dots encoded by `data`, as
+    /// indices into `get_keypoint_pos`.
+    pub fn calibration_hypothesis(&self, data: usize) -> (usize, usize) {
+        let keypoints = self.get_keypoint_pos();
+        let data_pos = self.get_data_pos();
+        let bl_node = (self.get_height() - 1) * self.get_width() - 2;
+        let br_node = data_pos.len() - 1;
+
+        let find = |node: usize, candidates: &Vec<(f32, f32)>| {
+            let symbol = (data >> (2 * node)) & 0b11;
+            let pos = data_pos[node][symbol];
+            candidates.iter()
+                .position(|c| (c.0 - pos.0).abs() < 1e-3 && (c.1 - pos.1).abs() < 1e-3)
+                .unwrap()
+        };
+
+        (find(bl_node, &keypoints[2]), find(br_node, &keypoints[3]))
+    }
+
+    /// Printable image of the tag encoding `data`, laid out like `LFtag/generate.py` with every grid
+    /// unit drawn as `scale` x `scale` pixels.
+    pub fn render(&self, data: usize, scale: u32) -> GrayImage {
+        let pix = self.get_pix_size() as u32;
+        let mut image = GrayImage::from_pixel(pix * scale, pix * scale, Luma { data: [255] });
+        // blackens a square given in grid units
+        let mut fill = |x0: u32, y0: u32, size: u32| {
+            for y in y0 * scale..(y0 + size) * scale {
+                for x in x0 * scale..(x0 + size) * scale {
+                    image.put_pixel(x, y, Luma { data: [0] });
+                }
+            }
+        };
+
+        // border two grid units wide
+        for i in 0..pix - 1 {
+            fill(i, 0, 2);
+            fill(i, pix - 2, 2);
+            fill(0, i, 2);
+            fill(pix - 2, i, 2);
+        }
+        fill(4, 4, 4);
+        fill(pix - 8, 4, 4);
+
+        for (node, positions) in self.get_data_pos().iter().enumerate() {
+            let (x, y) = positions[(data >> (2 * node)) & 0b11];
+            // 3x3 square centered on the sub-position
+            fill((x - 1.5) as u32, (y - 1.5) as u32, 3);
+        }
+
+        image
+    }
+
     fn get_data_pos(&self) -> Vec<[(f32, f32);4]> {
         let mut ret = Vec::new();
 
@@ -638,6 +1194,7 @@ impl LFTagClass {
 
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde-serialize", derive(Serialize, Deserialize))]
 pub enum TopotagClass {
     Topotag3x3,
     Topotag4x4,
@@ -673,6 +1230,12 @@ impl TopotagClass {
         }
     }
 
+    /// Edge length of the tag in the units of `get_expected_node_pos`, the nodes sit one unit
+    /// apart with a one unit margin to the edge.
+    pub fn get_pix_size(&self) -> f32 {
+        (self.get_width() + 1) as f32
+    }
+
     pub fn get_expected_node_pos(&self) -> Vec<(f32, f32)> {
         let mut out = Vec::new();
         for i in 0..self.get_height() {
@@ -793,6 +1356,19 @@ pub fn detect_lftag(
     start: u32,
     output: &mut HashMap<[(u32, u32); 2], LFTagComponents>,
     class: &Vec<LFTagClass>,
+) -> LFTagComponents {
+    detect_lftag_with(topo, start, output, class, false, 0)
+}
+
+/// Like `detect_lftag`, with `inverted` looking for light nodes in a dark background region and
+/// accepting regions with up to `max_missing` nodes fewer than a tag class has.
+pub fn detect_lftag_with(
+    topo: &Vec<FeatureVector>,
+    start: u32,
+    output: &mut HashMap<[(u32, u32); 2], LFTagComponents>,
+    class: &Vec<LFTagClass>,
+    inverted: bool,
+    max_missing: usize,
 ) -> LFTagComponents {
     let mut child = topo[start as usize].child;
     let current = &topo[start as usize];
@@ -812,38 +1388,341 @@ pub fn detect_lftag(
     }
 
     for &c in &all_children {
-        detect_lftag(topo, c, output, &class);
+        detect_lftag_with(topo, c, output, &class, inverted, max_missing);
     }
 
-    if children.len() == 0 && !current.color {
+    // background regions are light and nodes dark, or the other way round when inverted
+    if children.len() == 0 && current.color == inverted {
         return LFTagComponents::Normal {
             node: current.clone(),
         };
     }
 
-    for tag_class in class {
-        if node_children.len() >= tag_class.total_node_count() && current.color {
-            let mut nodes = Vec::new();
-            for &c in &node_children {
-                match detect_lftag(topo, c, output, &class) {
-                    n @ LFTagComponents::Normal{..} => {
-                        nodes.push(n)
-                    }
-                    _ => {}
+    let min_nodes = class.iter().map(|c| c.total_node_count().saturating_sub(max_missing)).min();
+    if min_nodes.map_or(false, |min| node_children.len() >= min) && current.color != inverted {
+        let mut nodes = Vec::new();
+        for &c in &node_children {
+            match detect_lftag_with(topo, c, output, &class, inverted, max_missing) {
+                n @ LFTagComponents::Normal{..} => {
+                    nodes.push(n)
                 }
+                _ => {}
             }
+        }
 
-            if nodes.len() >= tag_class.total_node_count() && nodes.len() <= tag_class.total_node_count() + 3 {
-                let root = LFTagComponents::Root {
-                    nodes,
-                    bg: current.clone(),
-                    class: (*tag_class).clone(),
-                };
-                output.insert(current.bounding_box, root.clone());
-                return root;
-            }
+        // a class with about as many nodes first, then the largest class that fits (decoding sorts
+        // out extra blobs such as dirt or text), then classes with missing nodes
+        let count = nodes.len();
+        let tag_class = class.iter()
+            .find(|c| count >= c.total_node_count() && count <= c.total_node_count() + 3)
+            .or_else(|| class.iter().filter(|c| count >= c.total_node_count()).max_by_key(|c| c.total_node_count()))
+            .or_else(|| class.iter().find(|c| count < c.total_node_count() && count + max_missing >= c.total_node_count()));
+
+        if let Some(tag_class) = tag_class {
+            let root = LFTagComponents::Root {
+                nodes,
+                bg: current.clone(),
+                class: (*tag_class).clone(),
+                inverted,
+            };
+            output.insert(current.bounding_box, root.clone());
+            return root;
         }
     }
 
     return LFTagComponents::None;
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+impl DecodedLFTag {
+    /// Detection of a `class` tag encoding `data` at `pose` (tag in camera, grid units), with every
+    /// node projected exactly through `camera`.
+    pub(crate) fn synthetic<C: Camera>(camera: &C, class: LFTagClass, data: usize, pose: WorldPose) -> DecodedLFTag {
+        let pix = class.get_pix_size();
+        let mut node_world_pos: Vec<(f32, f32)> = class.get_data_pos().iter().enumerate()
+            .map(|(node, positions)| positions[(data >> (2 * node)) & 0b11])
+            .collect();
+        node_world_pos.push((6.0, 6.0));
+        node_world_pos.push((pix - 6.0, 6.0));
+        let node_pos: Vec<(f32, f32)> = node_world_pos.iter()
+            .map(|p| world_to_camera(camera, &pose, &WorldPoint(Point3::new(p.0 as f64, p.1 as f64, 0.0))))
+            .collect();
+
+        DecodedLFTag {
+            data,
+            expected_node_pos: node_pos.clone(),
+            node_pos,
+            node_world_pos,
+            bounding_box: [(0, 0), (0, 0)],
+            class,
+            tag_size: pix as f64,
+            quality: 0.0,
+            inverted: false,
+            mirrored: false,
+            node_confidence: Vec::new(),
+            confidence: 1.0,
+            erasures: Vec::new(),
+            initial_pose: pose.clone(),
+            initial_poses: vec![pose.clone()],
+            hypotheses: Vec::new(),
+            final_pose: pose,
+        }
+    }
+
+    /// `synthetic` for a tag printed `size` wide at `pose` (tag in camera, units of `size`), with
+    /// every node pixel moved by up to `noise` in each direction.
+    pub(crate) fn synthetic_sized<C: Camera>(camera: &C, class: LFTagClass, data: usize, size: f64, pose: &IsometryMatrix3<f64>, noise: f32, rng: &mut SmallRng) -> DecodedLFTag {
+        use rand::Rng;
+
+        let grid = class.get_pix_size() as f64 / size;
+        let mut tag = DecodedLFTag::synthetic(camera, class, data, scale_pose(&WorldPose(*pose), grid));
+        tag.set_tag_size(size);
+        if noise > 0.0 {
+            for p in &mut tag.node_pos {
+                p.0 += rng.gen_range(-noise, noise);
+                p.1 += rng.gen_range(-noise, noise);
+            }
+        }
+        tag
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera::BrownConrady;
+    use crate::detector::LFTagDetector;
+    use cv_pinhole::CameraIntrinsics;
+    use image::imageops;
+    use imageproc::filter::gaussian_blur_f32;
+    use nalgebra::Vector2;
+
+    // pixels per grid unit and position of the tag in the test images
+    const SCALE: u32 = 8;
+    const OFFSET: u32 = 40;
+    const SIZE: u32 = 320;
+
+    fn detector() -> LFTagDetector<BrownConrady> {
+        let center = SIZE as f64 / 2.0;
+        LFTagDetector::new(BrownConrady::new(
+            CameraIntrinsics {
+                focals: Vector2::new(400.0, 400.0),
+                principal_point: Point2::new(center, center),
+                skew: 0.0,
+            },
+            [0.0; 5],
+        ))
+    }
+
+    fn scene(class: &LFTagClass, data: usize) -> GrayImage {
+        let mut image = GrayImage::from_pixel(SIZE, SIZE, Luma { data: [255] });
+        imageops::overlay(&mut image, &class.render(data, SCALE), OFFSET, OFFSET);
+        image
+    }
+
+    // fills a rectangle given in tag grid units
+    fn paint(image: &mut GrayImage, from: (f32, f32), to: (f32, f32), value: u8) {
+        let px = |v: f32| OFFSET + (v * SCALE as f32) as u32;
+        for y in px(from.1)..px(to.1) {
+            for x in px(from.0)..px(to.0) {
+                image.put_pixel(x, y, Luma { data: [value] });
+            }
+        }
+    }
+
+    // image position of a tag grid point, pixel centroids sit half a pixel before the grid point
+    fn grid_to_image(point: (f32, f32)) -> (f32, f32) {
+        let px = |v: f32| OFFSET as f32 + v * SCALE as f32 - 0.5;
+        (px(point.0), px(point.1))
+    }
+
+    fn assert_near(actual: (f32, f32), expected: (f32, f32)) {
+        let dist = ((actual.0 - expected.0).powi(2) + (actual.1 - expected.1).powi(2)).sqrt();
+        assert!(dist < 1.0, "{:?} is not near {:?}", actual, expected);
+    }
+
+    #[test]
+    fn ignores_large_distractors() {
+        let class = LFTagClass::LFTag4x4;
+        let id = 0x2d1_b34e;
+        let pix = class.get_pix_size();
+        let mut image = scene(&class, id);
+
+        // bars inside the border and between the node rows, each larger than a baseline node
+        paint(&mut image, (4.0, 2.5), (pix - 4.0, 3.5), 0);
+        paint(&mut image, (4.0, pix - 3.5), (pix - 4.0, pix - 2.5), 0);
+        paint(&mut image, (2.5, 4.0), (3.5, pix - 4.0), 0);
+        paint(&mut image, (pix - 3.5, 4.0), (pix - 2.5, pix - 4.0), 0);
+        for row in 0..3 {
+            let y = 8.5 + 6.0 * row as f32;
+            paint(&mut image, (4.0, y), (pix - 4.0, y + 1.0), 0);
+        }
+
+        let tags = detector().detect(&image).unwrap();
+        assert_eq!(tags.len(), 1);
+        let tag = &tags[0];
+        assert_eq!(tag.data, id);
+        assert_eq!(tag.node_pos.len(), class.total_node_count());
+
+        // the baseline nodes come last
+        let n = tag.node_pos.len();
+        assert_near(tag.node_pos[n - 2], grid_to_image((6.0, 6.0)));
+        assert_near(tag.node_pos[n - 1], grid_to_image((pix - 6.0, 6.0)));
+    }
+
+    // covers a data node with background, the tag being a 4x4 one
+    fn occlude(image: &mut GrayImage, node: usize) {
+        let center = LFTagClass::LFTag4x4.node_centers()[node + 2];
+        paint(image, (center.0 - 2.5, center.1 - 2.5), (center.0 + 2.5, center.1 + 2.5), 255);
+    }
+
+    #[test]
+    fn recovers_occluded_nodes() {
+        let class = LFTagClass::LFTag4x4;
+        let id = 0x2d1_b34e;
+        let mut detector = detector();
+        detector.classes = vec![class.clone()];
+        detector.max_missing = 2;
+        // the other ids differ from `id` on nodes that stay visible
+        detector.dictionary = Some(Dictionary::new(vec![id ^ (0b11 << 10), id, id ^ (0b01 << 16)]));
+
+        let mut image = scene(&class, id);
+        occlude(&mut image, 3);
+        let tags = detector.detect(&image).unwrap();
+        assert_eq!(tags.len(), 1);
+        assert_eq!(tags[0].data, id);
+        assert_eq!(tags[0].erasures, vec![3]);
+        assert!(tags[0].node_confidence[3].erased);
+        assert_eq!(tags[0].node_confidence[3].symbol, (id >> 6) & 0b11);
+
+        occlude(&mut image, 6);
+        let tags = detector.detect(&image).unwrap();
+        assert_eq!(tags.len(), 1);
+        assert_eq!(tags[0].data, id);
+        let mut erasures = tags[0].erasures.clone();
+        erasures.sort();
+        assert_eq!(erasures, vec![3, 6]);
+
+        // one more than allowed
+        occlude(&mut image, 8);
+        assert!(detector.detect(&image).unwrap().is_empty());
+
+        // two missing nodes without a dictionary to fill them in
+        detector.dictionary = None;
+        let mut image = scene(&class, id);
+        occlude(&mut image, 3);
+        occlude(&mut image, 6);
+        assert!(detector.detect(&image).unwrap().is_empty());
+    }
+
+    #[test]
+    fn hypotheses_ranked_by_residual() {
+        let class = LFTagClass::LFTag3x3;
+        let id = 0x1234;
+        let tags = detector().detect(&scene(&class, id)).unwrap();
+        assert_eq!(tags.len(), 1);
+        let tag = &tags[0];
+
+        // at most one per calibration dot placement, placements with a non-finite residual are
+        // dropped
+        let keypoints = class.get_keypoint_pos();
+        let placements = keypoints[2].len() * keypoints[3].len();
+        assert!(tag.hypotheses.len() > 1 && tag.hypotheses.len() <= placements);
+        assert!(tag.hypotheses.iter().all(|h| h.quality.is_finite()));
+        assert_eq!(tag.hypotheses[0].data, id);
+        assert_eq!(tag.hypotheses[0].calibration, class.calibration_hypothesis(id));
+        assert!(tag.hypotheses.windows(2).all(|w| w[0].quality <= w[1].quality));
+        assert!(tag.hypotheses[0].quality < tag.hypotheses[1].quality);
+
+        assert_eq!(tag.top_hypotheses(3).len(), 3);
+        assert_eq!(tag.top_hypotheses(3), &tag.hypotheses[..3]);
+        assert_eq!(tag.top_hypotheses(100).len(), tag.hypotheses.len());
+    }
+
+    #[test]
+    fn inverted_and_mirrored_tags() {
+        let class = LFTagClass::LFTag3x3;
+        let id = 0x1234;
+
+        let mut inverted = scene(&class, id);
+        imageops::invert(&mut inverted);
+        let mut light = detector();
+        assert_eq!(light.polarity, Polarity::DarkOnLight);
+        assert!(light.detect(&inverted).unwrap().is_empty());
+        for &polarity in &[Polarity::LightOnDark, Polarity::Both] {
+            light.polarity = polarity;
+            let tags = light.detect(&inverted).unwrap();
+            assert_eq!(tags.len(), 1);
+            assert_eq!(tags[0].data, id);
+            assert!(tags[0].inverted);
+            assert!(!tags[0].mirrored);
+        }
+
+        // read as it is, the mirror image is a tag with another id
+        let flipped = imageops::flip_horizontal(&scene(&class, id));
+        let mut mirror = detector();
+        let tags = mirror.detect(&flipped).unwrap();
+        assert_eq!(tags.len(), 1);
+        assert_ne!(tags[0].data, id);
+
+        mirror.dictionary = Some(Dictionary::new(vec![id]));
+        assert!(mirror.detect(&flipped).unwrap().is_empty());
+        mirror.mirrored = true;
+        let tags = mirror.detect(&flipped).unwrap();
+        assert_eq!(tags.len(), 1);
+        assert_eq!(tags[0].data, id);
+        assert!(tags[0].mirrored);
+        assert!(!tags[0].inverted);
+
+        // the unflipped tag is still read directly
+        let tags = mirror.detect(&scene(&class, id)).unwrap();
+        assert_eq!(tags.len(), 1);
+        assert!(!tags[0].mirrored);
+    }
+
+    #[test]
+    fn confidence_of_degraded_tags() {
+        let class = LFTagClass::LFTag3x3;
+        let id = 0x1234;
+        let clean = scene(&class, id);
+
+        // data nodes printed a little off their sub-positions, then blurred
+        let mut degraded = clean.clone();
+        for (node, positions) in class.get_data_pos().iter().enumerate() {
+            let (x, y) = positions[(id >> (2 * node)) & 0b11];
+            let dx = if node % 2 == 0 { 0.25 } else { -0.25 };
+            let dy = if node % 3 == 0 { 0.25 } else { -0.125 };
+            paint(&mut degraded, (x - 1.5, y - 1.5), (x + 1.5, y + 1.5), 255);
+            paint(&mut degraded, (x - 1.5 + dx, y - 1.5 + dy), (x + 1.5 + dx, y + 1.5 + dy), 0);
+        }
+        let degraded = gaussian_blur_f32(&degraded, 1.5);
+
+        let clean_tag = detector().detect(&clean).unwrap().remove(0);
+        let degraded_tag = detector().detect(&degraded).unwrap().remove(0);
+        assert_eq!(clean_tag.data, id);
+        assert_eq!(degraded_tag.data, id);
+        assert!(clean_tag.confidence > 0.9);
+        assert!(degraded_tag.confidence < clean_tag.confidence - 0.1);
+
+        assert_eq!(clean_tag.node_confidence.len(), class.total_node_count() - 2);
+        for (node, confidence) in clean_tag.node_confidence.iter().enumerate() {
+            assert_eq!(confidence.symbol, (id >> (2 * node)) & 0b11);
+            assert!(!confidence.erased);
+        }
+
+        // an erased node takes the confidence of the whole tag down with it
+        let class = LFTagClass::LFTag4x4;
+        let id = 0x2d1_b34e;
+        let mut occluded = scene(&class, id);
+        occlude(&mut occluded, 4);
+        let mut detector = detector();
+        detector.max_missing = 1;
+        detector.dictionary = Some(Dictionary::new(vec![id]));
+        let tag = detector.detect(&occluded).unwrap().remove(0);
+        assert_eq!(tag.data, id);
+        assert_eq!(tag.node_confidence.iter().filter(|n| n.erased).count(), 1);
+        assert!(tag.node_confidence[4].erased);
+        assert_eq!(tag.node_confidence[4].confidence(), 0.0);
+        assert_eq!(tag.confidence, 0.0);
+    }
+}
@@ -0,0 +1,292 @@
+use image::{GenericImageView, GrayImage, ImageBuffer, Luma};
+use imageproc::definitions::Image;
+use std::collections::HashMap;
+use std::io;
+
+use crate::camera::Camera;
+use crate::debug::{dilate_fv, sharpen3x3};
+use crate::decode::{detect_lftag_with, DecodeOptions, DecodedLFTag, LFTagClass, LFTagComponents, Polarity};
+use crate::dictionary::Dictionary;
+use crate::localize::TagSize;
+use crate::raw::RawImage;
+use crate::source::{Frame, FrameSource};
+use crate::segmentation::FeatureVector;
+use crate::{add_border, create_threshold, segmentation, Intensity};
+
+pub struct LFTagDetector<C: Camera> {
+    pub camera: C,
+    pub classes: Vec<LFTagClass>,
+    // poses are in tag grid units for ids without a known size
+    pub tag_size: TagSize,
+    pub polarity: Polarity,
+    // also decode mirror images of tags
+    pub mirrored: bool,
+    // data nodes a tag may be missing, recovered through `dictionary`
+    pub max_missing: usize,
+    pub dictionary: Option<Dictionary>,
+}
+
+/// Adaptive threshold, `hard_thresh` is in 8-bit units and scaled to the sample range.
+pub(crate) fn binarize<T: Intensity>(input: &Image<Luma<T>>, hard_thresh: u8) -> Option<GrayImage> {
+    let threshold_map = create_threshold(input)?;
+    let hard_thresh = hard_thresh as u32 * T::MAX / 255;
+    let dim = input.dimensions();
+    let mut output = ImageBuffer::new(dim.0, dim.1);
+
+    for i in input.enumerate_pixels() {
+        let value = i.2.data[0].to_u32();
+        let thresh = threshold_map.get_pixel(i.0, i.1).data[0].to_u32();
+        output.put_pixel(
+            i.0,
+            i.1,
+            Luma {
+                data: [{
+                    if (value > thresh || value > (T::MAX - hard_thresh))
+                        && (value > hard_thresh) {
+                        255
+                    } else {
+                        0
+                    }
+                }],
+            },
+        )
+    }
+
+    add_border(&mut output);
+    Some(output)
+}
+
+impl<C: Camera> LFTagDetector<C> {
+    pub fn new(camera: C) -> LFTagDetector<C> {
+        LFTagDetector {
+            camera,
+            classes: vec![LFTagClass::LFTag3x3, LFTagClass::LFTag4x4],
+            tag_size: TagSize::default(),
+            polarity: Polarity::default(),
+            mirrored: false,
+            max_missing: 0,
+            dictionary: None,
+        }
+    }
+
+    /// Finds and decodes all tags in an 8 or 16-bit image, returns None if the image dimensions
+    /// are not a multiple of 16.
+    pub fn detect<T: Intensity>(&self, input: &Image<Luma<T>>) -> Option<Vec<DecodedLFTag>> {
+        let mut gradient = T::gradients(input);
+        self.detect_region(input, (0, 0), &mut gradient, &DecodeOptions::default())
+    }
+
+    /// Detects tags in a capture buffer (YUYV, NV12, Bayer, ...) using its luma directly. Bayer
+    /// mosaics are interpolated at full resolution; detect on `RawImage::bayer_green_half` with a
+    /// `half_resolution_camera` for the cheaper path. Returns None if the buffer is too small or the
+    /// dimensions are not a multiple of 16.
+    pub fn detect_raw(&self, input: &RawImage) -> Option<Vec<DecodedLFTag>> {
+        self.detect(&input.to_luma()?)
+    }
+
+    /// Detects tags in every frame of a source, handing each frame and its detections to
+    /// `on_frame`. Stops at the end of the source, when `on_frame` returns false or at the first
+    /// frame that fails to load, returns the number of frames processed.
+    pub fn run<S: FrameSource + ?Sized, F: FnMut(&Frame, &[DecodedLFTag]) -> bool>(&self, source: &mut S, mut on_frame: F) -> io::Result<usize> {
+        let mut count = 0;
+        while let Some(frame) = source.next_frame() {
+            let frame = frame?;
+            let tags = self.detect(&frame.image).unwrap_or_default();
+            count += 1;
+            if !on_frame(&frame, &tags) {
+                break;
+            }
+        }
+        Ok(count)
+    }
+
+    /// Detects only inside `roi` ([min, max] corners in pixels), which is grown to a multiple of 16
+    /// and clamped to the image. Returned tags are in full image coordinates.
+    pub fn detect_in_roi<T: Intensity>(&self, input: &Image<Luma<T>>, roi: [(u32, u32); 2], options: &DecodeOptions) -> Option<Vec<DecodedLFTag>> {
+        let (width, height) = input.dimensions();
+        let x0 = roi[0].0.min(width) / 16 * 16;
+        let y0 = roi[0].1.min(height) / 16 * 16;
+        let x1 = ((roi[1].0.min(width) + 15) / 16 * 16).min(width / 16 * 16);
+        let y1 = ((roi[1].1.min(height) + 15) / 16 * 16).min(height / 16 * 16);
+        if x1 <= x0 || y1 <= y0 {
+            return None
+        }
+
+        let crop = input.view(x0, y0, x1 - x0, y1 - y0).to_image();
+        let mut gradient = T::gradients(&crop);
+        self.detect_region(&crop, (x0, y0), &mut gradient, options)
+    }
+
+    // `input` and `gradient` cover the image from `origin` on, detections are offset back to full
+    // image coordinates
+    fn detect_region<T: Intensity>(&self, input: &Image<Luma<T>>, origin: (u32, u32), gradient: &mut Image<Luma<u16>>, options: &DecodeOptions) -> Option<Vec<DecodedLFTag>> {
+        let input = sharpen3x3(input);
+
+        let output = binarize(&input, 10)?;
+
+        let topo = segmentation::to_topo(&output, &input);
+        let len = (topo.len() - 1) as u32;
+
+        let mut tags = HashMap::new();
+        for &inverted in self.polarity.variants() {
+            detect_lftag_with(&topo, len, &mut tags, &self.classes, inverted, self.max_missing);
+        }
+        let options = DecodeOptions {
+            mirrored: options.mirrored || self.mirrored,
+            max_missing: options.max_missing.max(self.max_missing),
+            dictionary: options.dictionary.clone().or_else(|| self.dictionary.clone()),
+            gradient_origin: origin,
+            ..options.clone()
+        };
+
+        let mut decoded = Vec::new();
+        for (_, mut tag) in tags {
+            if let LFTagComponents::Root { ref mut nodes, ref mut bg, .. } = tag {
+                for node in nodes {
+                    if let LFTagComponents::Normal { ref mut node } = node {
+                        dilate_fv(&output, &input, node);
+                        offset_fv(node, origin);
+                    }
+                }
+                offset_fv(bg, origin);
+            }
+
+            if let Some(mut d) = self.decode_any_class(&mut tag, gradient, &options) {
+                if let Some(size) = self.tag_size.get(d.data) {
+                    d.set_tag_size(size);
+                }
+                decoded.push(d);
+            }
+        }
+
+        Some(decoded)
+    }
+
+    // blobs beyond the tag nodes make the class guessed from the node count unreliable, the other
+    // classes with enough nodes are tried when it does not decode
+    fn decode_any_class(&self, tag: &mut LFTagComponents, gradient: &mut Image<Luma<u16>>, options: &DecodeOptions) -> Option<DecodedLFTag> {
+        if let Some(d) = DecodedLFTag::decode_lftag_with(tag, &self.camera, gradient, options) {
+            return Some(d)
+        }
+
+        let (count, guessed) = match tag {
+            LFTagComponents::Root { nodes, class, .. } => (nodes.len(), class.clone()),
+            _ => return None,
+        };
+        for candidate in &self.classes {
+            if *candidate == guessed || count + options.max_missing < candidate.total_node_count() {
+                continue
+            }
+            if let LFTagComponents::Root { ref mut class, .. } = tag {
+                *class = candidate.clone();
+            }
+            if let Some(d) = DecodedLFTag::decode_lftag_with(tag, &self.camera, gradient, options) {
+                return Some(d)
+            }
+        }
+        None
+    }
+}
+
+fn offset_fv(fv: &mut FeatureVector, origin: (u32, u32)) {
+    if origin == (0, 0) {
+        return
+    }
+    fv.fom[0] += fv.zom * origin.0 as f32;
+    fv.fom[1] += fv.zom * origin.1 as f32;
+    fv.max_x += origin.0;
+    for corner in &mut fv.bounding_box {
+        corner.0 += origin.0;
+        corner.1 += origin.1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera::BrownConrady;
+    use crate::raw::expand_bit_depth;
+    use cv_pinhole::CameraIntrinsics;
+    use image::imageops;
+    use nalgebra::{Point2, Vector2};
+
+    #[test]
+    fn roi_matches_full_frame() {
+        let detector = LFTagDetector::new(BrownConrady::new(
+            CameraIntrinsics {
+                focals: Vector2::new(400.0, 400.0),
+                principal_point: Point2::new(240.0, 160.0),
+                skew: 0.0,
+            },
+            [0.0; 5],
+        ));
+        let id = 0x1234;
+        let mut image = GrayImage::from_pixel(480, 320, Luma { data: [255] });
+        imageops::overlay(&mut image, &LFTagClass::LFTag3x3.render(id, 8), 200, 80);
+
+        let full = detector.detect(&image).unwrap();
+        assert_eq!(full.len(), 1);
+        let full = &full[0];
+        assert_eq!(full.data, id);
+
+        let roi = [(160, 48), (432, 304)];
+        let tracked = DecodeOptions {
+            known_id: Some(id),
+            prior_pose: Some(full.final_pose.clone()),
+            ..DecodeOptions::default()
+        };
+        for options in &[DecodeOptions::default(), tracked] {
+            let found = detector.detect_in_roi(&image, roi, options).unwrap();
+            assert_eq!(found.len(), 1);
+            let tag = &found[0];
+            assert_eq!(tag.data, id);
+            assert!((tag.quality - full.quality).abs() <= 1e-3 * full.quality.abs().max(1e-6));
+            assert_eq!(tag.node_pos.len(), full.node_pos.len());
+            for p in &full.node_pos {
+                assert!(tag.node_pos.iter().any(|q| (p.0 - q.0).abs() < 1e-2 && (p.1 - q.1).abs() < 1e-2));
+            }
+            let offset = tag.final_pose.0.translation.vector - full.final_pose.0.translation.vector;
+            assert!(offset.norm() < 1e-3 * full.final_pose.0.translation.vector.norm());
+        }
+    }
+
+    #[test]
+    fn bit_depths_decode_alike() {
+        let detector = LFTagDetector::new(BrownConrady::new(
+            CameraIntrinsics {
+                focals: Vector2::new(400.0, 400.0),
+                principal_point: Point2::new(160.0, 160.0),
+                skew: 0.0,
+            },
+            [0.0; 5],
+        ));
+        let id = 0x2d1_b34e;
+        let mut image = GrayImage::from_pixel(320, 320, Luma { data: [255] });
+        imageops::overlay(&mut image, &LFTagClass::LFTag4x4.render(id, 8), 40, 40);
+        let expected = detector.detect(&image).unwrap();
+        assert_eq!(expected.len(), 1);
+        let expected = &expected[0];
+        assert_eq!(expected.data, id);
+
+        let wide = |bits: u32| {
+            let max = (1 << bits) - 1;
+            let mut wide: Image<Luma<u16>> = ImageBuffer::from_fn(320, 320, |x, y| {
+                Luma { data: [(image.get_pixel(x, y).data[0] as u32 * max / 255) as u16] }
+            });
+            expand_bit_depth(&mut wide, bits);
+            wide
+        };
+        for &bits in &[12, 16] {
+            let found = detector.detect(&wide(bits)).unwrap();
+            assert_eq!(found.len(), 1);
+            let tag = &found[0];
+            assert_eq!(tag.data, expected.data);
+            assert_eq!(tag.node_pos.len(), expected.node_pos.len());
+            for p in &expected.node_pos {
+                assert!(tag.node_pos.iter().any(|q| (p.0 - q.0).abs() < 0.05 && (p.1 - q.1).abs() < 0.05));
+            }
+            let offset = tag.final_pose.0.translation.vector - expected.final_pose.0.translation.vector;
+            assert!(offset.norm() < 1e-2 * expected.final_pose.0.translation.vector.norm());
+        }
+    }
+}
@@ -0,0 +1,66 @@
+#[cfg(feature = "serde-serialize")]
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "serde-serialize")]
+use std::io;
+#[cfg(feature = "serde-serialize")]
+use std::path::Path;
+
+#[cfg(feature = "serde-serialize")]
+use crate::serialize::read_structured;
+
+/// The ids in use for a tag class, e.g. the tags printed for a deployment. Decoding checks reads
+/// against it and uses it to fill in the symbols of occluded nodes.
+#[derive(Debug, Clone, PartialEq, Default)]
+#[cfg_attr(feature = "serde-serialize", derive(Serialize, Deserialize))]
+pub struct Dictionary {
+    pub ids: Vec<usize>,
+}
+
+impl Dictionary {
+    pub fn new(ids: Vec<usize>) -> Dictionary {
+        Dictionary { ids }
+    }
+
+    /// Reads a JSON, YAML or TOML file with an `ids` list.
+    #[cfg(feature = "serde-serialize")]
+    pub fn load(path: &Path) -> io::Result<Dictionary> {
+        read_structured(path)
+    }
+
+    pub fn contains(&self, id: usize) -> bool {
+        self.ids.contains(&id)
+    }
+
+    /// The only id agreeing with `data` on every data node not listed in `erasures`, None if no id
+    /// or more than one does.
+    pub fn recover(&self, data: usize, erasures: &[usize]) -> Option<usize> {
+        let mask = erasure_mask(erasures);
+        let mut matches = self.ids.iter().filter(|&&id| id & mask == data & mask);
+        let id = *matches.next()?;
+        if matches.next().is_some() {
+            return None
+        }
+        Some(id)
+    }
+}
+
+/// Bits of the data nodes that are not erased.
+pub fn erasure_mask(erasures: &[usize]) -> usize {
+    erasures.iter().fold(!0, |mask, &node| mask & !(0b11 << (2 * node)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recovers_unique_id() {
+        let dictionary = Dictionary::new(vec![0b00_01_10, 0b11_01_10, 0b00_10_01]);
+
+        // middle node erased, only the first id matches the other two nodes
+        assert_eq!(dictionary.recover(0b00_00_10, &[1]), Some(0b00_01_10));
+        // top node erased, the first two ids both match
+        assert_eq!(dictionary.recover(0b00_01_10, &[2]), None);
+        assert_eq!(dictionary.recover(0b01_01_01, &[]), None);
+    }
+}
@@ -0,0 +1,298 @@
+use nalgebra::{IsometryMatrix3, Matrix3, Point2, Point3, Rotation3, Translation3, Vector3};
+#[cfg(feature = "serde-serialize")]
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "serde-serialize")]
+use std::io;
+#[cfg(feature = "serde-serialize")]
+use std::path::Path;
+
+use crate::camera::Camera;
+use crate::decode::DecodedLFTag;
+use crate::localize::{node_correspondences, project, reprojection_rms};
+use crate::optimize::{levenberg_marquardt, pose_from_params, pose_to_params, LeastSquares};
+use crate::pose::PoseRecord;
+#[cfg(feature = "serde-serialize")]
+use crate::serialize::read_structured;
+
+/// One robot station: the image taken there and the gripper pose in the robot base frame.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde-serialize", derive(Serialize, Deserialize))]
+pub struct RobotStation {
+    pub image: String,
+    #[cfg_attr(feature = "serde-serialize", serde(flatten))]
+    pub pose: PoseRecord,
+}
+
+/// Robot stations loaded from JSON, YAML or TOML, image paths are relative to the file.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde-serialize", derive(Serialize, Deserialize))]
+pub struct RobotStations {
+    pub stations: Vec<RobotStation>,
+}
+
+impl RobotStations {
+    #[cfg(feature = "serde-serialize")]
+    pub fn load(path: &Path) -> io::Result<RobotStations> {
+        read_structured(path)
+    }
+}
+
+/// The fixed tag as seen from one station.
+#[derive(Debug, Clone)]
+pub struct TargetObservation {
+    // tag frame in the camera frame
+    pub pose: IsometryMatrix3<f64>,
+    pub points: Vec<(Point3<f64>, Point2<f64>)>,
+}
+
+impl TargetObservation {
+    /// Picks the detection of tag `id` (or the only tag when None), in the units of its tag size.
+    pub fn from_detections(detections: &[DecodedLFTag], id: Option<usize>) -> Option<TargetObservation> {
+        let tag = match id {
+            Some(id) => detections.iter().find(|t| t.data == id)?,
+            None if detections.len() == 1 => &detections[0],
+            None => return None,
+        };
+
+        Some(TargetObservation {
+            pose: tag.final_pose.0,
+            points: node_correspondences(tag, tag.tag_size),
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HandEye {
+    // camera frame in the gripper frame
+    pub camera_in_gripper: IsometryMatrix3<f64>,
+    // tag frame in the robot base frame
+    pub target_in_base: IsometryMatrix3<f64>,
+    // disagreement of each station with the solution, rotation in radians and translation in the
+    // units of the robot poses
+    pub rotation_residuals: Vec<f64>,
+    pub translation_residuals: Vec<f64>,
+    pub image_errors: Vec<f64>,
+    pub rms: f64,
+}
+
+fn skew(v: &Vector3<f64>) -> Matrix3<f64> {
+    Matrix3::new(0.0, -v.z, v.y, v.z, 0.0, -v.x, -v.y, v.x, 0.0)
+}
+
+// modified rodrigues vector, 2 sin(theta / 2) times the axis
+fn rodrigues(rotation: &Rotation3<f64>) -> Vector3<f64> {
+    match rotation.axis_angle() {
+        Some((axis, angle)) => axis.into_inner() * 2.0 * (angle / 2.0).sin(),
+        None => Vector3::zeros(),
+    }
+}
+
+/// Closed form solution of AX = XB by Tsai and Lenz, from pairs of gripper motions A and camera
+/// motions B. Needs at least two motions with non-parallel rotation axes.
+pub fn tsai_lenz(motions: &[(IsometryMatrix3<f64>, IsometryMatrix3<f64>)]) -> Option<IsometryMatrix3<f64>> {
+    let mut ata = Matrix3::zeros();
+    let mut atb = Vector3::zeros();
+    for (a, b) in motions {
+        let pa = rodrigues(&a.rotation);
+        let pb = rodrigues(&b.rotation);
+        let m = skew(&(pa + pb));
+        ata += m.transpose() * m;
+        atb += m.transpose() * (pb - pa);
+    }
+    let p_prime = ata.try_inverse()? * atb;
+    let p = p_prime * 2.0 / (1.0 + p_prime.norm_squared()).sqrt();
+    let rotation = Matrix3::identity() * (1.0 - p.norm_squared() / 2.0)
+        + (p * p.transpose() + skew(&p) * (4.0 - p.norm_squared()).sqrt()) * 0.5;
+    let rotation = Rotation3::from_matrix_unchecked(rotation);
+
+    let mut ata = Matrix3::zeros();
+    let mut atb = Vector3::zeros();
+    for (a, b) in motions {
+        let m = a.rotation.matrix() - Matrix3::identity();
+        ata += m.transpose() * m;
+        atb += m.transpose() * (rotation * b.translation.vector - a.translation.vector);
+    }
+    let translation = ata.try_inverse()? * atb;
+
+    Some(IsometryMatrix3::from_parts(Translation3::from(translation), rotation))
+}
+
+struct HandEyeProblem<'a, C: Camera> {
+    camera: &'a C,
+    grippers: &'a [IsometryMatrix3<f64>],
+    observations: &'a [TargetObservation],
+}
+
+impl<'a, C: Camera> LeastSquares for HandEyeProblem<'a, C> {
+    fn blocks(&self) -> usize {
+        self.observations.len()
+    }
+
+    fn block_params(&self, _block: usize) -> Vec<usize> {
+        (0..12).collect()
+    }
+
+    fn residuals(&self, block: usize, params: &[f64]) -> Vec<f64> {
+        let pose = target_in_camera(&pose_from_params(&params[0..6]), &self.grippers[block], &pose_from_params(&params[6..12]));
+        self.observations[block].points.iter().flat_map(|(world, image)| {
+            let p = project(self.camera, &pose, world);
+            vec![p.x - image.x, p.y - image.y]
+        }).collect()
+    }
+}
+
+fn target_in_camera(camera_in_gripper: &IsometryMatrix3<f64>, gripper: &IsometryMatrix3<f64>, target_in_base: &IsometryMatrix3<f64>) -> IsometryMatrix3<f64> {
+    camera_in_gripper.inverse() * gripper.inverse() * target_in_base
+}
+
+/// Eye in hand calibration: the camera pose on the gripper from gripper poses in the base frame
+/// paired with observations of a tag fixed in the base frame. Tsai-Lenz gives the initial
+/// solution, which is refined by minimizing the reprojection error of the tag nodes over all
+/// stations. Translations of the robot poses and tag size must share units.
+pub fn calibrate_hand_eye<C: Camera>(camera: &C, grippers: &[IsometryMatrix3<f64>], observations: &[TargetObservation]) -> Option<HandEye> {
+    if grippers.len() != observations.len() || grippers.len() < 3 {
+        return None;
+    }
+
+    let motions: Vec<_> = (1..grippers.len()).map(|i| {
+        (
+            grippers[i - 1].inverse() * grippers[i],
+            observations[i - 1].pose * observations[i].pose.inverse(),
+        )
+    }).collect();
+    let initial = tsai_lenz(&motions)?;
+
+    let mut params = pose_to_params(&initial).to_vec();
+    params.extend_from_slice(&pose_to_params(&(grippers[0] * initial * observations[0].pose)));
+    let cost = levenberg_marquardt(&HandEyeProblem { camera, grippers, observations }, &mut params, 100);
+
+    let camera_in_gripper = pose_from_params(&params[0..6]);
+    let target_in_base = pose_from_params(&params[6..12]);
+
+    let mut rotation_residuals = Vec::new();
+    let mut translation_residuals = Vec::new();
+    let mut image_errors = Vec::new();
+    for (gripper, obs) in grippers.iter().zip(observations) {
+        let station_target = gripper * camera_in_gripper * obs.pose;
+        let delta = target_in_base.inverse() * station_target;
+        rotation_residuals.push(delta.rotation.angle());
+        translation_residuals.push((station_target.translation.vector - target_in_base.translation.vector).norm());
+        image_errors.push(reprojection_rms(camera, &target_in_camera(&camera_in_gripper, gripper, &target_in_base), &obs.points));
+    }
+    let point_count: usize = observations.iter().map(|o| o.points.len()).sum();
+
+    Some(HandEye {
+        camera_in_gripper,
+        target_in_base,
+        rotation_residuals,
+        translation_residuals,
+        image_errors,
+        rms: (cost / point_count as f64).sqrt(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera::BrownConrady;
+    use crate::decode::LFTagClass;
+    use crate::localize::refine_pose;
+    use cv_core::WorldPose;
+    use cv_pinhole::CameraIntrinsics;
+    use nalgebra::Vector2;
+    use rand::rngs::SmallRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn tsai_lenz_recovers_transform() {
+        let x = IsometryMatrix3::from_parts(Translation3::new(0.05, -0.02, 0.1), Rotation3::new(Vector3::new(0.1, -0.3, 0.2)));
+        let target = IsometryMatrix3::from_parts(Translation3::new(0.5, 0.1, 0.0), Rotation3::new(Vector3::new(3.0, 0.1, 0.0)));
+        let grippers = [
+            IsometryMatrix3::from_parts(Translation3::new(0.3, 0.0, 0.5), Rotation3::new(Vector3::new(0.0, 0.2, 0.0))),
+            IsometryMatrix3::from_parts(Translation3::new(0.4, 0.1, 0.45), Rotation3::new(Vector3::new(0.3, 0.0, 0.1))),
+            IsometryMatrix3::from_parts(Translation3::new(0.35, -0.1, 0.55), Rotation3::new(Vector3::new(-0.2, 0.1, 0.4))),
+        ];
+        let cameras: Vec<_> = grippers.iter().map(|g| target_in_camera(&x, g, &target)).collect();
+        let motions: Vec<_> = (1..3).map(|i| (grippers[i - 1].inverse() * grippers[i], cameras[i - 1] * cameras[i].inverse())).collect();
+
+        let solved = tsai_lenz(&motions).unwrap();
+        assert!((solved.translation.vector - x.translation.vector).norm() < 1e-6);
+        assert!((solved.rotation.inverse() * x.rotation).angle() < 1e-6);
+    }
+
+    fn camera() -> BrownConrady {
+        BrownConrady::new(
+            CameraIntrinsics {
+                focals: Vector2::new(700.0, 700.0),
+                principal_point: Point2::new(320.0, 240.0),
+                skew: 0.0,
+            },
+            [0.0; 5],
+        )
+    }
+
+    fn assert_pose_near(found: &IsometryMatrix3<f64>, expected: &IsometryMatrix3<f64>) {
+        assert!((found.translation.vector - expected.translation.vector).norm() < 5e-3);
+        assert!((found.rotation.inverse() * expected.rotation).angle() < 1e-2);
+    }
+
+    // camera frame in the gripper, tag frame in the base and stations given by where the tag
+    // appears in the camera
+    fn scene() -> (IsometryMatrix3<f64>, IsometryMatrix3<f64>, Vec<IsometryMatrix3<f64>>) {
+        let x = IsometryMatrix3::from_parts(Translation3::new(0.05, -0.02, 0.1), Rotation3::new(Vector3::new(0.1, -0.3, 0.2)));
+        let target = IsometryMatrix3::from_parts(Translation3::new(0.5, 0.1, 0.0), Rotation3::new(Vector3::new(3.0, 0.1, 0.0)));
+        let seen = vec![
+            IsometryMatrix3::from_parts(Translation3::new(0.0, 0.0, 0.6), Rotation3::from_euler_angles(0.0, 0.0, 0.0)),
+            IsometryMatrix3::from_parts(Translation3::new(0.05, -0.03, 0.55), Rotation3::from_euler_angles(0.3, 0.1, 0.2)),
+            IsometryMatrix3::from_parts(Translation3::new(-0.04, 0.04, 0.65), Rotation3::from_euler_angles(-0.2, 0.35, -0.1)),
+            IsometryMatrix3::from_parts(Translation3::new(0.02, 0.05, 0.5), Rotation3::from_euler_angles(0.1, -0.3, 0.5)),
+        ];
+        (x, target, seen)
+    }
+
+    #[test]
+    fn refines_rendered_stations() {
+        let camera = camera();
+        let mut rng = SmallRng::from_seed([5; 16]);
+        let (x, target, seen) = scene();
+
+        // X^-1 G^-1 T = C gives G = T C^-1 X^-1
+        let grippers: Vec<_> = seen.iter().map(|c| target * c.inverse() * x.inverse()).collect();
+        let observations: Vec<_> = seen.iter().map(|c| {
+            let mut tag = DecodedLFTag::synthetic_sized(&camera, LFTagClass::LFTag3x3, 6, 0.1, c, 0.2, &mut rng);
+            let (refined, _) = refine_pose(&camera, &node_correspondences(&tag, tag.tag_size), &tag.final_pose.0);
+            tag.final_pose = WorldPose(refined);
+            TargetObservation::from_detections(&[tag], Some(6)).unwrap()
+        }).collect();
+
+        let solution = calibrate_hand_eye(&camera, &grippers, &observations).unwrap();
+        assert_pose_near(&solution.camera_in_gripper, &x);
+        assert_pose_near(&solution.target_in_base, &target);
+
+        assert_eq!(solution.rotation_residuals.len(), seen.len());
+        assert_eq!(solution.translation_residuals.len(), seen.len());
+        assert_eq!(solution.image_errors.len(), seen.len());
+        assert!(solution.rotation_residuals.iter().all(|r| *r < 2e-2));
+        assert!(solution.translation_residuals.iter().all(|t| *t < 5e-3));
+        // nodes are off by up to 0.2 pixels in each direction
+        assert!(solution.image_errors.iter().all(|e| *e < 0.35));
+        assert!(solution.rms < 0.3);
+    }
+
+    #[test]
+    fn needs_three_matching_stations() {
+        let camera = camera();
+        let mut rng = SmallRng::from_seed([5; 16]);
+        let (x, target, seen) = scene();
+        let grippers: Vec<_> = seen.iter().map(|c| target * c.inverse() * x.inverse()).collect();
+        let observations: Vec<_> = seen.iter().map(|c| {
+            let tag = DecodedLFTag::synthetic_sized(&camera, LFTagClass::LFTag3x3, 6, 0.1, c, 0.0, &mut rng);
+            TargetObservation::from_detections(&[tag], None).unwrap()
+        }).collect();
+
+        assert!(calibrate_hand_eye(&camera, &grippers[..2], &observations[..2]).is_none());
+        assert!(calibrate_hand_eye(&camera, &grippers, &observations[..3]).is_none());
+        assert!(calibrate_hand_eye(&camera, &grippers[..3], &observations).is_none());
+        assert!(calibrate_hand_eye(&camera, &grippers, &observations).is_some());
+    }
+}
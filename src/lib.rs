@@ -14,14 +14,93 @@ extern crate quickcheck;
 #[cfg(test)]
 extern crate test;
 
-use image::{ImageBuffer, Luma, FilterType};
+use image::{ImageBuffer, Luma, FilterType, Primitive};
 use image::imageops::resize;
+use imageproc::definitions::Image;
+use imageproc::gradients::sobel_gradients;
 
+pub mod calibrate;
+pub mod calibration_file;
+pub mod bundle;
+pub mod camera;
 pub mod decode;
+pub mod detector;
+pub mod dictionary;
+pub mod handeye;
 pub mod localize;
+pub mod mapping;
+pub mod marker_map;
+pub mod optimize;
+pub mod pose;
+pub mod raw;
+pub mod relative;
+pub mod rig;
 pub mod segmentation;
+pub mod source;
+#[cfg(feature = "serde-serialize")]
+mod serialize;
+pub mod tracker;
 pub mod debug;
 
+/// Grayscale sample types the detector accepts. 16-bit images are expected to use the full range,
+/// see `raw::expand_bit_depth` for 10 and 12-bit sensor data.
+pub trait Intensity: Primitive + 'static {
+    const MAX: u32;
+
+    fn to_u32(self) -> u32;
+    fn from_u32(value: u32) -> Self;
+
+    // sobel gradient magnitudes, decoding draws its debug overlay onto these
+    fn gradients(image: &Image<Luma<Self>>) -> Image<Luma<u16>>;
+}
+
+impl Intensity for u8 {
+    const MAX: u32 = 255;
+
+    fn to_u32(self) -> u32 {
+        self as u32
+    }
+
+    fn from_u32(value: u32) -> u8 {
+        value as u8
+    }
+
+    fn gradients(image: &Image<Luma<u8>>) -> Image<Luma<u16>> {
+        sobel_gradients(image)
+    }
+}
+
+impl Intensity for u16 {
+    const MAX: u32 = 65535;
+
+    fn to_u32(self) -> u32 {
+        self as u32
+    }
+
+    fn from_u32(value: u32) -> u16 {
+        value as u16
+    }
+
+    // sobel on the full 16 bits, magnitudes scaled back to the range 8-bit input gives
+    fn gradients(image: &Image<Luma<u16>>) -> Image<Luma<u16>> {
+        let (width, height) = image.dimensions();
+        let at = |x: i64, y: i64| {
+            let x = x.max(0).min(width as i64 - 1) as u32;
+            let y = y.max(0).min(height as i64 - 1) as u32;
+            image.get_pixel(x, y).data[0] as i64
+        };
+        ImageBuffer::from_fn(width, height, |x, y| {
+            let (x, y) = (x as i64, y as i64);
+            let gx = at(x + 1, y - 1) + 2 * at(x + 1, y) + at(x + 1, y + 1)
+                - at(x - 1, y - 1) - 2 * at(x - 1, y) - at(x - 1, y + 1);
+            let gy = at(x - 1, y + 1) + 2 * at(x, y + 1) + at(x + 1, y + 1)
+                - at(x - 1, y - 1) - 2 * at(x, y - 1) - at(x + 1, y - 1);
+            let magnitude = ((gx * gx + gy * gy) as f64).sqrt() / 257.0;
+            Luma { data: [magnitude.min(u16::MAX as f64) as u16] }
+        })
+    }
+}
+
 fn add_border(input: &mut ImageBuffer<Luma<u8>, Vec<u8>>) {
     let (x, y) = input.dimensions();
     for i in 0..x {
@@ -35,7 +114,7 @@ fn add_border(input: &mut ImageBuffer<Luma<u8>, Vec<u8>>) {
     }
 }
 
-fn create_threshold(input: &ImageBuffer<Luma<u8>, Vec<u8>>) -> Option<ImageBuffer<Luma<u8>, Vec<u8>>> {
+fn create_threshold<T: Intensity>(input: &ImageBuffer<Luma<T>, Vec<T>>) -> Option<ImageBuffer<Luma<T>, Vec<T>>> {
     let dim = input.dimensions();
     let threshold_map_res_div: u32 = 16;
 
@@ -55,7 +134,7 @@ fn create_threshold(input: &ImageBuffer<Luma<u8>, Vec<u8>>) -> Option<ImageBuffe
     let mut threshold_map: Vec<Vec<u32>> = vec![vec![0; thresold_map_dim.0]; thresold_map_dim.1];
     for i in input.enumerate_pixels() {
         threshold_map[(i.1 / threshold_map_res_div) as usize]
-            [(i.0 / threshold_map_res_div) as usize] += i.2.data[0] as u32;
+            [(i.0 / threshold_map_res_div) as usize] += i.2.data[0].to_u32();
     }
     let mut output = ImageBuffer::new(dim.0, dim.1);
     for i in input.enumerate_pixels() {
@@ -67,10 +146,9 @@ fn create_threshold(input: &ImageBuffer<Luma<u8>, Vec<u8>>) -> Option<ImageBuffe
                     if i.0 >= (dim.0 - threshold_map_res_div)
                         || i.1 >= (dim.1 - threshold_map_res_div)
                     {
-                        ((threshold_map[(i.1 / threshold_map_res_div) as usize]
+                        T::from_u32((threshold_map[(i.1 / threshold_map_res_div) as usize]
                             [(i.0 / threshold_map_res_div) as usize])
                             / (threshold_map_res_div * threshold_map_res_div))
-                            as u8
                     } else {
                         let p1 = ((threshold_map[(i.1 / threshold_map_res_div) as usize]
                             [(i.0 / threshold_map_res_div) as usize])
@@ -92,9 +170,8 @@ fn create_threshold(input: &ImageBuffer<Luma<u8>, Vec<u8>>) -> Option<ImageBuffe
                         let y = i.1 % threshold_map_res_div;
                         let p5 = p3 * x + p1 * (threshold_map_res_div - x);
                         let p6 = p4 * x + p2 * (threshold_map_res_div - x);
-                        ((p6 * y + p5 * (threshold_map_res_div - y))
+                        T::from_u32((p6 * y + p5 * (threshold_map_res_div - y))
                             / (threshold_map_res_div * threshold_map_res_div))
-                            as u8
                     }
                 }],
             },
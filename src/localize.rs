@@ -1,20 +1,27 @@
-use crate::decode::DecodedTopotag;
+use crate::decode::{DecodedLFTag, DecodedTopotag};
+use crate::camera::Camera;
+use crate::optimize::{covariance, levenberg_marquardt, pose_from_params, pose_to_params, LeastSquares};
+use std::collections::HashMap;
 use cv_core::{CameraModel, FeatureWorldMatch, KeyPoint, WorldPoint, sample_consensus::Consensus, WorldPose};
-use cv_pinhole::{CameraIntrinsics, NormalizedKeyPoint};
+use cv_pinhole::NormalizedKeyPoint;
 use lambda_twist::LambdaTwist;
 use arrsac::{Arrsac, Config};
 use rand::{rngs::SmallRng, SeedableRng};
 use image::{ImageBuffer, Rgba};
 use imageproc::drawing::{draw_line_segment_mut, draw_antialiased_line_segment_mut};
-use nalgebra::{Point2, Point3, Isometry3, IsometryMatrix3, Translation, Vector3, Matrix3, Rotation3};
+use nalgebra::{Point2, Point3, Isometry3, IsometryMatrix3, Matrix6, Translation, Vector3, Matrix3, Rotation3};
 use opencv::core::{Vector, CV_64F};
 use opencv::prelude::*;
 use imageproc::pixelops::interpolate;
 
-pub fn opencv_localize(model: &CameraIntrinsics, world: &Vec<(f32, f32)>, camera: &Vec<(f32, f32)>) -> WorldPose {
+pub fn opencv_localize<C: Camera>(model: &C, world: &Vec<(f32, f32)>, camera: &Vec<(f32, f32)>) -> WorldPose {
+    // points are undistorted through the camera model, so opencv only sees an ideal pinhole camera
     let image_points_vector = opencv::core::Vector::from_iter(camera
         .iter()
-        .map(|(x, y)| opencv::core::Point2d::new(*x as f64, *y as f64)));
+        .map(|(x, y)| {
+            let normalized = model.calibrate(KeyPoint(Point2::new(*x as f64, *y as f64)));
+            opencv::core::Point2d::new(normalized.0.x, normalized.0.y)
+        }));
 
     let image_points: opencv::types::VectorOfPoint2d = image_points_vector.into();
 
@@ -27,9 +34,7 @@ pub fn opencv_localize(model: &CameraIntrinsics, world: &Vec<(f32, f32)>, camera
     let mut camera_matrix = vec![vec![0.0; 3]; 3];
 
     for i in 0..3 {
-        for j in 0..3 {
-            camera_matrix[i][j] = *model.matrix().get((i, j)).unwrap();
-        }
+        camera_matrix[i][i] = 1.0;
     }
 
     let mat = Mat::from_slice_2d(&camera_matrix).unwrap();
@@ -69,7 +74,49 @@ pub fn opencv_localize(model: &CameraIntrinsics, world: &Vec<(f32, f32)>, camera
     })
 }
 
-pub fn localize(model: &CameraIntrinsics, tag: &DecodedTopotag) -> WorldPose {
+/// Physical edge length of printed tags, globally and per tag id.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct TagSize {
+    pub default: Option<f64>,
+    pub per_id: HashMap<usize, f64>,
+}
+
+impl TagSize {
+    pub fn new(edge_length: f64) -> TagSize {
+        TagSize {
+            default: Some(edge_length),
+            per_id: HashMap::new(),
+        }
+    }
+
+    pub fn with_id(mut self, id: usize, edge_length: f64) -> TagSize {
+        self.per_id.insert(id, edge_length);
+        self
+    }
+
+    pub fn get(&self, id: usize) -> Option<f64> {
+        self.per_id.get(&id).cloned().or(self.default)
+    }
+}
+
+pub fn scale_pose(pose: &WorldPose, factor: f64) -> WorldPose {
+    WorldPose(IsometryMatrix3::from_parts(
+        Translation::from(pose.0.translation.vector * factor),
+        pose.0.rotation,
+    ))
+}
+
+/// Pose of a topotag, in units of node spacing or, given `edge_length`, in the same units as the
+/// edge length. The tag frame follows the LFTag convention, see `DecodedLFTag`.
+pub fn localize_scaled<C: Camera>(model: &C, tag: &DecodedTopotag, edge_length: Option<f64>) -> WorldPose {
+    let pose = localize(model, tag);
+    match edge_length {
+        Some(edge_length) => scale_pose(&pose, edge_length / tag.class.get_pix_size() as f64),
+        None => pose,
+    }
+}
+
+pub fn localize<C: Camera>(model: &C, tag: &DecodedTopotag) -> WorldPose {
     let normalized_image_coordinates: Vec<NormalizedKeyPoint> = tag
         .node_pos
         .iter()
@@ -99,7 +146,7 @@ pub fn localize(model: &CameraIntrinsics, tag: &DecodedTopotag) -> WorldPose {
         .unwrap()
 }
 
-pub fn draw_coords(input: &mut ImageBuffer<Rgba<u8>, Vec<u8>>, model: &CameraIntrinsics, pose: &WorldPose, scale: f64) {
+pub fn draw_coords<C: Camera>(input: &mut ImageBuffer<Rgba<u8>, Vec<u8>>, model: &C, pose: &WorldPose, scale: f64) {
     let origin = world_to_camera_i32(model, pose, &WorldPoint(Point3::new(2.0, 2.0, 0.0)));
     let x = world_to_camera_i32(model, pose, &WorldPoint(Point3::new(scale - 2.0, 2.0, 0.0)));
     let y = world_to_camera_i32(model, pose, &WorldPoint(Point3::new(2.0, scale - 2.0, 0.0)));
@@ -122,16 +169,258 @@ pub fn draw_coords(input: &mut ImageBuffer<Rgba<u8>, Vec<u8>>, model: &CameraInt
     }, interpolate);
 }
 
-pub fn world_to_camera(model: &CameraIntrinsics, pose: &WorldPose, point: &WorldPoint) -> (f32, f32) {
+pub fn world_to_camera<C: Camera>(model: &C, pose: &WorldPose, point: &WorldPoint) -> (f32, f32) {
     let camera_point = pose.transform(*point);
     let key_point = model.uncalibrate(camera_point.into());
 
     (key_point.0.coords[0] as f32, key_point.0.coords[1] as f32)
 }
 
-pub fn world_to_camera_i32(model: &CameraIntrinsics, pose: &WorldPose, point: &WorldPoint) -> (i32, i32) {
+pub fn world_to_camera_i32<C: Camera>(model: &C, pose: &WorldPose, point: &WorldPoint) -> (i32, i32) {
     let camera_point = pose.transform(*point);
     let key_point = model.uncalibrate(camera_point.into());
 
     (key_point.0.coords[0].round() as i32, key_point.0.coords[1].round() as i32)
-}
\ No newline at end of file
+}
+pub fn project<C: Camera>(model: &C, pose: &IsometryMatrix3<f64>, point: &Point3<f64>) -> Point2<f64> {
+    let camera_point = pose * point;
+    let key_point = model.uncalibrate(NormalizedKeyPoint(Point2::new(camera_point.x / camera_point.z, camera_point.y / camera_point.z)));
+
+    key_point.0
+}
+
+struct PoseProblem<'a, C: Camera> {
+    model: &'a C,
+    correspondences: &'a [(Point3<f64>, Point2<f64>)],
+}
+
+impl<'a, C: Camera> LeastSquares for PoseProblem<'a, C> {
+    fn blocks(&self) -> usize {
+        self.correspondences.len()
+    }
+
+    fn block_params(&self, _block: usize) -> Vec<usize> {
+        (0..6).collect()
+    }
+
+    fn residuals(&self, block: usize, params: &[f64]) -> Vec<f64> {
+        let (world, image) = &self.correspondences[block];
+        let p = project(self.model, &pose_from_params(params), world);
+        vec![p.x - image.x, p.y - image.y]
+    }
+}
+
+pub fn reprojection_rms<C: Camera>(model: &C, pose: &IsometryMatrix3<f64>, correspondences: &[(Point3<f64>, Point2<f64>)]) -> f64 {
+    let sq: f64 = correspondences.iter()
+        .map(|(w, i)| nalgebra::distance_squared(&project(model, pose, w), i))
+        .sum();
+    (sq / correspondences.len().max(1) as f64).sqrt()
+}
+
+/// Minimizes the reprojection error of world to pixel correspondences, returns the refined pose
+/// and its rms reprojection error in pixels.
+pub fn refine_pose<C: Camera>(model: &C, correspondences: &[(Point3<f64>, Point2<f64>)], initial: &IsometryMatrix3<f64>) -> (IsometryMatrix3<f64>, f64) {
+    let mut params = pose_to_params(initial).to_vec();
+    levenberg_marquardt(&PoseProblem { model, correspondences }, &mut params, 50);
+
+    let pose = pose_from_params(&params);
+    let rms = reprojection_rms(model, &pose, correspondences);
+    (pose, rms)
+}
+
+/// First order covariance of a pose (rotation vector then translation, see `pose_to_params`) from
+/// its correspondences, with pixel noise of standard deviation `sigma`.
+pub fn pose_covariance<C: Camera>(model: &C, correspondences: &[(Point3<f64>, Point2<f64>)], pose: &IsometryMatrix3<f64>, sigma: f64) -> Option<Matrix6<f64>> {
+    let cov = covariance(&PoseProblem { model, correspondences }, &pose_to_params(pose), sigma)?;
+    Some(Matrix6::from_iterator(cov.iter().cloned()))
+}
+
+/// Node correspondences of one detected tag, with the world points in a frame shared by all tags,
+/// and the pose of that frame in the camera implied by this tag alone.
+#[derive(Debug, Clone)]
+pub struct TagCorrespondences {
+    pub id: usize,
+    pub points: Vec<(Point3<f64>, Point2<f64>)>,
+    pub initial: IsometryMatrix3<f64>,
+}
+
+impl TagCorrespondences {
+    /// Correspondences of a detection in a frame holding the tag at `tag_pose` with the given printed
+    /// size.
+    pub fn from_detection(tag: &DecodedLFTag, size: f64, tag_pose: &IsometryMatrix3<f64>) -> TagCorrespondences {
+        TagCorrespondences {
+            id: tag.data,
+            points: node_correspondences(tag, size).into_iter().map(|(world, image)| (tag_pose * world, image)).collect(),
+            initial: scale_pose(&tag.final_pose, size / tag.tag_size).0 * tag_pose.inverse(),
+        }
+    }
+}
+
+/// Every detected node of a tag in the tag frame scaled to a printed edge length of `size` (pass
+/// `tag.tag_size` to keep the detection's units), paired with its pixel position.
+pub fn node_correspondences(tag: &DecodedLFTag, size: f64) -> Vec<(Point3<f64>, Point2<f64>)> {
+    let factor = size / tag.tag_size;
+    tag.node_world_pos.iter().zip(&tag.node_pos).map(|(world, image)| {
+        (
+            Point3::new(world.0 as f64 * factor, world.1 as f64 * factor, 0.0),
+            Point2::new(image.0 as f64, image.1 as f64),
+        )
+    }).collect()
+}
+
+#[derive(Debug, Clone)]
+pub struct PooledPose {
+    pub pose: IsometryMatrix3<f64>,
+    pub inliers: Vec<usize>,
+    pub outliers: Vec<usize>,
+    pub rms: f64,
+}
+
+/// Single pose from the nodes of several tags. Every tag's own estimate is tried as a hypothesis,
+/// tags reprojecting within `threshold` pixels of the best hypothesis are pooled into one refined
+/// pose and the rest are rejected as outliers.
+pub fn pooled_pose<C: Camera>(model: &C, tags: &[TagCorrespondences], threshold: f64) -> Option<PooledPose> {
+    let inliers_of = |pose: &IsometryMatrix3<f64>| -> (Vec<usize>, f64) {
+        let mut inliers = Vec::new();
+        let mut error = 0.0;
+        for (idx, tag) in tags.iter().enumerate() {
+            let rms = reprojection_rms(model, pose, &tag.points);
+            if rms < threshold {
+                inliers.push(idx);
+                error += rms;
+            }
+        }
+        (inliers, error)
+    };
+
+    let mut best: Option<(Vec<usize>, f64, IsometryMatrix3<f64>)> = None;
+    for tag in tags {
+        let (inliers, error) = inliers_of(&tag.initial);
+        let better = match &best {
+            Some((best_inliers, best_error, _)) => {
+                inliers.len() > best_inliers.len() || (inliers.len() == best_inliers.len() && error < *best_error)
+            }
+            None => !inliers.is_empty(),
+        };
+        if better {
+            best = Some((inliers, error, tag.initial));
+        }
+    }
+
+    let (mut inliers, _, mut pose) = best?;
+    let mut rms = 0.0;
+
+    for _ in 0..3 {
+        let points: Vec<_> = inliers.iter().flat_map(|&i| tags[i].points.iter().cloned()).collect();
+        let refined = refine_pose(model, &points, &pose);
+        pose = refined.0;
+        rms = refined.1;
+
+        let (new_inliers, _) = inliers_of(&pose);
+        if new_inliers == inliers || new_inliers.is_empty() {
+            break;
+        }
+        inliers = new_inliers;
+    }
+
+    Some(PooledPose {
+        pose,
+        outliers: (0..tags.len()).filter(|i| !inliers.contains(i)).map(|i| tags[i].id).collect(),
+        inliers: inliers.iter().map(|&i| tags[i].id).collect(),
+        rms,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera::BrownConrady;
+    use crate::decode::LFTagClass;
+    use cv_pinhole::CameraIntrinsics;
+    use nalgebra::Vector2;
+
+    #[test]
+    fn pooled_pose_beats_single_tags() {
+        let camera = BrownConrady::new(
+            CameraIntrinsics {
+                focals: Vector2::new(800.0, 800.0),
+                principal_point: Point2::new(320.0, 240.0),
+                skew: 0.0,
+            },
+            [0.0; 5],
+        );
+        // tags 5cm wide in a common frame
+        let layout: Vec<(usize, IsometryMatrix3<f64>)> = vec![
+            (1, IsometryMatrix3::identity()),
+            (2, IsometryMatrix3::from_parts(Translation::from(Vector3::new(0.1, 0.0, 0.0)), Rotation3::from_euler_angles(0.2, 0.0, 0.0))),
+            (3, IsometryMatrix3::from_parts(Translation::from(Vector3::new(0.0, 0.1, 0.0)), Rotation3::from_euler_angles(-0.2, 0.0, 0.0))),
+            (4, IsometryMatrix3::from_parts(Translation::from(Vector3::new(0.1, 0.1, 0.0)), Rotation3::identity())),
+            (5, IsometryMatrix3::from_parts(Translation::from(Vector3::new(0.2, 0.0, 0.0)), Rotation3::identity())),
+        ];
+        let frame = IsometryMatrix3::from_parts(Translation::from(Vector3::new(-0.1, -0.05, 0.5)), Rotation3::from_euler_angles(0.3, -0.2, 0.1));
+
+        let mut rng = SmallRng::from_seed([3; 16]);
+        let tags: Vec<TagCorrespondences> = layout.iter().map(|(id, pose)| {
+            // tag 5 sits 5cm away from its place in the layout
+            let shift = if *id == 5 { IsometryMatrix3::translation(0.0, 0.05, 0.0) } else { IsometryMatrix3::identity() };
+            let tag = DecodedLFTag::synthetic_sized(&camera, LFTagClass::LFTag3x3, *id, 0.05, &(frame * shift * pose), 0.5, &mut rng);
+            TagCorrespondences::from_detection(&tag, 0.05, pose)
+        }).collect();
+
+        let pooled = pooled_pose(&camera, &tags, 3.0).unwrap();
+        let mut inliers = pooled.inliers.clone();
+        inliers.sort();
+        assert_eq!(inliers, vec![1, 2, 3, 4]);
+        assert_eq!(pooled.outliers, vec![5]);
+        assert!(pooled.rms < 1.0);
+
+        // frame pose from each inlier tag on its own
+        let single_errors: Vec<f64> = tags[..4].iter().map(|tag| {
+            let (single, _) = refine_pose(&camera, &tag.points, &tag.initial);
+            (single.translation.vector - frame.translation.vector).norm()
+        }).collect();
+        let single_error = single_errors.iter().sum::<f64>() / single_errors.len() as f64;
+        let pooled_error = (pooled.pose.translation.vector - frame.translation.vector).norm();
+        assert!(pooled_error < single_error);
+        assert!(pooled_error < 5e-3);
+
+        assert!(pooled_pose(&camera, &[], 3.0).is_none());
+    }
+
+    #[test]
+    fn tag_size_per_id_overrides_default() {
+        let sizes = TagSize::new(0.1).with_id(7, 0.05);
+        assert_eq!(sizes.get(7), Some(0.05));
+        assert_eq!(sizes.get(3), Some(0.1));
+
+        let only_seven = TagSize::default().with_id(7, 0.05);
+        assert_eq!(only_seven.get(7), Some(0.05));
+        assert_eq!(only_seven.get(3), None);
+    }
+
+    #[test]
+    fn tag_size_scales_grid_pose() {
+        let camera = BrownConrady::new(
+            CameraIntrinsics {
+                focals: Vector2::new(700.0, 700.0),
+                principal_point: Point2::new(320.0, 240.0),
+                skew: 0.0,
+            },
+            [-0.1, 0.02, 0.0, 0.0, 0.0],
+        );
+        // a 3x3 tag is 24 grid units wide, so a 12cm print has 5mm units
+        let grid = IsometryMatrix3::from_parts(Translation::from(Vector3::new(20.0, -10.0, 160.0)), Rotation3::from_euler_angles(0.3, -0.2, 0.1));
+        let mut tag = DecodedLFTag::synthetic(&camera, LFTagClass::LFTag3x3, 5, WorldPose(grid));
+        assert_eq!(tag.class.get_pix_size(), 24.0);
+
+        tag.set_tag_size(0.12);
+        let metric = tag.final_pose.0;
+        assert!((metric.translation.vector - Vector3::new(0.1, -0.05, 0.8)).norm() < 1e-12);
+        assert!((metric.rotation.inverse() * grid.rotation).angle() < 1e-12);
+
+        // the scaled pose still projects the scaled nodes onto the detected ones
+        for (world, image) in node_correspondences(&tag, tag.tag_size) {
+            assert!(nalgebra::distance(&project(&camera, &metric, &world), &image) < 1e-3);
+        }
+    }
+}
@@ -0,0 +1,297 @@
+use nalgebra::{IsometryMatrix3, Point2, Point3};
+use std::collections::HashMap;
+
+use crate::bundle::BundleTag;
+use crate::camera::Camera;
+use crate::decode::DecodedLFTag;
+use crate::localize::{node_correspondences, project, reprojection_rms, scale_pose, TagSize};
+use crate::marker_map::MarkerMap;
+use crate::optimize::{levenberg_marquardt, pose_from_params, pose_to_params, LeastSquares};
+use crate::pose::PoseRecord;
+
+#[derive(Debug, Clone)]
+pub struct Mapping {
+    pub map: MarkerMap,
+    // camera frame in the world frame for each input frame, None if it could not be localized
+    pub trajectory: Vec<Option<IsometryMatrix3<f64>>>,
+    pub rms_error: f64,
+}
+
+struct Observation {
+    frame: usize,
+    tag: usize,
+    // tag frame (metric) to pixel
+    points: Vec<(Point3<f64>, Point2<f64>)>,
+}
+
+struct MappingProblem<'a, C: Camera> {
+    camera: &'a C,
+    observations: &'a [Observation],
+    // parameter offset of each tag pose, None for the fixed reference tag
+    tag_offsets: &'a HashMap<usize, Option<usize>>,
+    reference: IsometryMatrix3<f64>,
+    frame_offsets: &'a HashMap<usize, usize>,
+}
+
+impl<'a, C: Camera> MappingProblem<'a, C> {
+    fn tag_pose(&self, tag: usize, params: &[f64]) -> IsometryMatrix3<f64> {
+        match self.tag_offsets[&tag] {
+            Some(offset) => pose_from_params(&params[offset..offset + 6]),
+            None => self.reference,
+        }
+    }
+}
+
+impl<'a, C: Camera> LeastSquares for MappingProblem<'a, C> {
+    fn blocks(&self) -> usize {
+        self.observations.len()
+    }
+
+    fn block_params(&self, block: usize) -> Vec<usize> {
+        let obs = &self.observations[block];
+        let frame = self.frame_offsets[&obs.frame];
+        let mut out: Vec<usize> = (frame..frame + 6).collect();
+        if let Some(offset) = self.tag_offsets[&obs.tag] {
+            out.extend(offset..offset + 6);
+        }
+        out
+    }
+
+    fn residuals(&self, block: usize, params: &[f64]) -> Vec<f64> {
+        let obs = &self.observations[block];
+        let frame = self.frame_offsets[&obs.frame];
+        // world in camera composed with tag in world
+        let pose = pose_from_params(&params[frame..frame + 6]) * self.tag_pose(obs.tag, params);
+
+        obs.points.iter().flat_map(|(world, image)| {
+            let p = project(self.camera, &pose, world);
+            vec![p.x - image.x, p.y - image.y]
+        }).collect()
+    }
+}
+
+/// Builds a marker map from a sequence of frames. Tag poses are initialized by walking the graph of
+/// co-visible tags outwards from `reference` (the first tag seen if None), which defines the world
+/// frame, then every tag and camera pose is refined by bundle adjustment over all node
+/// observations. `threshold` is the reprojection error in pixels above which a tag is rejected in a
+/// frame.
+pub fn build_map<C: Camera>(camera: &C, frames: &[Vec<DecodedLFTag>], sizes: &TagSize, reference: Option<usize>, threshold: f64) -> Option<Mapping> {
+    // metric tag in camera poses, with the reprojection error as edge cost
+    let mut frame_tags: Vec<Vec<(usize, IsometryMatrix3<f64>, f64)>> = Vec::new();
+    for frame in frames {
+        frame_tags.push(frame.iter().filter_map(|tag| {
+            let size = sizes.get(tag.data)?;
+            let pose = scale_pose(&tag.final_pose, size / tag.tag_size).0;
+            let rms = reprojection_rms(camera, &pose, &node_correspondences(tag, size));
+            Some((tag.data, pose, rms))
+        }).collect());
+    }
+
+    let reference = reference.or_else(|| frame_tags.iter().flatten().next().map(|t| t.0))?;
+    let mut world_tags: HashMap<usize, IsometryMatrix3<f64>> = HashMap::new();
+    world_tags.insert(reference, IsometryMatrix3::identity());
+
+    // grow the spanning tree one tag at a time, always through the cheapest available edge
+    loop {
+        let mut best: Option<(f64, usize, IsometryMatrix3<f64>)> = None;
+        for tags in &frame_tags {
+            for (known, known_pose, known_rms) in tags {
+                let world_known = match world_tags.get(known) {
+                    Some(p) => *p,
+                    None => continue,
+                };
+                for (new, new_pose, new_rms) in tags {
+                    if world_tags.contains_key(new) {
+                        continue;
+                    }
+                    let cost = known_rms + new_rms;
+                    if best.as_ref().map_or(true, |b| cost < b.0) {
+                        best = Some((cost, *new, world_known * known_pose.inverse() * new_pose));
+                    }
+                }
+            }
+        }
+
+        match best {
+            Some((_, id, pose)) => {
+                world_tags.insert(id, pose);
+            }
+            None => break,
+        }
+    }
+
+    let mut map = MarkerMap {
+        tags: world_tags.iter().map(|(&id, pose)| BundleTag {
+            id,
+            size: sizes.get(id).unwrap(),
+            pose: PoseRecord::from(*pose),
+        }).collect(),
+    };
+    map.tags.sort_by_key(|t| t.id);
+
+    // initial camera poses and the observations that agree with them
+    let mut observations = Vec::new();
+    let mut frame_offsets = HashMap::new();
+    let mut tag_offsets = HashMap::new();
+    let mut params = Vec::new();
+
+    for tag in &map.tags {
+        if tag.id == reference {
+            tag_offsets.insert(tag.id, None);
+        } else {
+            tag_offsets.insert(tag.id, Some(params.len()));
+            params.extend_from_slice(&pose_to_params(&tag.pose.isometry()));
+        }
+    }
+
+    for (idx, frame) in frames.iter().enumerate() {
+        let localization = match map.localize(camera, frame, threshold) {
+            Some(l) => l,
+            None => continue,
+        };
+        frame_offsets.insert(idx, params.len());
+        params.extend_from_slice(&pose_to_params(&localization.world_pose.0));
+
+        for tag in frame {
+            if localization.tags.contains(&tag.data) {
+                observations.push(Observation {
+                    frame: idx,
+                    tag: tag.data,
+                    points: node_correspondences(tag, sizes.get(tag.data).unwrap()),
+                });
+            }
+        }
+    }
+
+    if observations.is_empty() {
+        return None;
+    }
+
+    let problem = MappingProblem {
+        camera,
+        observations: &observations,
+        tag_offsets: &tag_offsets,
+        reference: IsometryMatrix3::identity(),
+        frame_offsets: &frame_offsets,
+    };
+    let cost = levenberg_marquardt(&problem, &mut params, 100);
+    let point_count: usize = observations.iter().map(|o| o.points.len()).sum();
+
+    for tag in &mut map.tags {
+        tag.pose = PoseRecord::from(problem.tag_pose(tag.id, &params));
+    }
+
+    let trajectory = (0..frames.len()).map(|idx| {
+        frame_offsets.get(&idx).map(|&offset| pose_from_params(&params[offset..offset + 6]).inverse())
+    }).collect();
+
+    Some(Mapping {
+        map,
+        trajectory,
+        rms_error: (cost / point_count as f64).sqrt(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera::BrownConrady;
+    use crate::decode::LFTagClass;
+    use cv_pinhole::CameraIntrinsics;
+    use nalgebra::{Rotation3, Translation3, Vector2};
+    use rand::rngs::SmallRng;
+    use rand::SeedableRng;
+
+    fn camera() -> BrownConrady {
+        BrownConrady::new(
+            CameraIntrinsics {
+                focals: Vector2::new(700.0, 700.0),
+                principal_point: Point2::new(320.0, 240.0),
+                skew: 0.0,
+            },
+            [0.0; 5],
+        )
+    }
+
+    fn pose(x: f64, y: f64, z: f64, roll: f64, pitch: f64, yaw: f64) -> IsometryMatrix3<f64> {
+        IsometryMatrix3::from_parts(Translation3::new(x, y, z), Rotation3::from_euler_angles(roll, pitch, yaw))
+    }
+
+    // tags 1 to 4 in the frame of tag 1, tags 9 and 10 hang apart and are only ever seen together
+    fn layout() -> Vec<(usize, IsometryMatrix3<f64>)> {
+        vec![
+            (1, IsometryMatrix3::identity()),
+            (2, pose(0.3, 0.0, 0.0, 0.0, 0.0, 0.1)),
+            (3, pose(0.0, 0.3, 0.02, 0.2, 0.0, 0.0)),
+            (4, pose(0.3, 0.3, 0.05, 0.0, -0.2, 0.0)),
+            (9, pose(2.0, 0.0, 0.0, 0.0, 0.0, 0.0)),
+            (10, pose(2.3, 0.0, 0.0, 0.0, 0.0, 0.0)),
+        ]
+    }
+
+    // camera frames in the frame of tag 1 and the tags each one sees
+    fn views() -> Vec<(IsometryMatrix3<f64>, Vec<usize>)> {
+        vec![
+            (pose(0.1, 0.0, -0.8, 0.0, 0.1, 0.0), vec![1, 2]),
+            (pose(0.25, 0.25, -0.9, 0.05, -0.1, 0.0), vec![2, 3, 4]),
+            (pose(0.0, 0.2, -0.7, -0.1, 0.05, 0.02), vec![1, 3]),
+            (pose(0.2, 0.2, -1.1, 0.0, 0.0, -0.05), vec![1, 2, 3, 4]),
+            (pose(2.2, 0.0, -0.8, 0.0, 0.0, 0.0), vec![9, 10]),
+        ]
+    }
+
+    fn frames(camera: &BrownConrady) -> Vec<Vec<DecodedLFTag>> {
+        let layout = layout();
+        let mut rng = SmallRng::from_seed([11; 16]);
+        views().iter().map(|(camera_pose, ids)| {
+            ids.iter().map(|id| {
+                let tag_pose = layout.iter().find(|t| t.0 == *id).unwrap().1;
+                DecodedLFTag::synthetic_sized(camera, LFTagClass::LFTag3x3, *id, 0.1, &(camera_pose.inverse() * tag_pose), 0.3, &mut rng)
+            }).collect()
+        }).collect()
+    }
+
+    fn assert_pose_near(found: &IsometryMatrix3<f64>, expected: &IsometryMatrix3<f64>) {
+        assert!((found.translation.vector - expected.translation.vector).norm() < 5e-3);
+        assert!((found.rotation.inverse() * expected.rotation).angle() < 1e-2);
+    }
+
+    #[test]
+    fn recovers_known_layout() {
+        let camera = camera();
+        let mapping = build_map(&camera, &frames(&camera), &TagSize::new(0.1), None, 3.0).unwrap();
+
+        // 9 and 10 are not connected to the reference tag 1
+        let ids: Vec<usize> = mapping.map.tags.iter().map(|t| t.id).collect();
+        assert_eq!(ids, vec![1, 2, 3, 4]);
+        for tag in &mapping.map.tags {
+            assert_eq!(tag.size, 0.1);
+            let expected = layout().iter().find(|t| t.0 == tag.id).unwrap().1;
+            assert_pose_near(&tag.pose.isometry(), &expected);
+        }
+        assert_eq!(mapping.map.tags[0].pose.isometry(), IsometryMatrix3::identity());
+
+        let views = views();
+        assert_eq!(mapping.trajectory.len(), views.len());
+        for (found, (expected, _)) in mapping.trajectory[..4].iter().zip(&views) {
+            assert_pose_near(found.as_ref().unwrap(), expected);
+        }
+        assert!(mapping.trajectory[4].is_none());
+        assert!(mapping.rms_error < 0.5);
+    }
+
+    #[test]
+    fn disconnected_reference() {
+        let camera = camera();
+        let mapping = build_map(&camera, &frames(&camera), &TagSize::new(0.1), Some(9), 3.0).unwrap();
+
+        let ids: Vec<usize> = mapping.map.tags.iter().map(|t| t.id).collect();
+        assert_eq!(ids, vec![9, 10]);
+        assert_pose_near(&mapping.map.tags[1].pose.isometry(), &pose(0.3, 0.0, 0.0, 0.0, 0.0, 0.0));
+        assert!(mapping.trajectory[..4].iter().all(Option::is_none));
+        assert!(mapping.trajectory[4].is_some());
+
+        // a reference never seen gives no map
+        assert!(build_map(&camera, &frames(&camera), &TagSize::new(0.1), Some(5), 3.0).is_none());
+    }
+}
@@ -0,0 +1,133 @@
+use cv_core::WorldPose;
+use nalgebra::{IsometryMatrix3, Point2, Point3};
+#[cfg(feature = "serde-serialize")]
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "serde-serialize")]
+use std::io;
+#[cfg(feature = "serde-serialize")]
+use std::path::Path;
+
+use crate::bundle::{member_correspondences, pooled_member_pose, BundleTag};
+use crate::camera::Camera;
+use crate::decode::DecodedLFTag;
+#[cfg(feature = "serde-serialize")]
+use crate::serialize::{read_structured, write_structured};
+
+/// Tags mounted at surveyed poses in the world, loaded from JSON, YAML or TOML. Each entry's pose
+/// is the tag frame in the world frame.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde-serialize", derive(Serialize, Deserialize))]
+pub struct MarkerMap {
+    pub tags: Vec<BundleTag>,
+}
+
+#[derive(Debug, Clone)]
+pub struct CameraLocalization {
+    // camera frame in the world frame
+    pub camera_pose: IsometryMatrix3<f64>,
+    // world frame in the camera frame
+    pub world_pose: WorldPose,
+    pub tags: Vec<usize>,
+    pub rejected_tags: Vec<usize>,
+    pub rms_error: f64,
+}
+
+impl MarkerMap {
+    #[cfg(feature = "serde-serialize")]
+    pub fn load(path: &Path) -> io::Result<MarkerMap> {
+        read_structured(path)
+    }
+
+    #[cfg(feature = "serde-serialize")]
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        write_structured(path, self)
+    }
+
+    pub fn get(&self, id: usize) -> Option<&BundleTag> {
+        self.tags.iter().find(|t| t.id == id)
+    }
+
+    /// Pairs every node of the detected known tags with its position in the world.
+    pub fn correspondences(&self, detections: &[DecodedLFTag]) -> Vec<(Point3<f64>, Point2<f64>)> {
+        member_correspondences(&self.tags, detections).into_iter().flat_map(|tag| tag.points).collect()
+    }
+
+    /// Camera pose in the world from every detection of a known tag, pooled into one robust PnP.
+    /// Tags reprojecting worse than `threshold` pixels are rejected, e.g. moved or misread tags.
+    pub fn localize<C: Camera>(&self, camera: &C, detections: &[DecodedLFTag], threshold: f64) -> Option<CameraLocalization> {
+        let pooled = pooled_member_pose(camera, &self.tags, detections, threshold)?;
+
+        Some(CameraLocalization {
+            camera_pose: pooled.pose.inverse(),
+            world_pose: WorldPose(pooled.pose),
+            tags: pooled.inliers,
+            rejected_tags: pooled.outliers,
+            rms_error: pooled.rms,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera::BrownConrady;
+    use crate::decode::LFTagClass;
+    use crate::pose::PoseRecord;
+    use cv_pinhole::CameraIntrinsics;
+    use nalgebra::{Rotation3, Translation3, Vector2};
+    use rand::rngs::SmallRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn localizes_camera_in_world() {
+        let camera = BrownConrady::new(
+            CameraIntrinsics {
+                focals: Vector2::new(600.0, 600.0),
+                principal_point: Point2::new(320.0, 240.0),
+                skew: 0.0,
+            },
+            [0.0; 5],
+        );
+        // tags on a wall at z = 2 and a larger one on the floor at y = 0.6, y pointing down
+        let wall = |id: usize, x: f64, y: f64| BundleTag {
+            id,
+            size: 0.1,
+            pose: PoseRecord::from(IsometryMatrix3::from_parts(Translation3::new(x, y, 2.0), Rotation3::identity())),
+        };
+        let floor = BundleTag {
+            id: 7,
+            size: 0.15,
+            pose: PoseRecord::from(IsometryMatrix3::from_parts(Translation3::new(-0.2, 0.6, 1.8), Rotation3::from_euler_angles(-std::f64::consts::FRAC_PI_2, 0.0, 0.0))),
+        };
+        let map = MarkerMap { tags: vec![wall(1, -0.5, -0.3), wall(2, 0.4, -0.3), wall(3, -0.5, 0.4), wall(4, 0.4, 0.4), floor] };
+
+        // camera frame in the world frame
+        let camera_pose = IsometryMatrix3::from_parts(Translation3::new(0.1, -0.1, -0.2), Rotation3::from_euler_angles(-0.15, 0.1, 0.05));
+        let world_pose = camera_pose.inverse();
+
+        let mut rng = SmallRng::from_seed([5; 16]);
+        let mut detections: Vec<DecodedLFTag> = map.tags.iter().map(|t| {
+            // tag 2 was knocked 10cm down the wall since the map was surveyed
+            let moved = if t.id == 2 { IsometryMatrix3::translation(0.0, 0.1, 0.0) } else { IsometryMatrix3::identity() };
+            DecodedLFTag::synthetic_sized(&camera, LFTagClass::LFTag3x3, t.id, t.size, &(world_pose * moved * t.pose.isometry()), 0.3, &mut rng)
+        }).collect();
+        // a tag that is not in the map is ignored
+        let stray = IsometryMatrix3::from_parts(Translation3::new(0.0, 0.0, 1.5), Rotation3::identity());
+        detections.push(DecodedLFTag::synthetic_sized(&camera, LFTagClass::LFTag3x3, 9, 0.1, &stray, 0.3, &mut rng));
+
+        assert_eq!(map.correspondences(&detections).len(), map.tags.len() * LFTagClass::LFTag3x3.total_node_count());
+
+        let localization = map.localize(&camera, &detections, 3.0).unwrap();
+        let mut tags = localization.tags.clone();
+        tags.sort();
+        assert_eq!(tags, vec![1, 3, 4, 7]);
+        assert_eq!(localization.rejected_tags, vec![2]);
+        assert!(localization.rms_error < 0.5);
+        assert!((localization.camera_pose.translation.vector - camera_pose.translation.vector).norm() < 1e-2);
+        assert!((localization.camera_pose.rotation.inverse() * camera_pose.rotation).angle() < 1e-2);
+        let identity = localization.camera_pose * localization.world_pose.0;
+        assert!(identity.translation.vector.norm() < 1e-9 && identity.rotation.angle() < 1e-9);
+
+        assert!(map.localize(&camera, &detections[5..], 3.0).is_none());
+    }
+}
@@ -0,0 +1,167 @@
+use nalgebra::{DMatrix, DVector, IsometryMatrix3, Rotation3, Translation3, Vector3};
+
+/// A sparse nonlinear least squares problem made of residual blocks, each depending on a small
+/// subset of the parameters.
+pub trait LeastSquares {
+    fn blocks(&self) -> usize;
+
+    /// Indices of the free parameters the block depends on, parameters not listed by any block
+    /// are held constant.
+    fn block_params(&self, block: usize) -> Vec<usize>;
+
+    fn residuals(&self, block: usize, params: &[f64]) -> Vec<f64>;
+}
+
+pub fn total_cost<P: LeastSquares>(problem: &P, params: &[f64]) -> f64 {
+    (0..problem.blocks())
+        .map(|b| problem.residuals(b, params).iter().map(|r| r * r).sum::<f64>())
+        .sum()
+}
+
+fn normal_equations<P: LeastSquares>(problem: &P, params: &[f64]) -> (DMatrix<f64>, DVector<f64>) {
+    let n = params.len();
+    let mut jtj = DMatrix::zeros(n, n);
+    let mut jtr = DVector::zeros(n);
+    let mut perturbed = params.to_vec();
+
+    for block in 0..problem.blocks() {
+        let indices = problem.block_params(block);
+        let residuals = problem.residuals(block, params);
+
+        // forward differences, only over the parameters this block touches
+        let jacobian: Vec<Vec<f64>> = indices.iter().map(|&idx| {
+            let step = 1e-7 * params[idx].abs().max(1.0);
+            perturbed[idx] = params[idx] + step;
+            let shifted = problem.residuals(block, &perturbed);
+            perturbed[idx] = params[idx];
+
+            shifted.iter().zip(&residuals).map(|(s, r)| (s - r) / step).collect()
+        }).collect();
+
+        for (a, &idx_a) in indices.iter().enumerate() {
+            jtr[idx_a] += jacobian[a].iter().zip(&residuals).map(|(j, r)| j * r).sum::<f64>();
+            for (b, &idx_b) in indices.iter().enumerate() {
+                jtj[(idx_a, idx_b)] += jacobian[a].iter().zip(&jacobian[b]).map(|(x, y)| x * y).sum::<f64>();
+            }
+        }
+    }
+
+    (jtj, jtr)
+}
+
+/// Minimizes the sum of squared residuals in place, returns the final cost.
+pub fn levenberg_marquardt<P: LeastSquares>(problem: &P, params: &mut Vec<f64>, max_iterations: usize) -> f64 {
+    let mut cost = total_cost(problem, params);
+    let mut lambda = 1e-3;
+
+    for _ in 0..max_iterations {
+        let (jtj, jtr) = normal_equations(problem, params);
+        let mut improved = false;
+
+        while lambda < 1e10 {
+            let mut a = jtj.clone();
+            for i in 0..params.len() {
+                a[(i, i)] += lambda * jtj[(i, i)].max(1e-9);
+            }
+
+            let delta = match a.cholesky() {
+                Some(c) => c.solve(&(-jtr.clone())),
+                None => {
+                    lambda *= 10.0;
+                    continue;
+                }
+            };
+
+            let candidate: Vec<f64> = params.iter().zip(delta.iter()).map(|(p, d)| p + d).collect();
+            let new_cost = total_cost(problem, &candidate);
+
+            if new_cost < cost {
+                let decrease = (cost - new_cost) / cost.max(1e-300);
+                *params = candidate;
+                cost = new_cost;
+                lambda = (lambda / 10.0).max(1e-12);
+                improved = decrease > 1e-10;
+                break;
+            }
+            lambda *= 10.0;
+        }
+
+        if !improved {
+            break;
+        }
+    }
+
+    cost
+}
+
+/// Covariance of the parameters at a minimum, for residuals with standard deviation `sigma`.
+pub fn covariance<P: LeastSquares>(problem: &P, params: &[f64], sigma: f64) -> Option<DMatrix<f64>> {
+    let (jtj, _) = normal_equations(problem, params);
+    jtj.try_inverse().map(|inv| inv * sigma.powi(2))
+}
+
+/// Rotation vector followed by translation.
+pub fn pose_to_params(pose: &IsometryMatrix3<f64>) -> [f64; 6] {
+    let r = pose.rotation.scaled_axis();
+    let t = pose.translation.vector;
+    [r[0], r[1], r[2], t[0], t[1], t[2]]
+}
+
+pub fn pose_from_params(params: &[f64]) -> IsometryMatrix3<f64> {
+    IsometryMatrix3::from_parts(
+        Translation3::new(params[3], params[4], params[5]),
+        Rotation3::new(Vector3::new(params[0], params[1], params[2])),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Exponential {
+        samples: Vec<(f64, f64)>,
+    }
+
+    impl LeastSquares for Exponential {
+        fn blocks(&self) -> usize {
+            self.samples.len()
+        }
+
+        fn block_params(&self, _block: usize) -> Vec<usize> {
+            vec![0, 1]
+        }
+
+        fn residuals(&self, block: usize, params: &[f64]) -> Vec<f64> {
+            let (x, y) = self.samples[block];
+            vec![params[0] * (params[1] * x).exp() - y]
+        }
+    }
+
+    #[test]
+    fn fits_exponential() {
+        let samples = (0..20).map(|i| {
+            let x = i as f64 * 0.1;
+            (x, 2.0 * (0.5 * x).exp())
+        }).collect();
+
+        let mut params = vec![1.0, 0.0];
+        let cost = levenberg_marquardt(&Exponential { samples }, &mut params, 100);
+
+        assert!(cost < 1e-12);
+        assert!((params[0] - 2.0).abs() < 1e-5);
+        assert!((params[1] - 0.5).abs() < 1e-5);
+    }
+
+    #[quickcheck]
+    fn qc_pose_params_roundtrip(r: (f64, f64, f64), t: (f64, f64, f64)) -> bool {
+        // keep the rotation angle below pi so the axis angle representation is unique
+        let scale = 1.0 / (1.0 + r.0.abs() + r.1.abs() + r.2.abs());
+        let params = [r.0 * scale, r.1 * scale, r.2 * scale, t.0, t.1, t.2];
+        if params.iter().any(|p| !p.is_finite()) {
+            return true;
+        }
+
+        let roundtrip = pose_to_params(&pose_from_params(&params));
+        params.iter().zip(roundtrip.iter()).all(|(a, b)| (a - b).abs() < 1e-9 * a.abs().max(1.0))
+    }
+}
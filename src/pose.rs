@@ -0,0 +1,312 @@
+use nalgebra::{IsometryMatrix3, Matrix3, Rotation3, Translation3, UnitQuaternion, Vector3};
+#[cfg(feature = "serde-serialize")]
+use serde::{Deserialize, Serialize};
+
+/// Rigid transform as stored in bundle, map and rig files, with the rotation as a rotation vector
+/// (axis scaled by the angle in radians, as used by OpenCV).
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde-serialize", derive(Serialize, Deserialize))]
+pub struct PoseRecord {
+    pub translation: [f64; 3],
+    pub rotation: [f64; 3],
+}
+
+impl PoseRecord {
+    pub fn isometry(&self) -> IsometryMatrix3<f64> {
+        IsometryMatrix3::from_parts(
+            Translation3::new(self.translation[0], self.translation[1], self.translation[2]),
+            Rotation3::new(Vector3::new(self.rotation[0], self.rotation[1], self.rotation[2])),
+        )
+    }
+}
+
+impl From<IsometryMatrix3<f64>> for PoseRecord {
+    fn from(pose: IsometryMatrix3<f64>) -> PoseRecord {
+        let t = pose.translation.vector;
+        let r = pose.rotation.scaled_axis();
+        PoseRecord {
+            translation: [t[0], t[1], t[2]],
+            rotation: [r[0], r[1], r[2]],
+        }
+    }
+}
+
+/// Axis conventions for the camera and tag frames of reported poses. Detection always works in
+/// the OpenCV convention: camera x right, y down, z forward, and tag z into the tag face.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde-serialize", derive(Serialize, Deserialize))]
+pub enum FrameConvention {
+    OpenCv,
+    // REP-103 camera optical frame, same axes as OpenCV
+    RosOptical,
+    // REP-103 body frame: camera x forward, y left, z up, tag z out of the face
+    RosBody,
+    // camera x right, y up, z backward, tag z out of the face
+    OpenGl,
+}
+
+impl FrameConvention {
+    // OpenCV camera frame in this convention's camera frame
+    fn camera_rotation(&self) -> Rotation3<f64> {
+        let m = match self {
+            FrameConvention::OpenCv | FrameConvention::RosOptical => Matrix3::identity(),
+            FrameConvention::RosBody => Matrix3::new(0.0, 0.0, 1.0, -1.0, 0.0, 0.0, 0.0, -1.0, 0.0),
+            FrameConvention::OpenGl => Matrix3::new(1.0, 0.0, 0.0, 0.0, -1.0, 0.0, 0.0, 0.0, -1.0),
+        };
+        Rotation3::from_matrix_unchecked(m)
+    }
+
+    // this convention's tag frame in the OpenCV tag frame
+    fn tag_rotation(&self) -> Rotation3<f64> {
+        match self {
+            FrameConvention::OpenCv | FrameConvention::RosOptical => Rotation3::identity(),
+            FrameConvention::RosBody | FrameConvention::OpenGl => Rotation3::from_matrix_unchecked(
+                Matrix3::new(1.0, 0.0, 0.0, 0.0, -1.0, 0.0, 0.0, 0.0, -1.0),
+            ),
+        }
+    }
+
+    /// Converts a tag in camera pose from the OpenCV convention into this one.
+    pub fn convert(&self, tag_in_camera: &IsometryMatrix3<f64>) -> IsometryMatrix3<f64> {
+        let camera = IsometryMatrix3::from_parts(Translation3::identity(), self.camera_rotation());
+        let tag = IsometryMatrix3::from_parts(Translation3::identity(), self.tag_rotation());
+        camera * tag_in_camera * tag
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde-serialize", derive(Serialize, Deserialize))]
+pub enum PoseForm {
+    TagInCamera,
+    CameraInTag,
+}
+
+/// Tait-Bryan angle order, the rotation is the product of elementary rotations in the stated
+/// order, e.g. `Zyx` is yaw, pitch, roll with R = Rz(a0) Ry(a1) Rx(a2).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde-serialize", derive(Serialize, Deserialize))]
+pub enum EulerOrder {
+    Xyz,
+    Zyx,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde-serialize", derive(Serialize, Deserialize))]
+pub enum RotationRepresentation {
+    Quaternion,
+    RotationVector,
+    Euler(EulerOrder),
+    Matrix,
+}
+
+/// A pose in a chosen representation, angles in radians.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde-serialize", derive(Serialize, Deserialize))]
+pub enum PoseValue {
+    // rotation as x, y, z, w
+    Quaternion { translation: [f64; 3], rotation: [f64; 4] },
+    RotationVector { translation: [f64; 3], rotation: [f64; 3] },
+    Euler { translation: [f64; 3], order: EulerOrder, angles: [f64; 3] },
+    // row major homogeneous transform
+    Matrix([[f64; 4]; 4]),
+}
+
+pub fn euler_angles(rotation: &Rotation3<f64>, order: EulerOrder) -> [f64; 3] {
+    match order {
+        EulerOrder::Zyx => {
+            let (roll, pitch, yaw) = rotation.euler_angles();
+            [yaw, pitch, roll]
+        }
+        EulerOrder::Xyz => {
+            // Rx(a) Ry(b) Rz(c) is the inverse of Rz(-c) Ry(-b) Rx(-a)
+            let (roll, pitch, yaw) = rotation.inverse().euler_angles();
+            [-roll, -pitch, -yaw]
+        }
+    }
+}
+
+/// How poses are reported: frame convention, which frame is expressed in which, and the rotation
+/// representation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde-serialize", derive(Serialize, Deserialize))]
+pub struct PoseConfig {
+    pub convention: FrameConvention,
+    pub form: PoseForm,
+    pub representation: RotationRepresentation,
+}
+
+impl Default for PoseConfig {
+    fn default() -> PoseConfig {
+        PoseConfig {
+            convention: FrameConvention::OpenCv,
+            form: PoseForm::TagInCamera,
+            representation: RotationRepresentation::RotationVector,
+        }
+    }
+}
+
+impl PoseConfig {
+    /// The tag in camera pose in the OpenCV convention (e.g. `final_pose`), converted to the
+    /// configured convention and form.
+    pub fn transform(&self, tag_in_camera: &IsometryMatrix3<f64>) -> IsometryMatrix3<f64> {
+        let pose = self.convention.convert(tag_in_camera);
+        match self.form {
+            PoseForm::TagInCamera => pose,
+            PoseForm::CameraInTag => pose.inverse(),
+        }
+    }
+
+    pub fn apply(&self, tag_in_camera: &IsometryMatrix3<f64>) -> PoseValue {
+        let pose = self.transform(tag_in_camera);
+        let t = pose.translation.vector;
+        let translation = [t[0], t[1], t[2]];
+
+        match self.representation {
+            RotationRepresentation::Quaternion => {
+                let q = UnitQuaternion::from_rotation_matrix(&pose.rotation);
+                PoseValue::Quaternion { translation, rotation: [q.i, q.j, q.k, q.w] }
+            }
+            RotationRepresentation::RotationVector => {
+                PoseValue::RotationVector { translation, rotation: PoseRecord::from(pose).rotation }
+            }
+            RotationRepresentation::Euler(order) => {
+                PoseValue::Euler { translation, order, angles: euler_angles(&pose.rotation, order) }
+            }
+            RotationRepresentation::Matrix => {
+                let m = pose.to_homogeneous();
+                let mut out = [[0.0; 4]; 4];
+                for (row, out_row) in out.iter_mut().enumerate() {
+                    for (col, v) in out_row.iter_mut().enumerate() {
+                        *v = m[(row, col)];
+                    }
+                }
+                PoseValue::Matrix(out)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn euler_orders_reconstruct_rotation() {
+        let rotation = Rotation3::new(Vector3::new(0.3, -0.5, 1.1));
+
+        let [a, b, c] = euler_angles(&rotation, EulerOrder::Zyx);
+        let zyx = Rotation3::from_axis_angle(&Vector3::z_axis(), a)
+            * Rotation3::from_axis_angle(&Vector3::y_axis(), b)
+            * Rotation3::from_axis_angle(&Vector3::x_axis(), c);
+        assert!((zyx.inverse() * rotation).angle() < 1e-9);
+
+        let [a, b, c] = euler_angles(&rotation, EulerOrder::Xyz);
+        let xyz = Rotation3::from_axis_angle(&Vector3::x_axis(), a)
+            * Rotation3::from_axis_angle(&Vector3::y_axis(), b)
+            * Rotation3::from_axis_angle(&Vector3::z_axis(), c);
+        assert!((xyz.inverse() * rotation).angle() < 1e-9);
+    }
+
+    #[test]
+    fn ros_body_keeps_forward_distance() {
+        let tag = IsometryMatrix3::from_parts(Translation3::new(0.1, 0.2, 2.0), Rotation3::identity());
+        let body = FrameConvention::RosBody.convert(&tag);
+        let t = body.translation.vector;
+        assert!((t[0] - 2.0).abs() < 1e-12 && (t[1] + 0.1).abs() < 1e-12 && (t[2] + 0.2).abs() < 1e-12);
+    }
+
+    const CONVENTIONS: [FrameConvention; 4] = [FrameConvention::OpenCv, FrameConvention::RosOptical, FrameConvention::RosBody, FrameConvention::OpenGl];
+    const FORMS: [PoseForm; 2] = [PoseForm::TagInCamera, PoseForm::CameraInTag];
+
+    fn assert_pose(found: &IsometryMatrix3<f64>, translation: [f64; 3], columns: [[f64; 3]; 3]) {
+        assert!((found.translation.vector - Vector3::from(translation)).norm() < 1e-12, "translation {:?}", found.translation);
+        let expected = Matrix3::from_columns(&[Vector3::from(columns[0]), Vector3::from(columns[1]), Vector3::from(columns[2])]);
+        assert!((found.rotation.matrix() - expected).norm() < 1e-12, "rotation {:?}", found.rotation);
+    }
+
+    #[test]
+    fn conventions_place_facing_tag() {
+        // 2m ahead, 10cm right and 20cm below the optical axis, facing the camera
+        let tag = IsometryMatrix3::from_parts(Translation3::new(0.1, 0.2, 2.0), Rotation3::identity());
+        let identity = [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]];
+
+        for &(convention, translation, columns) in &[
+            // x right, y down, z forward and tag z into the face
+            (FrameConvention::OpenCv, [0.1, 0.2, 2.0], identity),
+            (FrameConvention::RosOptical, [0.1, 0.2, 2.0], identity),
+            // x forward, y left, z up, the tag x points right, y up and z back at the camera
+            (FrameConvention::RosBody, [2.0, -0.1, -0.2], [[0.0, -1.0, 0.0], [0.0, 0.0, 1.0], [-1.0, 0.0, 0.0]]),
+            // x right, y up, z backward, so the facing tag has the camera axes
+            (FrameConvention::OpenGl, [0.1, -0.2, -2.0], identity),
+        ] {
+            let config = PoseConfig { convention, form: PoseForm::TagInCamera, representation: RotationRepresentation::Matrix };
+            assert_pose(&config.transform(&tag), translation, columns);
+        }
+
+        // the camera behind the tag face for OpenCV, in front of it where tag z comes out of the face
+        for &(convention, translation) in &[
+            (FrameConvention::OpenCv, [-0.1, -0.2, -2.0]),
+            (FrameConvention::RosOptical, [-0.1, -0.2, -2.0]),
+            (FrameConvention::RosBody, [-0.1, 0.2, 2.0]),
+            (FrameConvention::OpenGl, [-0.1, 0.2, 2.0]),
+        ] {
+            let config = PoseConfig { convention, form: PoseForm::CameraInTag, representation: RotationRepresentation::Matrix };
+            let camera = config.transform(&tag);
+            assert!((camera.translation.vector - Vector3::from(translation)).norm() < 1e-12, "{:?}", convention);
+            assert!((camera * config.convention.convert(&tag)).rotation.angle() < 1e-12);
+        }
+    }
+
+    // isometry back from each representation
+    fn isometry(value: &PoseValue) -> IsometryMatrix3<f64> {
+        use nalgebra::{Matrix4, Quaternion};
+
+        let from = |t: &[f64; 3], r: Rotation3<f64>| IsometryMatrix3::from_parts(Translation3::new(t[0], t[1], t[2]), r);
+        match value {
+            PoseValue::Quaternion { translation, rotation: [x, y, z, w] } => {
+                let q = Quaternion::new(*w, *x, *y, *z);
+                assert!((q.norm() - 1.0).abs() < 1e-12);
+                from(translation, UnitQuaternion::from_quaternion(q).to_rotation_matrix())
+            }
+            PoseValue::RotationVector { translation, rotation } => from(translation, Rotation3::new(Vector3::from(*rotation))),
+            PoseValue::Euler { translation, order, angles: [a, b, c] } => {
+                let (x, y, z) = (Vector3::x_axis(), Vector3::y_axis(), Vector3::z_axis());
+                let r = match order {
+                    EulerOrder::Zyx => Rotation3::from_axis_angle(&z, *a) * Rotation3::from_axis_angle(&y, *b) * Rotation3::from_axis_angle(&x, *c),
+                    EulerOrder::Xyz => Rotation3::from_axis_angle(&x, *a) * Rotation3::from_axis_angle(&y, *b) * Rotation3::from_axis_angle(&z, *c),
+                };
+                from(translation, r)
+            }
+            PoseValue::Matrix(rows) => {
+                let m = Matrix4::from_fn(|row, col| rows[row][col]);
+                assert_eq!(rows[3], [0.0, 0.0, 0.0, 1.0]);
+                let r = Rotation3::from_matrix_unchecked(m.fixed_slice::<nalgebra::U3, nalgebra::U3>(0, 0).into_owned());
+                from(&[m[(0, 3)], m[(1, 3)], m[(2, 3)]], r)
+            }
+        }
+    }
+
+    #[test]
+    fn representations_roundtrip() {
+        let tag = IsometryMatrix3::from_parts(Translation3::new(-0.3, 0.15, 1.4), Rotation3::new(Vector3::new(0.4, -0.2, 0.7)));
+        let representations = [
+            RotationRepresentation::Quaternion,
+            RotationRepresentation::RotationVector,
+            RotationRepresentation::Euler(EulerOrder::Xyz),
+            RotationRepresentation::Euler(EulerOrder::Zyx),
+            RotationRepresentation::Matrix,
+        ];
+
+        for &convention in &CONVENTIONS {
+            for &form in &FORMS {
+                for &representation in &representations {
+                    let config = PoseConfig { convention, form, representation };
+                    let expected = config.transform(&tag);
+                    let found = isometry(&config.apply(&tag));
+                    assert!((found.translation.vector - expected.translation.vector).norm() < 1e-12, "{:?}", config);
+                    assert!((found.rotation.inverse() * expected.rotation).angle() < 1e-9, "{:?}", config);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,251 @@
+use cv_pinhole::CameraIntrinsics;
+use image::{GrayImage, Luma};
+use imageproc::definitions::Image;
+use nalgebra::{Point2, Vector2};
+
+use crate::camera::BrownConrady;
+use crate::source::PixelFormat;
+
+/// Color of the top left 2x2 block of a Bayer mosaic, in reading order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BayerPattern {
+    Rggb,
+    Bggr,
+    Grbg,
+    Gbrg,
+}
+
+impl BayerPattern {
+    // whether (x, y) holds a green sample
+    fn is_green(&self, x: u32, y: u32) -> bool {
+        let green_on_even = match self {
+            BayerPattern::Rggb | BayerPattern::Bggr => false,
+            BayerPattern::Grbg | BayerPattern::Gbrg => true,
+        };
+        ((x + y) % 2 == 0) == green_on_even
+    }
+}
+
+/// A frame buffer straight from a capture device.
+#[derive(Debug, Clone, Copy)]
+pub struct RawImage<'a> {
+    pub data: &'a [u8],
+    pub width: u32,
+    pub height: u32,
+    // bytes per row of the first plane, rows may be padded
+    pub stride: usize,
+    pub format: PixelFormat,
+}
+
+impl<'a> RawImage<'a> {
+    /// A tightly packed buffer.
+    pub fn new(data: &'a [u8], width: u32, height: u32, format: PixelFormat) -> RawImage<'a> {
+        let bytes_per_pixel = match format {
+            PixelFormat::Rgb8 => 3,
+            PixelFormat::Yuyv => 2,
+            PixelFormat::Gray8 | PixelFormat::Nv12 | PixelFormat::Bayer(_) => 1,
+        };
+        RawImage { data, width, height, stride: width as usize * bytes_per_pixel, format }
+    }
+
+    fn row(&self, y: u32) -> Option<&'a [u8]> {
+        let start = y as usize * self.stride;
+        self.data.get(start..start + self.stride)
+    }
+
+    /// Full resolution luma. YUYV and NV12 use the Y samples as they are, Bayer mosaics use the
+    /// green channel with the missing samples interpolated from their four neighbours. Returns None
+    /// if the buffer is too small for the dimensions.
+    pub fn to_luma(&self) -> Option<GrayImage> {
+        let (width, height) = (self.width, self.height);
+        let mut out = GrayImage::new(width, height);
+
+        for y in 0..height {
+            let row = self.row(y)?;
+            for x in 0..width {
+                let value = match self.format {
+                    PixelFormat::Gray8 | PixelFormat::Nv12 => *row.get(x as usize)?,
+                    PixelFormat::Yuyv => *row.get(x as usize * 2)?,
+                    PixelFormat::Rgb8 => {
+                        let p = row.get(x as usize * 3..x as usize * 3 + 3)?;
+                        ((p[0] as u32 * 77 + p[1] as u32 * 150 + p[2] as u32 * 29) >> 8) as u8
+                    }
+                    PixelFormat::Bayer(pattern) => {
+                        if pattern.is_green(x, y) {
+                            *row.get(x as usize)?
+                        } else {
+                            self.green_at(x, y)?
+                        }
+                    }
+                };
+                out.put_pixel(x, y, Luma { data: [value] });
+            }
+        }
+        Some(out)
+    }
+
+    // average of the green neighbours of a red or blue site
+    fn green_at(&self, x: u32, y: u32) -> Option<u8> {
+        let mut sum = 0u32;
+        let mut count = 0u32;
+        for &(dx, dy) in &[(-1i64, 0i64), (1, 0), (0, -1), (0, 1)] {
+            let nx = x as i64 + dx;
+            let ny = y as i64 + dy;
+            if nx < 0 || ny < 0 || nx >= self.width as i64 || ny >= self.height as i64 {
+                continue;
+            }
+            sum += *self.row(ny as u32)?.get(nx as usize)? as u32;
+            count += 1;
+        }
+        Some((sum / count.max(1)) as u8)
+    }
+
+    /// Half resolution green channel of a Bayer mosaic, averaging the two green samples of every
+    /// 2x2 block. Much cheaper than interpolation, use `half_resolution_camera` for the matching
+    /// camera model. Returns None for other formats.
+    pub fn bayer_green_half(&self) -> Option<GrayImage> {
+        let pattern = match self.format {
+            PixelFormat::Bayer(p) => p,
+            _ => return None,
+        };
+        let mut out = GrayImage::new(self.width / 2, self.height / 2);
+
+        for y in 0..self.height / 2 {
+            let top = self.row(y * 2)?;
+            let bottom = self.row(y * 2 + 1)?;
+            for x in 0..self.width / 2 {
+                let (a, b) = if pattern.is_green(0, 0) {
+                    (top.get(x as usize * 2)?, bottom.get(x as usize * 2 + 1)?)
+                } else {
+                    (top.get(x as usize * 2 + 1)?, bottom.get(x as usize * 2)?)
+                };
+                out.put_pixel(x, y, Luma { data: [((*a as u32 + *b as u32) / 2) as u8] });
+            }
+        }
+        Some(out)
+    }
+}
+
+/// Camera model for images from `bayer_green_half`, where each pixel is the center of a 2x2 block
+/// of the full resolution sensor.
+pub fn half_resolution_camera(camera: &BrownConrady) -> BrownConrady {
+    let i = &camera.intrinsics;
+    BrownConrady::new(
+        CameraIntrinsics {
+            focals: Vector2::new(i.focals[0] / 2.0, i.focals[1] / 2.0),
+            principal_point: Point2::new((i.principal_point[0] - 0.5) / 2.0, (i.principal_point[1] - 0.5) / 2.0),
+            skew: i.skew / 2.0,
+        },
+        camera.distortion,
+    )
+}
+
+/// Shifts 10, 12 or 14-bit samples stored in the low bits of 16-bit words up to the full range the
+/// detector expects, replicating the top bits into the bottom so white stays at 65535.
+pub fn expand_bit_depth(image: &mut Image<Luma<u16>>, bits: u32) {
+    if bits == 0 || bits >= 16 {
+        return
+    }
+    for p in image.pixels_mut() {
+        let v = p.data[0] as u32;
+        p.data[0] = ((v << (16 - bits)) | (v >> (2 * bits).saturating_sub(16))) as u16;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn luma_from_raw_formats() {
+        // 4x2 YUYV: Y U Y V per pixel pair
+        let yuyv = [10, 128, 20, 128, 30, 128, 40, 128, 50, 128, 60, 128, 70, 128, 80, 128];
+        let luma = RawImage::new(&yuyv, 4, 2, PixelFormat::Yuyv).to_luma().unwrap();
+        assert_eq!(luma.into_raw(), vec![10, 20, 30, 40, 50, 60, 70, 80]);
+
+        // 2x2 NV12 with a single interleaved chroma pair after the luma plane
+        let nv12 = [1, 2, 3, 4, 128, 128];
+        let luma = RawImage::new(&nv12, 2, 2, PixelFormat::Nv12).to_luma().unwrap();
+        assert_eq!(luma.into_raw(), vec![1, 2, 3, 4]);
+
+        // RGGB block with greens 100 and 120
+        let bayer = [0, 100, 120, 255];
+        let raw = RawImage::new(&bayer, 2, 2, PixelFormat::Bayer(BayerPattern::Rggb));
+        assert_eq!(raw.bayer_green_half().unwrap().into_raw(), vec![110]);
+        assert_eq!(raw.to_luma().unwrap().into_raw(), vec![110, 100, 120, 110]);
+    }
+
+    #[test]
+    fn bayer_patterns() {
+        // 2x2 blocks with the greens at 100 and 120, red and blue at 0 and 255
+        let green_odd = [0, 100, 120, 255];
+        let green_even = [100, 0, 255, 120];
+        for &(pattern, data, luma) in &[
+            (BayerPattern::Rggb, green_odd, [110, 100, 120, 110]),
+            (BayerPattern::Bggr, [255, 100, 120, 0], [110, 100, 120, 110]),
+            (BayerPattern::Grbg, green_even, [100, 110, 110, 120]),
+            (BayerPattern::Gbrg, [100, 255, 0, 120], [100, 110, 110, 120]),
+        ] {
+            let raw = RawImage::new(&data, 2, 2, PixelFormat::Bayer(pattern));
+            assert_eq!(raw.to_luma().unwrap().into_raw(), luma.to_vec(), "{:?}", pattern);
+            assert_eq!(raw.bayer_green_half().unwrap().into_raw(), vec![110], "{:?}", pattern);
+        }
+
+        // two blocks side by side keep their own greens
+        let wide = [0, 10, 0, 50, 30, 0, 70, 0];
+        let raw = RawImage::new(&wide, 4, 2, PixelFormat::Bayer(BayerPattern::Rggb));
+        assert_eq!(raw.bayer_green_half().unwrap().into_raw(), vec![20, 60]);
+        assert!(RawImage::new(&wide, 4, 2, PixelFormat::Gray8).bayer_green_half().is_none());
+    }
+
+    #[test]
+    fn padded_rows() {
+        // 3x2 gray rows padded to 5 bytes
+        let gray = [1, 2, 3, 99, 99, 4, 5, 6, 99, 99];
+        let raw = RawImage { stride: 5, ..RawImage::new(&gray, 3, 2, PixelFormat::Gray8) };
+        assert_eq!(raw.to_luma().unwrap().into_raw(), vec![1, 2, 3, 4, 5, 6]);
+        // the last row has to be complete, padding included
+        assert!(RawImage { stride: 5, ..RawImage::new(&gray[..8], 3, 2, PixelFormat::Gray8) }.to_luma().is_none());
+
+        // 2x2 YUYV rows padded from 4 to 6 bytes
+        let yuyv = [10, 128, 20, 128, 0, 0, 30, 128, 40, 128, 0, 0];
+        let raw = RawImage { stride: 6, ..RawImage::new(&yuyv, 2, 2, PixelFormat::Yuyv) };
+        assert_eq!(raw.to_luma().unwrap().into_raw(), vec![10, 20, 30, 40]);
+
+        // 1x2 RGB rows padded from 3 to 4 bytes
+        let rgb = [255, 255, 255, 7, 0, 0, 0, 7];
+        let raw = RawImage { stride: 4, ..RawImage::new(&rgb, 1, 2, PixelFormat::Rgb8) };
+        assert_eq!(raw.to_luma().unwrap().into_raw(), vec![255, 0]);
+
+        // RGGB rows padded from 2 to 4 bytes
+        let bayer = [0, 100, 9, 9, 120, 255, 9, 9];
+        let raw = RawImage { stride: 4, ..RawImage::new(&bayer, 2, 2, PixelFormat::Bayer(BayerPattern::Rggb)) };
+        assert_eq!(raw.bayer_green_half().unwrap().into_raw(), vec![110]);
+        assert_eq!(raw.to_luma().unwrap().into_raw(), vec![110, 100, 120, 110]);
+    }
+
+    #[test]
+    fn half_resolution_intrinsics() {
+        use cv_core::{CameraModel, KeyPoint};
+
+        let full = BrownConrady::new(
+            CameraIntrinsics {
+                focals: Vector2::new(800.0, 780.0),
+                principal_point: Point2::new(320.5, 240.5),
+                skew: 0.0,
+            },
+            [-0.1, 0.02, 0.0, 0.0, 0.0],
+        );
+        let half = half_resolution_camera(&full);
+        assert_eq!(half.intrinsics.focals, Vector2::new(400.0, 390.0));
+        assert_eq!(half.intrinsics.principal_point, Point2::new(160.0, 120.0));
+        assert_eq!(half.distortion, full.distortion);
+
+        // half resolution pixel (x, y) is the center of the full resolution block at (2x, 2y)
+        for &(x, y) in &[(0.0, 0.0), (100.0, 50.0), (319.0, 239.0)] {
+            let from_half = half.calibrate(KeyPoint(Point2::new(x, y)));
+            let from_full = full.calibrate(KeyPoint(Point2::new(2.0 * x + 0.5, 2.0 * y + 0.5)));
+            assert!((from_half.0 - from_full.0).norm() < 1e-12);
+        }
+    }
+}
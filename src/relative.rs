@@ -0,0 +1,260 @@
+use nalgebra::{IsometryMatrix3, Matrix3, Matrix6, Point3, Rotation3, SymmetricEigen, Translation3, Unit, Vector3};
+
+use crate::camera::Camera;
+use crate::decode::DecodedLFTag;
+use crate::localize::{node_correspondences, pose_covariance};
+use crate::optimize::{pose_from_params, pose_to_params};
+
+#[derive(Debug, Clone)]
+pub struct RelativePose {
+    pub from: usize,
+    pub to: usize,
+    // frame of tag `to` in the frame of tag `from`
+    pub pose: IsometryMatrix3<f64>,
+    // rotation vector then translation, as in `optimize::pose_to_params`
+    pub covariance: Matrix6<f64>,
+}
+
+// jacobian of the parameters of `f(pose)` with respect to the parameters of `pose`
+fn pose_jacobian<F: Fn(&IsometryMatrix3<f64>) -> IsometryMatrix3<f64>>(pose: &IsometryMatrix3<f64>, f: F) -> Matrix6<f64> {
+    let params = pose_to_params(pose);
+    let base = pose_to_params(&f(pose));
+    let mut jacobian = Matrix6::zeros();
+    for col in 0..6 {
+        let mut shifted = params;
+        let step = 1e-7 * params[col].abs().max(1.0);
+        shifted[col] += step;
+        let out = pose_to_params(&f(&pose_from_params(&shifted)));
+        for row in 0..6 {
+            jacobian[(row, col)] = (out[row] - base[row]) / step;
+        }
+    }
+    jacobian
+}
+
+/// Pose of tag `b` in the frame of tag `a`, both seen in the same image. The covariance is
+/// propagated to first order from the reprojection uncertainty of both detections, with pixel noise
+/// of standard deviation `pixel_sigma`. Units follow the tag sizes of the detections.
+pub fn relative_pose<C: Camera>(camera: &C, a: &DecodedLFTag, b: &DecodedLFTag, pixel_sigma: f64) -> Option<RelativePose> {
+    let pose_a = a.final_pose.0;
+    let pose_b = b.final_pose.0;
+    let cov_a = pose_covariance(camera, &node_correspondences(a, a.tag_size), &pose_a, pixel_sigma)?;
+    let cov_b = pose_covariance(camera, &node_correspondences(b, b.tag_size), &pose_b, pixel_sigma)?;
+
+    let j_a = pose_jacobian(&pose_a, |p| p.inverse() * pose_b);
+    let j_b = pose_jacobian(&pose_b, |p| pose_a.inverse() * p);
+
+    Some(RelativePose {
+        from: a.data,
+        to: b.data,
+        pose: pose_a.inverse() * pose_b,
+        covariance: j_a * cov_a * j_a.transpose() + j_b * cov_b * j_b.transpose(),
+    })
+}
+
+/// Relative poses between every pair of detections in a frame, each pair once with the lower index
+/// first.
+pub fn relative_poses<C: Camera>(camera: &C, detections: &[DecodedLFTag], pixel_sigma: f64) -> Vec<RelativePose> {
+    let mut out = Vec::new();
+    for (i, a) in detections.iter().enumerate() {
+        for b in &detections[i + 1..] {
+            if let Some(r) = relative_pose(camera, a, b, pixel_sigma) {
+                out.push(r);
+            }
+        }
+    }
+    out
+}
+
+#[derive(Debug, Clone)]
+pub struct GroundPlane {
+    // plane frame in the camera frame, z along the normal pointing into the floor like the tag z
+    // axes, origin at the centroid of the tag nodes
+    pub pose: IsometryMatrix3<f64>,
+    pub normal: Vector3<f64>,
+    // normal . p + offset = 0 on the plane
+    pub offset: f64,
+    // rms distance of the tag nodes to the plane
+    pub rms: f64,
+    // mean signed distance of each tag's nodes to the plane, by id
+    pub tag_offsets: Vec<(usize, f64)>,
+}
+
+impl GroundPlane {
+    /// Height of a camera frame point above the floor, positive on the camera side.
+    pub fn height(&self, point: &Point3<f64>) -> f64 {
+        -(self.normal.dot(&point.coords) + self.offset)
+    }
+}
+
+/// Fits a plane through the nodes of several tags lying flat on the same surface. Needs at least
+/// one detection, several spread out tags give a much better estimate.
+pub fn ground_plane(detections: &[DecodedLFTag]) -> Option<GroundPlane> {
+    let points: Vec<(usize, Point3<f64>)> = detections.iter().flat_map(|tag| {
+        let pose = tag.final_pose.0;
+        tag.node_world_pos.iter().map(move |w| (tag.data, pose * Point3::new(w.0 as f64, w.1 as f64, 0.0)))
+    }).collect();
+    if points.len() < 3 {
+        return None;
+    }
+
+    let centroid = points.iter().fold(Vector3::zeros(), |acc, (_, p)| acc + p.coords) / points.len() as f64;
+    let mut scatter = Matrix3::zeros();
+    for (_, p) in &points {
+        let d = p.coords - centroid;
+        scatter += d * d.transpose();
+    }
+
+    let eigen = SymmetricEigen::new(scatter);
+    let (min_idx, _) = eigen.eigenvalues.iter().enumerate()
+        .fold((0, std::f64::MAX), |best, (idx, v)| if *v < best.1 { (idx, *v) } else { best });
+    let mut normal: Vector3<f64> = eigen.eigenvectors.column(min_idx).into_owned();
+
+    let tag_z = detections.iter().fold(Vector3::zeros(), |acc, t| acc + t.final_pose.0.rotation * Vector3::z());
+    if normal.dot(&tag_z) < 0.0 {
+        normal = -normal;
+    }
+    let offset = -normal.dot(&centroid);
+
+    let distances: Vec<(usize, f64)> = points.iter().map(|(id, p)| (*id, normal.dot(&p.coords) + offset)).collect();
+    let rms = (distances.iter().map(|(_, d)| d * d).sum::<f64>() / distances.len() as f64).sqrt();
+    let tag_offsets = detections.iter().map(|tag| {
+        let own: Vec<f64> = distances.iter().filter(|(id, _)| *id == tag.data).map(|(_, d)| *d).collect();
+        (tag.data, own.iter().sum::<f64>() / own.len().max(1) as f64)
+    }).collect();
+
+    // keep the x axis of the first tag projected into the plane
+    let x_hint = detections[0].final_pose.0.rotation * Vector3::x();
+    let x = Unit::new_normalize(x_hint - normal * normal.dot(&x_hint));
+    let y = normal.cross(&x);
+    let rotation = Rotation3::from_matrix_unchecked(Matrix3::from_columns(&[x.into_inner(), y, normal]));
+
+    Some(GroundPlane {
+        pose: IsometryMatrix3::from_parts(Translation3::from(centroid), rotation),
+        normal,
+        offset,
+        rms,
+        tag_offsets,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera::BrownConrady;
+    use crate::decode::LFTagClass;
+    use crate::localize::refine_pose;
+    use cv_core::WorldPose;
+    use cv_pinhole::CameraIntrinsics;
+    use nalgebra::{Point2, Vector2};
+    use rand::rngs::SmallRng;
+    use rand::SeedableRng;
+
+    fn camera() -> BrownConrady {
+        BrownConrady::new(
+            CameraIntrinsics {
+                focals: Vector2::new(700.0, 700.0),
+                principal_point: Point2::new(320.0, 240.0),
+                skew: 0.0,
+            },
+            [0.0; 5],
+        )
+    }
+
+    // detection of a tag 0.1 wide at `pose` with noisy nodes and the pose refined from them
+    fn detection(camera: &BrownConrady, data: usize, pose: &IsometryMatrix3<f64>, rng: &mut SmallRng) -> DecodedLFTag {
+        let mut tag = DecodedLFTag::synthetic_sized(camera, LFTagClass::LFTag3x3, data, 0.1, pose, 0.2, rng);
+        let (refined, _) = refine_pose(camera, &node_correspondences(&tag, tag.tag_size), &tag.final_pose.0);
+        tag.final_pose = WorldPose(refined);
+        tag
+    }
+
+    fn assert_covariance(covariance: &Matrix6<f64>) {
+        assert!((covariance - covariance.transpose()).norm() <= 1e-9 * covariance.norm());
+        let eigenvalues = SymmetricEigen::new(*covariance).eigenvalues;
+        let largest = eigenvalues.iter().cloned().fold(0.0, f64::max);
+        assert!(largest > 0.0);
+        assert!(eigenvalues.iter().all(|v| *v >= -1e-9 * largest));
+    }
+
+    #[test]
+    fn composes_detections() {
+        let camera = camera();
+        let mut rng = SmallRng::from_seed([7; 16]);
+        let pose_a = IsometryMatrix3::from_parts(Translation3::new(-0.15, 0.0, 0.8), Rotation3::from_euler_angles(0.2, 0.0, 0.1));
+        let pose_b = IsometryMatrix3::from_parts(Translation3::new(0.1, 0.05, 0.9), Rotation3::from_euler_angles(-0.1, 0.3, 0.0));
+        let a = detection(&camera, 3, &pose_a, &mut rng);
+        let b = detection(&camera, 12, &pose_b, &mut rng);
+
+        let relative = relative_pose(&camera, &a, &b, 0.2).unwrap();
+        assert_eq!((relative.from, relative.to), (3, 12));
+        let expected = pose_a.inverse() * pose_b;
+        assert!((relative.pose.translation.vector - expected.translation.vector).norm() < 1e-2);
+        assert!((relative.pose.rotation.inverse() * expected.rotation).angle() < 2e-2);
+
+        let covariance = relative.covariance;
+        assert_covariance(&covariance);
+
+        // first order propagation scales with the pixel variance
+        let doubled = relative_pose(&camera, &a, &b, 0.4).unwrap().covariance;
+        assert!((doubled - covariance * 4.0).norm() <= 1e-6 * doubled.norm());
+    }
+
+    #[test]
+    fn every_pair_once() {
+        let camera = camera();
+        let mut rng = SmallRng::from_seed([8; 16]);
+        let poses = [
+            IsometryMatrix3::from_parts(Translation3::new(-0.2, 0.0, 0.9), Rotation3::from_euler_angles(0.1, 0.0, 0.0)),
+            IsometryMatrix3::from_parts(Translation3::new(0.05, -0.1, 0.8), Rotation3::from_euler_angles(0.0, 0.2, 0.1)),
+            IsometryMatrix3::from_parts(Translation3::new(0.1, 0.1, 1.0), Rotation3::from_euler_angles(-0.2, 0.0, 0.3)),
+        ];
+        let detections: Vec<_> = [4, 2, 9].iter().zip(&poses).map(|(id, pose)| detection(&camera, *id, pose, &mut rng)).collect();
+
+        let pairs = relative_poses(&camera, &detections, 0.2);
+        assert_eq!(pairs.iter().map(|r| (r.from, r.to)).collect::<Vec<_>>(), vec![(4, 2), (4, 9), (2, 9)]);
+
+        let noisier = relative_poses(&camera, &detections, 0.4);
+        for (pair, noisy) in pairs.iter().zip(&noisier) {
+            assert_covariance(&pair.covariance);
+            assert!(noisy.covariance.trace() > pair.covariance.trace());
+        }
+        assert!(relative_poses(&camera, &detections[..1], 0.2).is_empty());
+    }
+
+    #[test]
+    fn fits_floor_plane() {
+        let camera = camera();
+        let mut rng = SmallRng::from_seed([9; 16]);
+        // floor seen from above at an angle, z pointing into the floor like the tag z axes
+        let floor = IsometryMatrix3::from_parts(Translation3::new(0.0, 0.1, 1.2), Rotation3::from_euler_angles(0.5, 0.0, 0.1));
+        // tag centers in the floor frame, the one in the middle lies on a 1cm thick board
+        let centers = [(1, -0.3, -0.2, 0.0), (2, 0.3, -0.2, 0.0), (3, -0.3, 0.2, 0.0), (4, 0.3, 0.2, 0.0), (5, 0.0, 0.0, -0.01)];
+        let detections: Vec<_> = centers.iter().map(|&(id, x, y, z)| {
+            let pose = floor * IsometryMatrix3::translation(x - 0.05, y - 0.05, z);
+            DecodedLFTag::synthetic_sized(&camera, LFTagClass::LFTag3x3, id, 0.1, &pose, 0.0, &mut rng)
+        }).collect();
+
+        let plane = ground_plane(&detections).unwrap();
+        let floor_z = floor.rotation * Vector3::z();
+        assert!(plane.normal.angle(&floor_z) < 1e-2);
+        assert!((plane.pose.rotation * Vector3::z() - plane.normal).norm() < 1e-9);
+
+        // the raised tag pulls the fit 2mm up, a fifth of its height
+        let lift = 0.01 / 5.0;
+        assert!((plane.offset - (-floor_z.dot(&floor.translation.vector) + lift)).abs() < 1e-3);
+        assert!((plane.height(&Point3::origin()) - (floor_z.dot(&floor.translation.vector) - lift)).abs() < 1e-3);
+        let above = Point3::from(floor.translation.vector - floor_z * 0.5);
+        assert!((plane.height(&above) - (0.5 - lift)).abs() < 1e-3);
+        assert!(plane.height(&Point3::from(floor.translation.vector + floor_z * 0.5)) < 0.0);
+
+        assert!(plane.rms > 0.0 && plane.rms < 0.01);
+        assert_eq!(plane.tag_offsets.iter().map(|(id, _)| *id).collect::<Vec<_>>(), vec![1, 2, 3, 4, 5]);
+        for (id, offset) in &plane.tag_offsets {
+            let expected = if *id == 5 { -0.01 + lift } else { lift };
+            assert!((offset - expected).abs() < 1e-3, "tag {} is {} off the plane", id, offset);
+        }
+
+        assert!(ground_plane(&[]).is_none());
+    }
+}
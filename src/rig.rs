@@ -0,0 +1,331 @@
+use cv_core::{CameraModel, KeyPoint};
+use nalgebra::{IsometryMatrix3, Matrix3, Point2, Point3, Rotation3, Translation3, Vector3};
+#[cfg(feature = "serde-serialize")]
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+#[cfg(feature = "serde-serialize")]
+use std::io;
+#[cfg(feature = "serde-serialize")]
+use std::path::Path;
+
+#[cfg(feature = "serde-serialize")]
+use crate::calibration_file::{from_simple, to_simple, CameraCalibration, SimpleCalibration};
+#[cfg(feature = "serde-serialize")]
+use crate::camera::BrownConrady;
+use crate::camera::Camera;
+use crate::decode::DecodedLFTag;
+use crate::localize::{project, reprojection_rms};
+use crate::optimize::{levenberg_marquardt, pose_from_params, pose_to_params, LeastSquares};
+#[cfg(feature = "serde-serialize")]
+use crate::pose::PoseRecord;
+#[cfg(feature = "serde-serialize")]
+use crate::serialize::{read_structured, write_structured};
+
+#[derive(Debug, Clone)]
+pub struct RigCamera<C: Camera> {
+    pub name: String,
+    pub camera: C,
+    // rig frame in the camera frame
+    pub pose: IsometryMatrix3<f64>,
+}
+
+/// Several calibrated cameras with known extrinsics, observing the same scene.
+#[derive(Debug, Clone)]
+pub struct Rig<C: Camera> {
+    pub cameras: Vec<RigCamera<C>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct TriangulatedTag {
+    pub id: usize,
+    // tag frame in the rig frame
+    pub pose: IsometryMatrix3<f64>,
+    // triangulated nodes, tag frame position and rig frame position
+    pub points: Vec<(Point3<f64>, Point3<f64>)>,
+    // rms reprojection error under the final pose for each camera, None if it did not see the tag
+    pub camera_errors: Vec<Option<f64>>,
+}
+
+// node position key in half grid units, identical across cameras for the same decoded tag
+fn node_key(tag: &DecodedLFTag, world: (f32, f32)) -> (i32, i32) {
+    let grid = tag.class.get_pix_size() as f64 / tag.tag_size;
+    ((world.0 as f64 * grid * 2.0).round() as i32, (world.1 as f64 * grid * 2.0).round() as i32)
+}
+
+#[cfg(feature = "serde-serialize")]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RigCameraRecord {
+    name: String,
+    intrinsics: SimpleCalibration,
+    // rig frame in the camera frame
+    pose: PoseRecord,
+}
+
+#[cfg(feature = "serde-serialize")]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RigRecord {
+    cameras: Vec<RigCameraRecord>,
+}
+
+/// Point closest to all rays in the least squares sense, rays given as origin and unit direction.
+fn intersect_rays(rays: &[(Point3<f64>, Vector3<f64>)]) -> Option<Point3<f64>> {
+    let mut a = Matrix3::zeros();
+    let mut b = Vector3::zeros();
+    for (origin, dir) in rays {
+        let m = Matrix3::identity() - dir * dir.transpose();
+        a += m;
+        b += m * origin.coords;
+    }
+    a.try_inverse().map(|inv| Point3::from(inv * b))
+}
+
+/// Rigid transform taking `from` onto `to`, by the Kabsch algorithm.
+fn align(from: &[Point3<f64>], to: &[Point3<f64>]) -> Option<IsometryMatrix3<f64>> {
+    let n = from.len() as f64;
+    let from_mean = from.iter().fold(Vector3::zeros(), |acc, p| acc + p.coords) / n;
+    let to_mean = to.iter().fold(Vector3::zeros(), |acc, p| acc + p.coords) / n;
+
+    let mut h = Matrix3::zeros();
+    for (f, t) in from.iter().zip(to) {
+        h += (f.coords - from_mean) * (t.coords - to_mean).transpose();
+    }
+
+    let svd = h.svd(true, true);
+    let u = svd.u?;
+    let v_t = svd.v_t?;
+    let mut correction = Matrix3::identity();
+    if (v_t.transpose() * u.transpose()).determinant() < 0.0 {
+        correction[(2, 2)] = -1.0;
+    }
+    let rotation = Rotation3::from_matrix_unchecked(v_t.transpose() * correction * u.transpose());
+
+    Some(IsometryMatrix3::from_parts(Translation3::from(to_mean - rotation * from_mean), rotation))
+}
+
+struct RigPoseProblem<'a, C: Camera> {
+    rig: &'a Rig<C>,
+    // camera index, tag frame point, pixel
+    observations: &'a [(usize, Point3<f64>, Point2<f64>)],
+}
+
+impl<'a, C: Camera> LeastSquares for RigPoseProblem<'a, C> {
+    fn blocks(&self) -> usize {
+        self.observations.len()
+    }
+
+    fn block_params(&self, _block: usize) -> Vec<usize> {
+        (0..6).collect()
+    }
+
+    fn residuals(&self, block: usize, params: &[f64]) -> Vec<f64> {
+        let (camera, world, image) = &self.observations[block];
+        let rig_camera = &self.rig.cameras[*camera];
+        let p = project(&rig_camera.camera, &(rig_camera.pose * pose_from_params(params)), world);
+        vec![p.x - image.x, p.y - image.y]
+    }
+}
+
+#[cfg(feature = "serde-serialize")]
+impl Rig<BrownConrady> {
+    /// Reads a rig description from JSON, YAML or TOML, holding the name, intrinsics and pose of
+    /// the rig frame for each camera.
+    pub fn load(path: &Path) -> io::Result<Rig<BrownConrady>> {
+        let record: RigRecord = read_structured(path)?;
+        let mut cameras = Vec::new();
+        for camera in record.cameras {
+            cameras.push(RigCamera {
+                name: camera.name,
+                camera: from_simple(camera.intrinsics)?.camera,
+                pose: camera.pose.isometry(),
+            });
+        }
+        Ok(Rig { cameras })
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let record = RigRecord {
+            cameras: self.cameras.iter().map(|c| RigCameraRecord {
+                name: c.name.clone(),
+                intrinsics: to_simple(&CameraCalibration { camera: c.camera, image_size: None }),
+                pose: PoseRecord::from(c.pose),
+            }).collect(),
+        };
+        write_structured(path, &record)
+    }
+}
+
+impl<C: Camera> Rig<C> {
+    pub fn new(cameras: Vec<RigCamera<C>>) -> Rig<C> {
+        Rig { cameras }
+    }
+
+    /// Pose of tag `id` in the rig frame from its detections in every camera, `frames` holding the
+    /// detections of each camera in rig order. Nodes seen by at least two cameras are triangulated
+    /// to initialize the pose, which is then refined against the pixel observations of all cameras.
+    pub fn triangulate(&self, frames: &[Vec<DecodedLFTag>], id: usize) -> Option<TriangulatedTag> {
+        let detections: Vec<Option<&DecodedLFTag>> = self.cameras.iter().enumerate()
+            .map(|(idx, _)| frames.get(idx).and_then(|f| f.iter().find(|t| t.data == id)))
+            .collect();
+
+        // rays of each node, by node key
+        let mut rays: HashMap<(i32, i32), (Point3<f64>, Vec<(Point3<f64>, Vector3<f64>)>)> = HashMap::new();
+        let mut observations = Vec::new();
+        for (idx, detection) in detections.iter().enumerate() {
+            let tag = match detection {
+                Some(t) => t,
+                None => continue,
+            };
+            let rig_camera = &self.cameras[idx];
+            let camera_in_rig = rig_camera.pose.inverse();
+            let origin = camera_in_rig * Point3::origin();
+
+            for (world, image) in tag.node_world_pos.iter().zip(&tag.node_pos) {
+                let tag_point = Point3::new(world.0 as f64, world.1 as f64, 0.0);
+                let pixel = Point2::new(image.0 as f64, image.1 as f64);
+                let normalized = rig_camera.camera.calibrate(KeyPoint(pixel));
+                let dir = camera_in_rig * Vector3::new(normalized.0.x, normalized.0.y, 1.0).normalize();
+
+                rays.entry(node_key(tag, *world))
+                    .or_insert_with(|| (tag_point, Vec::new()))
+                    .1.push((origin, dir));
+                observations.push((idx, tag_point, pixel));
+            }
+        }
+
+        let (tag_points, rig_points): (Vec<_>, Vec<_>) = rays.values()
+            .filter(|(_, r)| r.len() >= 2)
+            .filter_map(|(tag_point, r)| Some((*tag_point, intersect_rays(r)?)))
+            .unzip();
+        if tag_points.len() < 3 {
+            return None
+        }
+
+        let initial = align(&tag_points, &rig_points)?;
+        let mut params = pose_to_params(&initial).to_vec();
+        levenberg_marquardt(&RigPoseProblem { rig: self, observations: &observations }, &mut params, 50);
+        let pose = pose_from_params(&params);
+
+        let camera_errors = detections.iter().enumerate().map(|(idx, detection)| {
+            detection.map(|_| {
+                let points: Vec<_> = observations.iter()
+                    .filter(|o| o.0 == idx)
+                    .map(|o| (o.1, o.2))
+                    .collect();
+                reprojection_rms(&self.cameras[idx].camera, &(self.cameras[idx].pose * pose), &points)
+            })
+        }).collect();
+
+        Some(TriangulatedTag {
+            id,
+            pose,
+            points: tag_points.into_iter().zip(rig_points).collect(),
+            camera_errors,
+        })
+    }
+
+    /// Triangulates every tag id seen by at least two cameras.
+    pub fn triangulate_all(&self, frames: &[Vec<DecodedLFTag>]) -> Vec<TriangulatedTag> {
+        let mut counts: HashMap<usize, usize> = HashMap::new();
+        for frame in frames {
+            let mut ids: Vec<usize> = frame.iter().map(|t| t.data).collect();
+            ids.sort();
+            ids.dedup();
+            for id in ids {
+                *counts.entry(id).or_insert(0) += 1;
+            }
+        }
+
+        let mut ids: Vec<usize> = counts.into_iter().filter(|(_, c)| *c >= 2).map(|(id, _)| id).collect();
+        ids.sort();
+        ids.into_iter().filter_map(|id| self.triangulate(frames, id)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera::BrownConrady;
+    use crate::decode::LFTagClass;
+    use cv_core::WorldPose;
+    use cv_pinhole::CameraIntrinsics;
+    use nalgebra::Vector2;
+
+    fn camera(distortion: [f64; 5]) -> BrownConrady {
+        BrownConrady::new(
+            CameraIntrinsics {
+                focals: Vector2::new(800.0, 780.0),
+                principal_point: Point2::new(320.0, 240.0),
+                skew: 0.0,
+            },
+            distortion,
+        )
+    }
+
+    // two cameras 30 tag units apart, the right one turned in towards the left
+    fn stereo_rig() -> Rig<BrownConrady> {
+        Rig::new(vec![
+            RigCamera {
+                name: String::from("left"),
+                camera: camera([0.0; 5]),
+                pose: IsometryMatrix3::identity(),
+            },
+            RigCamera {
+                name: String::from("right"),
+                camera: camera([-0.1, 0.02, 0.001, -0.002, 0.0]),
+                pose: IsometryMatrix3::from_parts(Translation3::new(-30.0, 1.0, 2.0), Rotation3::from_euler_angles(0.01, -0.15, 0.0)),
+            },
+        ])
+    }
+
+    #[test]
+    fn triangulates_known_tag() {
+        let rig = stereo_rig();
+        let class = LFTagClass::LFTag3x3;
+        let id = 0x1234;
+        // tag frame in the rig frame, about 150 tag units in front of the cameras
+        let tag_pose = IsometryMatrix3::from_parts(Translation3::new(3.0, -10.0, 150.0), Rotation3::from_euler_angles(0.3, -0.2, 0.1));
+
+        let frames: Vec<Vec<DecodedLFTag>> = rig.cameras.iter()
+            .map(|c| vec![DecodedLFTag::synthetic(&c.camera, class.clone(), id, WorldPose(c.pose * tag_pose))])
+            .collect();
+        let triangulated = rig.triangulate(&frames, id).unwrap();
+
+        assert_eq!(triangulated.id, id);
+        assert_eq!(triangulated.points.len(), class.total_node_count());
+        for (tag_point, rig_point) in &triangulated.points {
+            assert!((tag_pose * tag_point - rig_point).norm() < 1e-2);
+        }
+        assert!((triangulated.pose.translation.vector - tag_pose.translation.vector).norm() < 1e-2);
+        assert!((triangulated.pose.rotation.inverse() * tag_pose.rotation).angle() < 1e-4);
+        for error in &triangulated.camera_errors {
+            assert!(error.unwrap() < 1e-3);
+        }
+
+        // one camera alone cannot triangulate
+        assert!(rig.triangulate(&frames[..1], id).is_none());
+        assert!(rig.triangulate(&frames, id + 1).is_none());
+        assert_eq!(rig.triangulate_all(&frames).len(), 1);
+    }
+
+    #[test]
+    #[cfg(feature = "serde-serialize")]
+    fn load_save_roundtrip() {
+        let rig = stereo_rig();
+        for extension in &["json", "yaml", "toml"] {
+            let path = std::env::temp_dir().join(format!("fiducial-rig-{}.{}", std::process::id(), extension));
+            rig.save(&path).unwrap();
+            let loaded = Rig::<BrownConrady>::load(&path).unwrap();
+            std::fs::remove_file(&path).unwrap();
+
+            assert_eq!(loaded.cameras.len(), rig.cameras.len());
+            for (a, b) in rig.cameras.iter().zip(&loaded.cameras) {
+                assert_eq!(a.name, b.name);
+                assert_eq!(a.camera.intrinsics.focals, b.camera.intrinsics.focals);
+                assert_eq!(a.camera.intrinsics.principal_point, b.camera.intrinsics.principal_point);
+                assert_eq!(a.camera.distortion, b.camera.distortion);
+                assert!((a.pose.translation.vector - b.pose.translation.vector).norm() < 1e-12);
+                assert!((a.pose.rotation.inverse() * b.pose.rotation).angle() < 1e-6);
+            }
+        }
+    }
+}
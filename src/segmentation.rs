@@ -2,12 +2,17 @@ use image::{ImageBuffer, Luma};
 use std::collections::VecDeque;
 use std::mem;
 
+use crate::Intensity;
+
 #[cfg(test)]
 use imageproc::gray_image;
+#[cfg(feature = "serde-serialize")]
+use serde::{Deserialize, Serialize};
 
 type Label = u32;
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde-serialize", derive(Serialize, Deserialize))]
 pub struct FeatureVector {
     pub area: u32,
     pub bounding_box: [(u32, u32); 2],
@@ -25,8 +30,9 @@ pub struct FeatureVector {
 }
 
 impl FeatureVector {
-    fn new(x: u32, y: u32, value: u32, color: bool, merge: AugmentedLabel) -> FeatureVector {
-        let actual_value = if color { value } else { 255 - value };
+    // `max` is the brightest possible value, dark regions are weighted by their distance to it
+    fn new(x: u32, y: u32, value: u32, max: u32, color: bool, merge: AugmentedLabel) -> FeatureVector {
+        let actual_value = if color { value } else { max - value };
 
         FeatureVector {
             max_x: x,
@@ -40,7 +46,8 @@ impl FeatureVector {
             last_sibling: 0,
             color,
             visited: false,
-            fom: [(x * actual_value) as f32, (y * actual_value) as f32],
+            // in floating point, 16-bit weights overflow u32 past x = 65536
+            fom: [x as f32 * actual_value as f32, y as f32 * actual_value as f32],
             zom: actual_value as f32,
         }
     }
@@ -56,20 +63,20 @@ impl FeatureVector {
         (self.fom[0] / self.zom, self.fom[1] / self.zom)
     }
 
-    pub(crate) fn add_pixel_no_area(&mut self, x: u32, y: u32, value: u32, color: bool) {
-        let actual_value = if color { value } else { 255 - value };
-        self.fom[0] += (x * actual_value) as f32;
-        self.fom[1] += (y * actual_value) as f32;
+    pub(crate) fn add_pixel_no_area(&mut self, x: u32, y: u32, value: u32, max: u32, color: bool) {
+        let actual_value = if color { value } else { max - value };
+        self.fom[0] += x as f32 * actual_value as f32;
+        self.fom[1] += y as f32 * actual_value as f32;
         self.zom += actual_value as f32;
     }
 
-    fn add_pixel(&mut self, x: u32, y: u32, value: u32, color: bool) {
-        let actual_value = if color { value } else { 255 - value };
+    fn add_pixel(&mut self, x: u32, y: u32, value: u32, max: u32, color: bool) {
+        let actual_value = if color { value } else { max - value };
 
         //assert!(self.valid);
         self.area += 1;
-        self.fom[0] += (x * actual_value) as f32;
-        self.fom[1] += (y * actual_value) as f32;
+        self.fom[0] += x as f32 * actual_value as f32;
+        self.fom[1] += y as f32 * actual_value as f32;
         self.zom += actual_value as f32;
 
         // extending bb
@@ -114,6 +121,7 @@ impl FeatureVector {
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, Copy)]
+#[cfg_attr(feature = "serde-serialize", derive(Serialize, Deserialize))]
 pub struct AugmentedLabel {
     label: Label,
     row: u16,
@@ -166,9 +174,9 @@ fn print_img(input: &ImageBuffer<Luma<u8>, Vec<u8>>) {
     }
 }
 
-pub fn to_topo(
+pub fn to_topo<T: Intensity>(
     input: &ImageBuffer<Luma<u8>, Vec<u8>>,
-    input_grey: &ImageBuffer<Luma<u8>, Vec<u8>>,
+    input_grey: &ImageBuffer<Luma<T>, Vec<T>>,
 ) -> Vec<FeatureVector> {
     //print_img(input);
     let mut final_output: Vec<FeatureVector> = Vec::new();
@@ -215,7 +223,7 @@ pub fn to_topo(
 
         // update neighborhood
         let current = i.2.data[0] > 0;
-        let value = input_grey.get_pixel(i.0, i.1).data[0];
+        let value = input_grey.get_pixel(i.0, i.1).data[0].to_u32();
         let top = if i.1 > 0 {
             input.get_pixel(i.0, i.1 - 1).data[0] > 0
         } else {
@@ -255,7 +263,8 @@ pub fn to_topo(
                     active_components[current_label.label as usize].add_pixel(
                         i.0,
                         i.1,
-                        value as u32,
+                        value,
+                        T::MAX,
                         current,
                     );
                 } else {
@@ -269,7 +278,7 @@ pub fn to_topo(
                     );
 
                     merged_fv.merge(free_fv);
-                    merged_fv.add_pixel(i.0, i.1, value as u32, current);
+                    merged_fv.add_pixel(i.0, i.1, value, T::MAX, current);
 
                     //let merged_child = merged_fv.child;
                     //let merged_sib = merged_fv.sibling;
@@ -342,25 +351,26 @@ pub fn to_topo(
                 active_components[current_label.label as usize].add_pixel(
                     i.0,
                     i.1,
-                    value as u32,
+                    value,
+                    T::MAX,
                     current,
                 );
             }
         } else if current == left {
             // add to region
             current_label = left_label;
-            active_components[left_label.label as usize].add_pixel(i.0, i.1, value as u32, current);
+            active_components[left_label.label as usize].add_pixel(i.0, i.1, value, T::MAX, current);
         } else if current == top {
             // add to region
             current_label = top_label;
-            active_components[top_label.label as usize].add_pixel(i.0, i.1, value as u32, current);
+            active_components[top_label.label as usize].add_pixel(i.0, i.1, value, T::MAX, current);
         } else {
             // create region
             current_label = label_fifo.pop_front().unwrap();
             current_label.row = i.1 as u16;
 
             active_components[current_label.label as usize] =
-                FeatureVector::new(i.0, i.1, value as u32, current, current_label);
+                FeatureVector::new(i.0, i.1, value, T::MAX, current, current_label);
         }
 
         // enclosing logic
@@ -489,6 +499,19 @@ mod tests {
         return area == img_size * img_size;
     }
 
+    #[test]
+    fn wide_16_bit_moments() {
+        let max = u16::MAX as u32;
+        let label = AugmentedLabel::bg();
+        let mut fv = FeatureVector::new(70_000, 3, 0, max, false, label);
+        fv.add_pixel(70_001, 3, 0, max, false);
+        fv.add_pixel_no_area(70_002, 4, 0, max, false);
+
+        let (x, y) = fv.get_com_f32();
+        assert!((x - 70_001.0).abs() < 0.1);
+        assert!((y - 10.0 / 3.0).abs() < 1e-3);
+    }
+
     use rand::prelude::*;
     use test::Bencher;
 
@@ -0,0 +1,218 @@
+//! Serde adapters for foreign types held by detections and camera models, and the JSON, YAML
+//! and TOML files they are stored in.
+
+use cv_core::WorldPose;
+use cv_pinhole::CameraIntrinsics;
+use nalgebra::{Point2, Vector2};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::pose::PoseRecord;
+
+pub mod world_pose {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(pose: &WorldPose, serializer: S) -> Result<S::Ok, S::Error> {
+        PoseRecord::from(pose.0).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<WorldPose, D::Error> {
+        Ok(WorldPose(PoseRecord::deserialize(deserializer)?.isometry()))
+    }
+}
+
+pub mod world_poses {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(poses: &[WorldPose], serializer: S) -> Result<S::Ok, S::Error> {
+        let records: Vec<PoseRecord> = poses.iter().map(|p| PoseRecord::from(p.0)).collect();
+        records.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<WorldPose>, D::Error> {
+        let records = Vec::<PoseRecord>::deserialize(deserializer)?;
+        Ok(records.iter().map(|r| WorldPose(r.isometry())).collect())
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct IntrinsicsRecord {
+    focals: [f64; 2],
+    principal_point: [f64; 2],
+    #[serde(default)]
+    skew: f64,
+}
+
+pub mod intrinsics {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(intrinsics: &CameraIntrinsics, serializer: S) -> Result<S::Ok, S::Error> {
+        IntrinsicsRecord {
+            focals: [intrinsics.focals[0], intrinsics.focals[1]],
+            principal_point: [intrinsics.principal_point[0], intrinsics.principal_point[1]],
+            skew: intrinsics.skew,
+        }.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<CameraIntrinsics, D::Error> {
+        let record = IntrinsicsRecord::deserialize(deserializer)?;
+        Ok(CameraIntrinsics {
+            focals: Vector2::new(record.focals[0], record.focals[1]),
+            principal_point: Point2::new(record.principal_point[0], record.principal_point[1]),
+            skew: record.skew,
+        })
+    }
+}
+
+fn invalid_data<E: ToString>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+/// Reads a JSON, YAML or TOML file depending on its extension.
+pub(crate) fn read_structured<T: DeserializeOwned>(path: &Path) -> io::Result<T> {
+    let contents = fs::read_to_string(path)?;
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("json") => serde_json::from_str(&contents).map_err(invalid_data),
+        Some("yaml") | Some("yml") => serde_yaml::from_str(&contents).map_err(invalid_data),
+        Some("toml") => toml::from_str(&contents).map_err(invalid_data),
+        _ => Err(invalid_data(format!("unknown file type {}", path.display()))),
+    }
+}
+
+pub(crate) fn write_structured<T: Serialize>(path: &Path, value: &T) -> io::Result<()> {
+    let contents = match path.extension().and_then(|e| e.to_str()) {
+        Some("json") => serde_json::to_string_pretty(value).map_err(invalid_data)?,
+        Some("yaml") | Some("yml") => serde_yaml::to_string(value).map_err(invalid_data)?,
+        Some("toml") => toml::to_string(value).map_err(invalid_data)?,
+        _ => return Err(invalid_data(format!("unknown file type {}", path.display()))),
+    };
+    fs::write(path, contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::camera::BrownConrady;
+    use crate::decode::{DecodeHypothesis, DecodedLFTag, LFTagClass, NodeConfidence, TopotagClass};
+    use crate::segmentation::{to_topo, FeatureVector};
+    use cv_core::WorldPose;
+    use cv_pinhole::CameraIntrinsics;
+    use image::{GrayImage, Luma};
+    use nalgebra::{IsometryMatrix3, Point2, Rotation3, Translation3, Vector2};
+    use rand::rngs::SmallRng;
+    use rand::SeedableRng;
+    use serde::de::DeserializeOwned;
+    use serde::Serialize;
+
+    fn roundtrip<T: Serialize + DeserializeOwned>(value: &T) -> T {
+        serde_json::from_str(&serde_json::to_string(value).unwrap()).unwrap()
+    }
+
+    // rotations go through rotation vectors, so poses only come back up to rounding
+    fn assert_pose_near(found: &WorldPose, expected: &WorldPose) {
+        assert!((found.0.translation.vector - expected.0.translation.vector).norm() < 1e-12);
+        assert!((found.0.rotation.inverse() * expected.0.rotation).angle() < 1e-12);
+    }
+
+    #[test]
+    fn camera_roundtrip() {
+        let camera = BrownConrady::new(
+            CameraIntrinsics {
+                focals: Vector2::new(900.0, 910.0),
+                principal_point: Point2::new(640.0, 360.0),
+                skew: 0.0,
+            },
+            [0.1, -0.05, 0.001, 0.002, 0.0],
+        );
+        let json = serde_json::to_string(&camera).unwrap();
+        let back: BrownConrady = serde_json::from_str(&json).unwrap();
+        assert_eq!(back.intrinsics.focals, camera.intrinsics.focals);
+        assert_eq!(back.intrinsics.principal_point, camera.intrinsics.principal_point);
+        assert_eq!(back.distortion, camera.distortion);
+    }
+
+    #[test]
+    fn detection_roundtrip() {
+        let camera = BrownConrady::new(
+            CameraIntrinsics {
+                focals: Vector2::new(700.0, 700.0),
+                principal_point: Point2::new(320.0, 240.0),
+                skew: 0.0,
+            },
+            [0.0; 5],
+        );
+        let pose = IsometryMatrix3::from_parts(Translation3::new(0.05, -0.02, 0.7), Rotation3::from_euler_angles(0.3, -0.1, 0.2));
+        let mut tag = DecodedLFTag::synthetic_sized(&camera, LFTagClass::LFTag4x4, 1234, 0.1, &pose, 0.3, &mut SmallRng::from_seed([1; 16]));
+        tag.quality = 0.012;
+        tag.confidence = 0.0;
+        tag.erasures = vec![3];
+        tag.node_confidence = (0..14).map(|symbol| NodeConfidence {
+            symbol: symbol % 4,
+            best_distance: 0.1,
+            second_distance: 0.9,
+            erased: symbol == 3,
+        }).collect();
+        let other = WorldPose(IsometryMatrix3::from_parts(Translation3::new(0.04, 0.0, 0.72), Rotation3::from_euler_angles(-0.2, 0.1, 2.0)));
+        tag.hypotheses = vec![
+            DecodeHypothesis {
+                data: 1234,
+                pose: tag.final_pose.clone(),
+                quality: 0.012,
+                confidence: 0.0,
+                erasures: vec![3],
+                calibration: (0, 2),
+                mirrored: false,
+            },
+            DecodeHypothesis {
+                data: 987,
+                pose: other,
+                quality: 0.4,
+                confidence: 0.1,
+                erasures: vec![],
+                calibration: (1, 3),
+                mirrored: true,
+            },
+        ];
+        tag.initial_poses.push(tag.hypotheses[1].pose.clone());
+
+        let mut back = roundtrip(&tag);
+        assert_pose_near(&back.final_pose, &tag.final_pose);
+        assert_pose_near(&back.initial_pose, &tag.initial_pose);
+        assert_eq!(back.initial_poses.len(), tag.initial_poses.len());
+        for (found, expected) in back.initial_poses.iter().zip(&tag.initial_poses) {
+            assert_pose_near(found, expected);
+        }
+        for (found, expected) in back.hypotheses.iter().zip(&tag.hypotheses) {
+            assert_pose_near(&found.pose, &expected.pose);
+        }
+
+        // everything else comes back exactly
+        back.final_pose = tag.final_pose.clone();
+        back.initial_pose = tag.initial_pose.clone();
+        back.initial_poses = tag.initial_poses.clone();
+        for (found, expected) in back.hypotheses.iter_mut().zip(&tag.hypotheses) {
+            found.pose = expected.pose.clone();
+        }
+        assert_eq!(back, tag);
+    }
+
+    #[test]
+    fn classes_and_segments_roundtrip() {
+        let lftags = vec![LFTagClass::LFTag2x2, LFTagClass::LFTag3x3, LFTagClass::LFTag4x4, LFTagClass::LFTag5x5];
+        assert_eq!(roundtrip(&lftags), lftags);
+        let topotags = vec![TopotagClass::Topotag3x3, TopotagClass::Topotag4x4, TopotagClass::Topotag5x5];
+        assert_eq!(roundtrip(&topotags), topotags);
+
+        // a dark square with a light hole, segmented like any image
+        let mut image = GrayImage::from_fn(12, 12, |x, y| {
+            let dark = (2..10).contains(&x) && (2..10).contains(&y) && !((5..7).contains(&x) && (5..7).contains(&y));
+            Luma { data: [if dark { 0 } else { 255 }] }
+        });
+        crate::add_border(&mut image);
+        let segments: Vec<FeatureVector> = to_topo(&image, &image);
+        assert!(segments.len() > 2);
+        assert_eq!(roundtrip(&segments), segments);
+    }
+}
@@ -0,0 +1,516 @@
+use image::{imageops, GrayImage, Luma};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::raw::BayerPattern;
+
+/// Pixel layout a source delivers before conversion to the grayscale frames used for detection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    Gray8,
+    Rgb8,
+    // packed 4:2:2, Y0 U Y1 V
+    Yuyv,
+    // Y plane followed by interleaved half resolution UV
+    Nv12,
+    Bayer(BayerPattern),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameFormat {
+    pub width: u32,
+    pub height: u32,
+    pub pixel_format: PixelFormat,
+}
+
+#[derive(Debug, Clone)]
+pub struct Frame {
+    pub image: GrayImage,
+    // position in the source, starting at 0
+    pub index: usize,
+    // seconds since the start of the source
+    pub timestamp: f64,
+    // file the frame was read from, if any
+    pub name: Option<String>,
+}
+
+/// Anything producing a sequence of frames, e.g. image files, a video or a camera.
+pub trait FrameSource {
+    /// The next frame, None at the end of the source. A frame that fails to load is returned as an
+    /// error and the source moves on to the following one.
+    fn next_frame(&mut self) -> Option<io::Result<Frame>>;
+
+    /// Native format of the frames, None when it is only known per frame (e.g. a list of files).
+    fn format(&self) -> Option<FrameFormat> {
+        None
+    }
+}
+
+fn open_gray(path: &Path) -> io::Result<GrayImage> {
+    image::open(path)
+        .map(|im| im.to_luma())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e)))
+}
+
+/// A list of image files played back at a fixed frame rate.
+pub struct ImageFiles {
+    paths: Vec<PathBuf>,
+    fps: f64,
+    next: usize,
+}
+
+impl ImageFiles {
+    pub fn new(paths: Vec<PathBuf>, fps: f64) -> ImageFiles {
+        ImageFiles { paths, fps, next: 0 }
+    }
+
+    /// Every file of a directory in sorted name order.
+    pub fn from_dir(dir: &Path, fps: f64) -> io::Result<ImageFiles> {
+        let mut paths: Vec<PathBuf> = fs::read_dir(dir)?
+            .filter_map(|e| e.ok().map(|e| e.path()))
+            .filter(|p| p.is_file())
+            .collect();
+        paths.sort();
+        Ok(ImageFiles::new(paths, fps))
+    }
+}
+
+impl FrameSource for ImageFiles {
+    fn next_frame(&mut self) -> Option<io::Result<Frame>> {
+        let path = self.paths.get(self.next)?;
+        let index = self.next;
+        self.next += 1;
+
+        Some(open_gray(path).map(|image| Frame {
+            image,
+            index,
+            timestamp: index as f64 / self.fps,
+            name: Some(path.to_string_lossy().into_owned()),
+        }))
+    }
+}
+
+/// Numbered files following a printf style pattern such as `frame_%05d.png`, read from `start`
+/// until the first missing number.
+pub struct ImageSequence {
+    prefix: String,
+    suffix: String,
+    width: usize,
+    start: usize,
+    current: usize,
+    fps: f64,
+}
+
+impl ImageSequence {
+    pub fn new(pattern: &str, start: usize, fps: f64) -> Option<ImageSequence> {
+        let pos = pattern.find('%')?;
+        let end = pos + pattern[pos..].find('d')?;
+        let spec = &pattern[pos + 1..end];
+        // `%d` and `%0d` have no minimum width
+        let digits = spec.trim_start_matches('0');
+        let width = if digits.is_empty() { 0 } else { digits.parse().ok()? };
+
+        Some(ImageSequence {
+            prefix: pattern[..pos].to_string(),
+            suffix: pattern[end + 1..].to_string(),
+            width,
+            start,
+            current: start,
+            fps,
+        })
+    }
+
+    fn path(&self, number: usize) -> PathBuf {
+        PathBuf::from(format!("{}{:0width$}{}", self.prefix, number, self.suffix, width = self.width))
+    }
+}
+
+impl FrameSource for ImageSequence {
+    fn next_frame(&mut self) -> Option<io::Result<Frame>> {
+        let path = self.path(self.current);
+        if !path.exists() {
+            return None;
+        }
+        let index = self.current - self.start;
+        self.current += 1;
+
+        Some(open_gray(&path).map(|image| Frame {
+            image,
+            index,
+            timestamp: index as f64 / self.fps,
+            name: Some(path.to_string_lossy().into_owned()),
+        }))
+    }
+}
+
+/// Video file decoded through OpenCV's videoio, timestamps come from the container rather than a
+/// fixed frame rate.
+#[cfg(feature = "video")]
+pub struct VideoFile {
+    capture: opencv::videoio::VideoCapture,
+    index: usize,
+}
+
+#[cfg(feature = "video")]
+fn video_error(e: opencv::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+#[cfg(feature = "video")]
+impl VideoFile {
+    pub fn open(path: &Path) -> io::Result<VideoFile> {
+        use opencv::prelude::*;
+        use opencv::videoio::{VideoCapture, CAP_ANY};
+
+        let path_str = path.to_str().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "path is not valid unicode"))?;
+        let capture = VideoCapture::from_file(path_str, CAP_ANY).map_err(video_error)?;
+        if !capture.is_opened().map_err(video_error)? {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("could not open {}", path.display())));
+        }
+        Ok(VideoFile { capture, index: 0 })
+    }
+
+    fn gray_frame(&mut self, decoded: &opencv::core::Mat) -> io::Result<Frame> {
+        use opencv::core::Mat;
+        use opencv::imgproc::{cvt_color, COLOR_BGR2GRAY};
+        use opencv::prelude::*;
+        use opencv::videoio::CAP_PROP_POS_MSEC;
+
+        // position of the frame just decoded
+        let timestamp = self.capture.get(CAP_PROP_POS_MSEC).map_err(video_error)? / 1000.0;
+        let mut converted = Mat::default().map_err(video_error)?;
+        let gray = if decoded.channels().map_err(video_error)? == 1 {
+            decoded
+        } else {
+            cvt_color(decoded, &mut converted, COLOR_BGR2GRAY, 0).map_err(video_error)?;
+            &converted
+        };
+
+        let (width, height) = (gray.cols() as u32, gray.rows() as u32);
+        let data = gray.data_typed::<u8>().map_err(video_error)?.to_vec();
+        let image = GrayImage::from_raw(width, height, data)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "unexpected frame layout"))?;
+
+        Ok(Frame {
+            image,
+            index: self.index,
+            timestamp,
+            name: None,
+        })
+    }
+}
+
+#[cfg(feature = "video")]
+impl FrameSource for VideoFile {
+    fn next_frame(&mut self) -> Option<io::Result<Frame>> {
+        use opencv::core::Mat;
+        use opencv::prelude::*;
+
+        let mut decoded = match Mat::default() {
+            Ok(mat) => mat,
+            Err(e) => return Some(Err(video_error(e))),
+        };
+        match self.capture.read(&mut decoded) {
+            Ok(true) => {}
+            Ok(false) => return None,
+            Err(e) => return Some(Err(video_error(e))),
+        }
+
+        let frame = self.gray_frame(&decoded);
+        self.index += 1;
+        Some(frame)
+    }
+
+    fn format(&self) -> Option<FrameFormat> {
+        use opencv::prelude::*;
+        use opencv::videoio::{CAP_PROP_FRAME_HEIGHT, CAP_PROP_FRAME_WIDTH};
+
+        // videoio hands out BGR frames whatever the codec
+        Some(FrameFormat {
+            width: self.capture.get(CAP_PROP_FRAME_WIDTH).ok()? as u32,
+            height: self.capture.get(CAP_PROP_FRAME_HEIGHT).ok()? as u32,
+            pixel_format: PixelFormat::Rgb8,
+        })
+    }
+}
+
+/// Frames generated by a closure from the frame index and timestamp, for tests and benchmarks.
+pub struct SyntheticSource<F: FnMut(usize, f64) -> GrayImage> {
+    format: FrameFormat,
+    fps: f64,
+    count: usize,
+    index: usize,
+    render: F,
+}
+
+impl<F: FnMut(usize, f64) -> GrayImage> SyntheticSource<F> {
+    /// `count` frames of `width` x `height`, `render` must return images of that size.
+    pub fn new(width: u32, height: u32, fps: f64, count: usize, render: F) -> SyntheticSource<F> {
+        SyntheticSource {
+            format: FrameFormat { width, height, pixel_format: PixelFormat::Gray8 },
+            fps,
+            count,
+            index: 0,
+            render,
+        }
+    }
+}
+
+/// A synthetic source moving `template` (e.g. a rendered tag) over a gray background, starting at
+/// `start` and moving by `velocity` pixels per frame.
+pub fn moving_template(template: GrayImage, width: u32, height: u32, fps: f64, count: usize, start: (f64, f64), velocity: (f64, f64)) -> SyntheticSource<impl FnMut(usize, f64) -> GrayImage> {
+    SyntheticSource::new(width, height, fps, count, move |index, _| {
+        let mut frame = GrayImage::from_pixel(width, height, Luma { data: [128] });
+        let x = (start.0 + velocity.0 * index as f64).max(0.0) as u32;
+        let y = (start.1 + velocity.1 * index as f64).max(0.0) as u32;
+        imageops::overlay(&mut frame, &template, x, y);
+        frame
+    })
+}
+
+impl<F: FnMut(usize, f64) -> GrayImage> FrameSource for SyntheticSource<F> {
+    fn next_frame(&mut self) -> Option<io::Result<Frame>> {
+        if self.index >= self.count {
+            return None;
+        }
+        let index = self.index;
+        self.index += 1;
+        let timestamp = index as f64 / self.fps;
+
+        Some(Ok(Frame {
+            image: (self.render)(index, timestamp),
+            index,
+            timestamp,
+            name: None,
+        }))
+    }
+
+    fn format(&self) -> Option<FrameFormat> {
+        Some(self.format)
+    }
+}
+
+/// Live camera through `camera_capture`, timestamps are seconds since the stream started.
+#[cfg(feature = "camera")]
+pub struct CameraSource {
+    frames: camera_capture::ImageIterator,
+    format: FrameFormat,
+    start: std::time::Instant,
+    index: usize,
+}
+
+#[cfg(feature = "camera")]
+impl CameraSource {
+    pub fn open(device: u32, width: u32, height: u32, fps: f64) -> io::Result<CameraSource> {
+        let other = |e: &dyn std::fmt::Debug| io::Error::new(io::ErrorKind::Other, format!("{:?}", e));
+        let frames = camera_capture::create(device).map_err(|e| other(&e))?
+            .resolution(width, height).map_err(|e| other(&e))?
+            .fps(fps).map_err(|e| other(&e))?
+            .start().map_err(|e| other(&e))?;
+
+        Ok(CameraSource {
+            frames,
+            format: FrameFormat { width, height, pixel_format: PixelFormat::Rgb8 },
+            start: std::time::Instant::now(),
+            index: 0,
+        })
+    }
+}
+
+#[cfg(feature = "camera")]
+impl FrameSource for CameraSource {
+    fn next_frame(&mut self) -> Option<io::Result<Frame>> {
+        use image::ConvertBuffer;
+
+        let frame = self.frames.next()?;
+        let index = self.index;
+        self.index += 1;
+
+        Some(Ok(Frame {
+            image: frame.convert(),
+            index,
+            timestamp: self.start.elapsed().as_secs_f64(),
+            name: None,
+        }))
+    }
+
+    fn format(&self) -> Option<FrameFormat> {
+        Some(self.format)
+    }
+}
+
+/// Picks a source from a command line style argument: a directory, a glob such as `*.png` (matches
+/// in sorted order), a numbered pattern containing `%d`, a video file (MP4, MKV or AVI, with the
+/// `video` feature) or a single image file. `fps` only sets the timestamps of image inputs.
+pub fn open_source(spec: &str, fps: f64) -> io::Result<Box<dyn FrameSource>> {
+    let path = Path::new(spec);
+    if is_video(path) {
+        #[cfg(feature = "video")]
+        return Ok(Box::new(VideoFile::open(path)?));
+        #[cfg(not(feature = "video"))]
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} needs the video feature", spec)));
+    }
+    if path.is_dir() {
+        return Ok(Box::new(ImageFiles::from_dir(path, fps)?));
+    }
+    if spec.contains(|c| c == '*' || c == '?' || c == '[') {
+        let matches = glob::glob(spec)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("bad pattern {}: {}", spec, e)))?;
+        let mut paths: Vec<PathBuf> = matches.filter_map(Result::ok).collect();
+        paths.sort();
+        return Ok(Box::new(ImageFiles::new(paths, fps)));
+    }
+    if spec.contains('%') {
+        return ImageSequence::new(spec, 0, fps)
+            .map(|s| Box::new(s) as Box<dyn FrameSource>)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("bad sequence pattern {}", spec)));
+    }
+
+    Ok(Box::new(ImageFiles::new(vec![path.to_path_buf()], fps)))
+}
+
+fn is_video(path: &Path) -> bool {
+    let ext = path.extension().and_then(|e| e.to_str()).map(|e| e.to_lowercase());
+    ext.map_or(false, |e| ["mp4", "mkv", "avi"].contains(&e.as_str()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("fiducial-source-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // one 16x16 frame per name, filled with 10 times its position in `names`
+    fn write_frames(dir: &Path, names: &[&str]) {
+        for (i, name) in names.iter().enumerate() {
+            GrayImage::from_pixel(16, 16, Luma { data: [i as u8 * 10] }).save(dir.join(name)).unwrap();
+        }
+    }
+
+    fn read_all(source: &mut dyn FrameSource) -> Vec<Frame> {
+        let mut frames = Vec::new();
+        while let Some(frame) = source.next_frame() {
+            frames.push(frame.unwrap());
+        }
+        frames
+    }
+
+    fn file_names(frames: &[Frame]) -> Vec<String> {
+        frames.iter().map(|f| Path::new(f.name.as_ref().unwrap()).file_name().unwrap().to_string_lossy().into_owned()).collect()
+    }
+
+    #[test]
+    fn image_files_in_name_order() {
+        let dir = scratch_dir("files");
+        write_frames(&dir, &["b.png", "c.png", "a.png"]);
+
+        let frames = read_all(&mut ImageFiles::from_dir(&dir, 10.0).unwrap());
+        assert_eq!(file_names(&frames), vec!["a.png", "b.png", "c.png"]);
+        assert_eq!(frames.iter().map(|f| f.image.get_pixel(0, 0).data[0]).collect::<Vec<_>>(), vec![20, 0, 10]);
+        assert_eq!(frames.iter().map(|f| f.index).collect::<Vec<_>>(), vec![0, 1, 2]);
+        assert_eq!(frames[2].timestamp, 0.2);
+
+        // a file that fails to load is reported and skipped over
+        let mut files = ImageFiles::new(vec![dir.join("missing.png"), dir.join("a.png")], 10.0);
+        assert!(files.next_frame().unwrap().is_err());
+        assert_eq!(files.next_frame().unwrap().unwrap().index, 1);
+        assert!(files.next_frame().is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn image_sequence_patterns() {
+        let sequence = ImageSequence::new("frame_%05d.png", 0, 30.0).unwrap();
+        assert_eq!(sequence.path(42), PathBuf::from("frame_00042.png"));
+        for pattern in &["frame_%d.png", "frame_%0d.png"] {
+            assert_eq!(ImageSequence::new(pattern, 0, 30.0).unwrap().path(42), PathBuf::from("frame_42.png"));
+        }
+        assert!(ImageSequence::new("frame.png", 0, 30.0).is_none());
+        assert!(ImageSequence::new("frame_%xd.png", 0, 30.0).is_none());
+
+        let dir = scratch_dir("sequence");
+        write_frames(&dir, &["img_001.png", "img_002.png", "img_003.png", "img_005.png"]);
+        let pattern = dir.join("img_%03d.png");
+        let mut sequence = ImageSequence::new(pattern.to_str().unwrap(), 1, 10.0).unwrap();
+
+        // read from `start` up to the first gap
+        let frames = read_all(&mut sequence);
+        assert_eq!(file_names(&frames), vec!["img_001.png", "img_002.png", "img_003.png"]);
+        assert_eq!(frames.iter().map(|f| f.index).collect::<Vec<_>>(), vec![0, 1, 2]);
+        assert_eq!(frames[1].timestamp, 0.1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn synthetic_source_frames() {
+        let mut source = SyntheticSource::new(32, 16, 25.0, 4, |index, _| GrayImage::from_pixel(32, 16, Luma { data: [index as u8] }));
+        assert_eq!(source.format(), Some(FrameFormat { width: 32, height: 16, pixel_format: PixelFormat::Gray8 }));
+
+        let frames = read_all(&mut source);
+        assert_eq!(frames.len(), 4);
+        for (i, frame) in frames.iter().enumerate() {
+            assert_eq!(frame.index, i);
+            assert_eq!(frame.timestamp, i as f64 / 25.0);
+            assert_eq!(frame.image.get_pixel(0, 0).data[0], i as u8);
+            assert_eq!(frame.name, None);
+        }
+        assert!(source.next_frame().is_none());
+    }
+
+    #[test]
+    fn open_source_dispatch() {
+        let dir = scratch_dir("open");
+        write_frames(&dir, &["img_2.png", "img_0.png", "img_1.png", "other.jpg"]);
+
+        let names = |spec: &Path| file_names(&read_all(&mut *open_source(spec.to_str().unwrap(), 30.0).unwrap()));
+        assert_eq!(names(&dir), vec!["img_0.png", "img_1.png", "img_2.png", "other.jpg"]);
+        assert_eq!(names(&dir.join("img_*.png")), vec!["img_0.png", "img_1.png", "img_2.png"]);
+        assert_eq!(names(&dir.join("img_[02].png")), vec!["img_0.png", "img_2.png"]);
+        assert_eq!(names(&dir.join("img_%d.png")), vec!["img_0.png", "img_1.png", "img_2.png"]);
+        assert_eq!(names(&dir.join("other.jpg")), vec!["other.jpg"]);
+        assert!(open_source(dir.join("img_%xd.png").to_str().unwrap(), 30.0).is_err());
+        #[cfg(not(feature = "video"))]
+        assert!(open_source(dir.join("clip.mp4").to_str().unwrap(), 30.0).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "video")]
+    fn video_file_frames() {
+        use opencv::core::{Mat, Scalar, Size, CV_8UC3};
+        use opencv::prelude::*;
+        use opencv::videoio::VideoWriter;
+
+        let dir = scratch_dir("video");
+        let path = dir.join("clip.avi");
+        let fourcc = VideoWriter::fourcc('M' as i8, 'J' as i8, 'P' as i8, 'G' as i8).unwrap();
+        let mut writer = VideoWriter::new(path.to_str().unwrap(), fourcc, 10.0, Size::new(64, 48), true).unwrap();
+        assert!(writer.is_opened().unwrap());
+        for i in 0..10 {
+            let frame = Mat::new_rows_cols_with_default(48, 64, CV_8UC3, Scalar::all(i as f64 * 20.0)).unwrap();
+            writer.write(&frame).unwrap();
+        }
+        writer.release().unwrap();
+
+        // .avi goes through the video source, not the image loader
+        let mut source = open_source(path.to_str().unwrap(), 30.0).unwrap();
+        assert_eq!(source.format(), Some(FrameFormat { width: 64, height: 48, pixel_format: PixelFormat::Rgb8 }));
+        let frames = read_all(&mut *source);
+        assert_eq!(frames.len(), 10);
+        for (i, frame) in frames.iter().enumerate() {
+            assert_eq!(frame.index, i);
+            assert_eq!(frame.image.dimensions(), (64, 48));
+            // from the container at 10 fps, not from the 30 fps passed in
+            assert!((frame.timestamp - i as f64 * 0.1).abs() < 1e-3, "frame {} at {}", i, frame.timestamp);
+            let value = frame.image.get_pixel(32, 24).data[0] as i32;
+            assert!((value - i as i32 * 20).abs() <= 4, "frame {} has {}", i, value);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,547 @@
+use cv_core::WorldPose;
+use image::Luma;
+use imageproc::definitions::Image;
+use nalgebra::{DMatrix, DVector, IsometryMatrix3, Point3, Rotation3, Translation3, Vector3};
+use std::collections::HashMap;
+use std::io;
+
+use crate::camera::Camera;
+use crate::decode::{DecodeOptions, DecodedLFTag};
+use crate::detector::LFTagDetector;
+use crate::localize::{project, scale_pose};
+use crate::source::{Frame, FrameSource};
+use crate::Intensity;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrackerConfig {
+    // frames a track is predicted through without detections before it is reported lost
+    pub max_coast_frames: usize,
+    // frames a lost track is remembered for reacquisition
+    pub max_lost_frames: usize,
+    // white noise acceleration densities for the constant velocity model, translation in tag
+    // widths (the `tag_size` of the detections) and rotation in radians
+    pub translation_process_noise: f64,
+    pub rotation_process_noise: f64,
+    // standard deviations of the per frame pose measurement, in tag widths and radians
+    pub translation_measurement_noise: f64,
+    pub rotation_measurement_noise: f64,
+    // detections further than this many tag widths from the prediction are rejected as outliers
+    pub gate_distance: f64,
+}
+
+impl Default for TrackerConfig {
+    fn default() -> TrackerConfig {
+        TrackerConfig {
+            max_coast_frames: 5,
+            max_lost_frames: 300,
+            translation_process_noise: 25.0,
+            rotation_process_noise: 10.0,
+            translation_measurement_noise: 0.05,
+            rotation_measurement_noise: 0.02,
+            gate_distance: 3.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TrackEvent {
+    New { id: usize },
+    Lost { id: usize },
+    Reacquired { id: usize, missed: usize },
+    Removed { id: usize },
+}
+
+/// Error state Kalman filter over position, velocity, orientation and angular velocity, with the
+/// orientation error kept as a rotation vector applied on the left.
+#[derive(Debug, Clone)]
+struct PoseFilter {
+    // tag width in pose units, translation noises are scaled by it
+    scale: f64,
+    position: Vector3<f64>,
+    velocity: Vector3<f64>,
+    rotation: Rotation3<f64>,
+    angular_velocity: Vector3<f64>,
+    covariance: DMatrix<f64>,
+}
+
+impl PoseFilter {
+    fn new(pose: &IsometryMatrix3<f64>, scale: f64, config: &TrackerConfig) -> PoseFilter {
+        let mut covariance = DMatrix::zeros(12, 12);
+        for i in 0..3 {
+            covariance[(i, i)] = (config.translation_measurement_noise * scale).powi(2);
+            covariance[(i + 3, i + 3)] = scale.powi(2);
+            covariance[(i + 6, i + 6)] = config.rotation_measurement_noise.powi(2);
+            covariance[(i + 9, i + 9)] = 1.0;
+        }
+
+        PoseFilter {
+            scale,
+            position: pose.translation.vector,
+            velocity: Vector3::zeros(),
+            rotation: pose.rotation,
+            angular_velocity: Vector3::zeros(),
+            covariance,
+        }
+    }
+
+    fn pose(&self) -> IsometryMatrix3<f64> {
+        IsometryMatrix3::from_parts(Translation3::from(self.position), self.rotation)
+    }
+
+    fn predicted(&self, dt: f64) -> IsometryMatrix3<f64> {
+        IsometryMatrix3::from_parts(
+            Translation3::from(self.position + self.velocity * dt),
+            Rotation3::new(self.angular_velocity * dt) * self.rotation,
+        )
+    }
+
+    fn predict(&mut self, dt: f64, config: &TrackerConfig) {
+        let pose = self.predicted(dt);
+        self.position = pose.translation.vector;
+        self.rotation = pose.rotation;
+
+        let mut f = DMatrix::identity(12, 12);
+        let mut q = DMatrix::zeros(12, 12);
+        for i in 0..3 {
+            f[(i, i + 3)] = dt;
+            f[(i + 6, i + 9)] = dt;
+
+            for (offset, noise) in &[(0, config.translation_process_noise * self.scale.powi(2)), (6, config.rotation_process_noise)] {
+                let a = offset + i;
+                let b = offset + i + 3;
+                q[(a, a)] = noise * dt.powi(3) / 3.0;
+                q[(a, b)] = noise * dt.powi(2) / 2.0;
+                q[(b, a)] = noise * dt.powi(2) / 2.0;
+                q[(b, b)] = noise * dt;
+            }
+        }
+
+        self.covariance = &f * &self.covariance * f.transpose() + q;
+    }
+
+    fn update(&mut self, pose: &IsometryMatrix3<f64>, config: &TrackerConfig) {
+        let mut h = DMatrix::zeros(6, 12);
+        let mut r = DMatrix::zeros(6, 6);
+        for i in 0..3 {
+            h[(i, i)] = 1.0;
+            h[(i + 3, i + 6)] = 1.0;
+            r[(i, i)] = (config.translation_measurement_noise * self.scale).powi(2);
+            r[(i + 3, i + 3)] = config.rotation_measurement_noise.powi(2);
+        }
+
+        let dp = pose.translation.vector - self.position;
+        let dr = (pose.rotation * self.rotation.inverse()).scaled_axis();
+        let y = DVector::from_column_slice(&[dp[0], dp[1], dp[2], dr[0], dr[1], dr[2]]);
+
+        let s = &h * &self.covariance * h.transpose() + r;
+        let s_inv = match s.try_inverse() {
+            Some(s_inv) => s_inv,
+            None => return,
+        };
+        let k = &self.covariance * h.transpose() * s_inv;
+        let dx = &k * y;
+
+        self.position += Vector3::new(dx[0], dx[1], dx[2]);
+        self.velocity += Vector3::new(dx[3], dx[4], dx[5]);
+        self.rotation = Rotation3::new(Vector3::new(dx[6], dx[7], dx[8])) * self.rotation;
+        self.angular_velocity += Vector3::new(dx[9], dx[10], dx[11]);
+        self.covariance = (DMatrix::identity(12, 12) - k * h) * &self.covariance;
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Track {
+    pub id: usize,
+    // frames since the track was created
+    pub age: usize,
+    // consecutive frames without a detection
+    pub missed: usize,
+    pub lost: bool,
+    pub last_detection: DecodedLFTag,
+    pub last_seen: f64,
+    timestamp: f64,
+    filter: PoseFilter,
+}
+
+impl Track {
+    /// Filtered tag pose in the camera frame at the last update, predicted while coasting.
+    pub fn pose(&self) -> IsometryMatrix3<f64> {
+        self.filter.pose()
+    }
+
+    pub fn velocity(&self) -> (Vector3<f64>, Vector3<f64>) {
+        (self.filter.velocity, self.filter.angular_velocity)
+    }
+
+    /// Extrapolated pose at a later time, under constant velocity.
+    pub fn predict(&self, timestamp: f64) -> IsometryMatrix3<f64> {
+        self.filter.predicted(timestamp - self.timestamp)
+    }
+
+    /// Image bounding box ([min, max] corners) of the tag at its predicted pose, grown by `margin`
+    /// times its size on every side. None if the tag would be behind the camera.
+    pub fn predicted_footprint<C: Camera>(&self, camera: &C, timestamp: f64, margin: f64) -> Option<[(u32, u32); 2]> {
+        let pose = self.predict(timestamp);
+        let size = self.last_detection.tag_size;
+
+        let mut min = (f64::MAX, f64::MAX);
+        let mut max = (f64::MIN, f64::MIN);
+        for &(x, y) in &[(0.0, 0.0), (size, 0.0), (0.0, size), (size, size)] {
+            let corner = Point3::new(x, y, 0.0);
+            if (pose * corner).z <= 0.0 {
+                return None
+            }
+            let p = project(camera, &pose, &corner);
+            min = (min.0.min(p.x), min.1.min(p.y));
+            max = (max.0.max(p.x), max.1.max(p.y));
+        }
+
+        let grow = (max.0 - min.0).max(max.1 - min.1) * margin;
+        Some([
+            ((min.0 - grow).max(0.0) as u32, (min.1 - grow).max(0.0) as u32),
+            ((max.0 + grow).max(0.0) as u32, (max.1 + grow).max(0.0) as u32),
+        ])
+    }
+}
+
+/// Associates detections across frames by decoded id and smooths their poses.
+pub struct Tracker {
+    pub config: TrackerConfig,
+    tracks: HashMap<usize, Track>,
+}
+
+impl Tracker {
+    pub fn new(config: TrackerConfig) -> Tracker {
+        Tracker {
+            config,
+            tracks: HashMap::new(),
+        }
+    }
+
+    pub fn tracks(&self) -> impl Iterator<Item = &Track> {
+        self.tracks.values()
+    }
+
+    /// Tracks currently seen or coasting, i.e. not lost.
+    pub fn active_tracks(&self) -> impl Iterator<Item = &Track> {
+        self.tracks.values().filter(|t| !t.lost)
+    }
+
+    pub fn get(&self, id: usize) -> Option<&Track> {
+        self.tracks.get(&id)
+    }
+
+    /// Advances every track to `timestamp` (seconds) and folds in the detections of the frame.
+    /// Detections too far from their track's prediction are treated as misses, a lost track is
+    /// restarted from its next detection wherever it is.
+    pub fn update(&mut self, detections: &[DecodedLFTag], timestamp: f64) -> Vec<TrackEvent> {
+        let config = &self.config;
+        let mut events = Vec::new();
+
+        // several detections can share an id, keep the one closest to the prediction
+        let mut matched: HashMap<usize, &DecodedLFTag> = HashMap::new();
+        for detection in detections {
+            let position = detection.final_pose.0.translation.vector;
+            let closer = match (matched.get(&detection.data), self.tracks.get(&detection.data)) {
+                (Some(other), Some(track)) => {
+                    let predicted = track.predict(timestamp).translation.vector;
+                    (position - predicted).norm() < (other.final_pose.0.translation.vector - predicted).norm()
+                }
+                (Some(_), None) => false,
+                (None, _) => true,
+            };
+            if closer {
+                matched.insert(detection.data, detection);
+            }
+        }
+
+        for (id, track) in &mut self.tracks {
+            let dt = (timestamp - track.timestamp).max(0.0);
+            track.filter.predict(dt, config);
+            track.timestamp = timestamp;
+            track.age += 1;
+
+            let gated = matched.get(id).filter(|detection| {
+                let distance = (detection.final_pose.0.translation.vector - track.filter.position).norm();
+                track.lost || distance <= config.gate_distance * track.filter.scale
+            });
+
+            match gated {
+                Some(detection) => {
+                    let pose = detection.final_pose.0;
+                    if track.lost {
+                        track.filter = PoseFilter::new(&pose, detection.tag_size, config);
+                    } else {
+                        track.filter.update(&pose, config);
+                    }
+
+                    if track.lost {
+                        events.push(TrackEvent::Reacquired { id: *id, missed: track.missed });
+                        track.lost = false;
+                    }
+                    track.missed = 0;
+                    track.last_seen = timestamp;
+                    track.last_detection = (*detection).clone();
+                }
+                None => {
+                    track.missed += 1;
+                    if !track.lost && track.missed > config.max_coast_frames {
+                        track.lost = true;
+                        events.push(TrackEvent::Lost { id: *id });
+                    }
+                }
+            }
+        }
+
+        let max_missed = config.max_coast_frames + config.max_lost_frames;
+        let removed: Vec<usize> = self.tracks.values().filter(|t| t.missed > max_missed).map(|t| t.id).collect();
+        for id in removed {
+            self.tracks.remove(&id);
+            events.push(TrackEvent::Removed { id });
+        }
+
+        for (id, detection) in matched {
+            if !self.tracks.contains_key(&id) {
+                self.tracks.insert(id, Track {
+                    id,
+                    age: 0,
+                    missed: 0,
+                    lost: false,
+                    last_detection: detection.clone(),
+                    last_seen: timestamp,
+                    timestamp,
+                    filter: PoseFilter::new(&detection.final_pose.0, detection.tag_size, &self.config),
+                });
+                events.push(TrackEvent::New { id });
+            }
+        }
+
+        events
+    }
+}
+
+/// Runs the detector only around the predicted footprints of tracked tags, decoding each with its
+/// known id and predicted pose. Full frame detection runs every `full_detection_interval` frames
+/// and when nothing is tracked; a tracked tag that is not found in its region is looked for in the
+/// full frame, while the tags found in their regions are kept.
+pub struct TrackingDetector<C: Camera> {
+    pub detector: LFTagDetector<C>,
+    pub tracker: Tracker,
+    pub full_detection_interval: usize,
+    // fraction of the tag footprint added around each region of interest
+    pub roi_margin: f64,
+    frames_since_full: usize,
+}
+
+impl<C: Camera> TrackingDetector<C> {
+    pub fn new(detector: LFTagDetector<C>, config: TrackerConfig) -> TrackingDetector<C> {
+        TrackingDetector {
+            detector,
+            tracker: Tracker::new(config),
+            full_detection_interval: 30,
+            roi_margin: 0.5,
+            frames_since_full: 0,
+        }
+    }
+
+    /// Detects the tags in a frame taken at `timestamp` (seconds) and updates the tracks with them.
+    pub fn process<T: Intensity>(&mut self, input: &Image<Luma<T>>, timestamp: f64) -> (Vec<DecodedLFTag>, Vec<TrackEvent>) {
+        let tracking = self.frames_since_full + 1 < self.full_detection_interval && self.tracker.active_tracks().next().is_some();
+
+        let detections = if tracking {
+            self.frames_since_full += 1;
+            let (mut detections, missed) = self.detect_tracked(input, timestamp);
+            if !missed.is_empty() {
+                // only the missed tags are taken from the full frame, new tags wait for the next
+                // periodic full detection
+                let full = self.detector.detect(input).unwrap_or_default();
+                detections.extend(full.into_iter().filter(|d| missed.contains(&d.data)));
+            }
+            detections
+        } else {
+            self.frames_since_full = 0;
+            self.detector.detect(input).unwrap_or_default()
+        };
+
+        let events = self.tracker.update(&detections, timestamp);
+        (detections, events)
+    }
+
+    /// Processes every frame of a source with its timestamp, like `LFTagDetector::run`.
+    pub fn run<S: FrameSource + ?Sized, F: FnMut(&Frame, &[DecodedLFTag], &[TrackEvent]) -> bool>(&mut self, source: &mut S, mut on_frame: F) -> io::Result<usize> {
+        let mut count = 0;
+        while let Some(frame) = source.next_frame() {
+            let frame = frame?;
+            let (tags, events) = self.process(&frame.image, frame.timestamp);
+            count += 1;
+            if !on_frame(&frame, &tags, &events) {
+                break;
+            }
+        }
+        Ok(count)
+    }
+
+    // detections of the active tracks in their predicted regions, and the ids of those not found
+    fn detect_tracked<T: Intensity>(&self, input: &Image<Luma<T>>, timestamp: f64) -> (Vec<DecodedLFTag>, Vec<usize>) {
+        let mut detections = Vec::new();
+        let mut missed = Vec::new();
+        for track in self.tracker.active_tracks() {
+            let roi = match track.predicted_footprint(&self.detector.camera, timestamp, self.roi_margin) {
+                Some(roi) => roi,
+                None => {
+                    missed.push(track.id);
+                    continue
+                }
+            };
+
+            // decoding works in tag grid units
+            let last = &track.last_detection;
+            let grid = last.class.get_pix_size() as f64 / last.tag_size;
+            let options = DecodeOptions {
+                known_id: Some(track.id),
+                prior_pose: Some(scale_pose(&WorldPose(track.predict(timestamp)), grid)),
+                // the prior is in the frame of the mirror image for mirrored tags
+                mirrored: last.mirrored,
+                ..DecodeOptions::default()
+            };
+
+            match self.detector.detect_in_roi(input, roi, &options).and_then(|found| found.into_iter().next()) {
+                Some(detection) => detections.push(detection),
+                None => missed.push(track.id),
+            }
+        }
+        (detections, missed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera::BrownConrady;
+    use crate::decode::LFTagClass;
+    use cv_pinhole::CameraIntrinsics;
+    use image::{imageops, GrayImage};
+    use nalgebra::{Point2, Vector2};
+
+    fn camera() -> BrownConrady {
+        BrownConrady::new(
+            CameraIntrinsics {
+                focals: Vector2::new(500.0, 500.0),
+                principal_point: Point2::new(320.0, 240.0),
+                skew: 0.0,
+            },
+            [0.0; 5],
+        )
+    }
+
+    fn pose_at(x: f64, z: f64) -> IsometryMatrix3<f64> {
+        IsometryMatrix3::from_parts(Translation3::new(x, 0.0, z), Rotation3::new(Vector3::new(0.1, 0.2, 0.0)))
+    }
+
+    // detection of tag 5 at `pose`, given in units of `tag_size`
+    fn detection(pose: IsometryMatrix3<f64>, tag_size: f64) -> DecodedLFTag {
+        let class = LFTagClass::LFTag3x3;
+        let grid = class.get_pix_size() as f64 / tag_size;
+        let mut tag = DecodedLFTag::synthetic(&camera(), class, 5, scale_pose(&WorldPose(pose), grid));
+        tag.set_tag_size(tag_size);
+        tag
+    }
+
+    #[test]
+    fn filter_predicts_constant_velocity() {
+        let config = TrackerConfig::default();
+        let start = pose_at(0.0, 1.0);
+        let mut filter = PoseFilter::new(&start, 0.1, &config);
+        filter.velocity = Vector3::new(0.2, 0.0, 0.0);
+        filter.angular_velocity = Vector3::new(0.0, 0.0, 0.5);
+        let variance = filter.covariance[(0, 0)];
+
+        filter.predict(0.5, &config);
+        assert!((filter.position - Vector3::new(0.1, 0.0, 1.0)).norm() < 1e-9);
+        let turned = filter.rotation * start.rotation.inverse();
+        assert!((turned.scaled_axis() - Vector3::new(0.0, 0.0, 0.25)).norm() < 1e-9);
+        assert!(filter.covariance[(0, 0)] > variance);
+    }
+
+    #[test]
+    fn filter_update_converges() {
+        let config = TrackerConfig::default();
+
+        // a fresh filter is as uncertain as the measurement and moves halfway
+        let mut filter = PoseFilter::new(&pose_at(0.0, 1.0), 0.1, &config);
+        filter.update(&pose_at(0.02, 1.0), &config);
+        assert!((filter.position.x - 0.01).abs() < 1e-9);
+
+        let target = pose_at(0.02, 1.01);
+        for _ in 0..100 {
+            filter.predict(1.0 / 30.0, &config);
+            filter.update(&target, &config);
+        }
+        assert!((filter.position - target.translation.vector).norm() < 1e-3);
+        assert!((filter.rotation.inverse() * target.rotation).angle() < 1e-3);
+        assert!(filter.velocity.norm() < 0.05);
+    }
+
+    #[test]
+    fn gate_scales_with_tag_size() {
+        // the same trajectory in grid units and in meters
+        for &tag_size in &[24.0, 0.1] {
+            let mut tracker = Tracker::new(TrackerConfig::default());
+            let dt = 1.0 / 30.0;
+            let at = |frame: usize| pose_at(0.2 * tag_size * frame as f64, 10.0 * tag_size);
+
+            for frame in 0..20 {
+                tracker.update(&[detection(at(frame), tag_size)], frame as f64 * dt);
+            }
+            let track = tracker.get(5).unwrap();
+            assert_eq!(track.missed, 0);
+            assert!((track.velocity().0.x - 6.0 * tag_size).abs() < 0.5 * tag_size);
+
+            // a misread far off the trajectory is rejected and the track coasts
+            let jump = pose_at(0.2 * tag_size * 20.0 + 20.0 * tag_size, 10.0 * tag_size);
+            let events = tracker.update(&[detection(jump, tag_size)], 20.0 * dt);
+            assert!(events.is_empty());
+            let track = tracker.get(5).unwrap();
+            assert_eq!(track.missed, 1);
+            assert!((track.pose().translation.vector - at(20).translation.vector).norm() < 0.2 * tag_size);
+
+            // the next detection on the trajectory is taken again
+            tracker.update(&[detection(at(21), tag_size)], 21.0 * dt);
+            let track = tracker.get(5).unwrap();
+            assert_eq!(track.missed, 0);
+            assert!((track.pose().translation.vector - at(21).translation.vector).norm() < 0.2 * tag_size);
+        }
+    }
+
+    #[test]
+    fn missed_tracks_fall_back_to_full_frame() {
+        let detector = LFTagDetector::new(BrownConrady::new(
+            CameraIntrinsics {
+                focals: Vector2::new(400.0, 400.0),
+                principal_point: Point2::new(400.0, 240.0),
+                skew: 0.0,
+            },
+            [0.0; 5],
+        ));
+        let mut tracking = TrackingDetector::new(detector, TrackerConfig::default());
+        let class = LFTagClass::LFTag3x3;
+        let frame = |b: (u32, u32)| {
+            let mut image = GrayImage::from_pixel(800, 480, Luma { data: [255] });
+            imageops::overlay(&mut image, &class.render(0x1234, 8), 40, 40);
+            imageops::overlay(&mut image, &class.render(0x2b1d, 8), b.0, b.1);
+            image
+        };
+
+        let (tags, _) = tracking.process(&frame((360, 40)), 0.0);
+        assert_eq!(tags.len(), 2);
+
+        // the second tag jumps out of its predicted region
+        let (tags, _) = tracking.process(&frame((560, 240)), 1.0 / 30.0);
+        let mut ids: Vec<usize> = tags.iter().map(|t| t.data).collect();
+        ids.sort();
+        assert_eq!(ids, vec![0x1234, 0x2b1d]);
+        // still between periodic full detections
+        assert_eq!(tracking.frames_since_full, 1);
+        assert_eq!(tracking.tracker.get(0x2b1d).unwrap().missed, 0);
+    }
+}